@@ -1,16 +1,32 @@
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 use std::time::Instant;
 
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tauri::State;
 
+use crate::commands::history::sync_operation_log;
+use crate::commands::session::DocumentSessions;
+use crate::commands::versioning::{auto_snapshot, auto_snapshot_stored};
+use crate::model::consistency::{self, ConsistencyReport};
+use crate::model::formatting::{normalize_ranges, FormattingIndex};
 use crate::model::piece_table::PieceTableContent;
-use crate::storage::zip_container::{export_markdown, load_document, save_document, DocumentPayload};
+use crate::model::version::VersionSummary;
+use crate::storage::version_store;
+use crate::storage::zip_container::{
+    export_markdown, load_asset_history, load_document, load_operation_log, save_document, save_working_copy,
+    DocumentPayload,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SaveRequest {
     pub path: String,
     pub payload: DocumentPayload,
+    /// The save happens because the app is closing
+    #[serde(default)]
+    pub closing: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -21,42 +37,119 @@ pub struct ExportRequest {
     pub chunks: Vec<crate::model::piece_table::PieceChunk>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConsistencyRequest {
+    pub path: String,
+    /// Apply automatic repairs and write the document back
+    #[serde(default)]
+    pub repair: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FormattingQuery {
+    pub path: String,
+    /// Buffer offsets (UTF-16 code units) to resolve
+    pub offsets: Vec<usize>,
+}
+
 #[derive(Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PerfSnapshot {
     pub operation: String,
     pub elapsed_ms: u128,
     pub payload_bytes: usize,
+    /// Version taken automatically during the operation, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auto_version: Option<VersionSummary>,
+}
+
+/// Run the tree-over-buffer consistency checker on a payload and apply
+/// automatic repairs. Returns `None` for documents without a tree.
+pub fn repair_payload(payload: &mut DocumentPayload) -> Option<ConsistencyReport> {
+    let text = PieceTableContent {
+        base_text: payload.base_text.clone(),
+        chunks: payload.chunks.clone(),
+    }
+    .to_text();
+    let tree = payload.document_tree.as_mut()?;
+    let report = consistency::repair(tree, &text);
+    for issue in &report.repaired {
+        log::info!("repaired document tree: {}", issue.message);
+    }
+    for issue in &report.unresolved {
+        log::warn!("document tree inconsistency: {}", issue.message);
+    }
+    Some(report)
 }
 
 #[tauri::command]
-pub fn save_grokedoc(request: SaveRequest) -> Result<PerfSnapshot, String> {
+pub fn save_grokedoc(sessions: State<'_, DocumentSessions>, mut request: SaveRequest) -> Result<PerfSnapshot, String> {
     let start = Instant::now();
-    let path = PathBuf::from(request.path);
-    let payload_size = serde_json::to_vec(&request.payload)
-        .map_err(|err| err.to_string())?
-        .len();
-    save_document(&path, &request.payload).map_err(|err| err.to_string())?;
-    Ok(PerfSnapshot {
-        operation: "save_grokedoc".to_string(),
-        elapsed_ms: start.elapsed().as_millis(),
-        payload_bytes: payload_size,
+    let path = PathBuf::from(&request.path);
+    sessions.write_through(&path, || {
+        repair_payload(&mut request.payload);
+        normalize_ranges(&mut request.payload.metadata.ranges);
+        // The frontend ships neither the operation log, the versions nor the
+        // asset history; keep the ones on disk.
+        if request.payload.operations.is_none() {
+            request.payload.operations = load_operation_log(&path).map_err(|err| err.to_string())?;
+        }
+        // The saved text may have moved on without the log.
+        sync_operation_log(&mut request.payload);
+        if request.payload.asset_history.is_empty() {
+            request.payload.asset_history = load_asset_history(&path).map_err(|err| err.to_string())?;
+        }
+        // A normal save copies the stored versions as they are.
+        let saved = request.payload.versions.is_empty()
+            && path.exists()
+            && save_working_copy(&path, &request.payload).map_err(|err| err.to_string())?;
+        let auto_version = if saved {
+            auto_snapshot_stored(&path, &request.payload, request.closing).map_err(|err| err.to_string())?
+        } else {
+            if request.payload.versions.is_empty() {
+                let (versions, branches) = version_store::load_versions(&path).map_err(|err| err.to_string())?;
+                request.payload.versions = versions;
+                request.payload.branches = branches;
+            }
+            let auto_version = auto_snapshot(&mut request.payload, request.closing).map_err(|err| err.to_string())?;
+            save_document(&path, &request.payload).map_err(|err| err.to_string())?;
+            auto_version
+        };
+        let payload_size = serde_json::to_vec(&request.payload)
+            .map_err(|err| err.to_string())?
+            .len();
+        Ok(PerfSnapshot {
+            operation: "save_grokedoc".to_string(),
+            elapsed_ms: start.elapsed().as_millis(),
+            payload_bytes: payload_size,
+            auto_version: auto_version.map(|version| version.to_summary()),
+        })
     })
 }
 
 #[tauri::command]
-pub fn load_grokedoc(path: String) -> Result<(DocumentPayload, PerfSnapshot), String> {
+pub fn load_grokedoc(
+    sessions: State<'_, DocumentSessions>,
+    path: String,
+) -> Result<(DocumentPayload, PerfSnapshot), String> {
     let start = Instant::now();
-    let parsed = load_document(PathBuf::from(path).as_path()).map_err(|err| err.to_string())?;
-    let payload_size = serde_json::to_vec(&parsed).map_err(|err| err.to_string())?.len();
-    Ok((
-        parsed,
-        PerfSnapshot {
-            operation: "load_grokedoc".to_string(),
-            elapsed_ms: start.elapsed().as_millis(),
-            payload_bytes: payload_size,
-        },
-    ))
+    let path = PathBuf::from(path);
+    sessions.read_through(&path, || {
+        let mut parsed = load_document(&path).map_err(|err| err.to_string())?;
+        repair_payload(&mut parsed);
+        let payload_size = serde_json::to_vec(&parsed).map_err(|err| err.to_string())?.len();
+        Ok((
+            parsed,
+            PerfSnapshot {
+                operation: "load_grokedoc".to_string(),
+                elapsed_ms: start.elapsed().as_millis(),
+                payload_bytes: payload_size,
+                auto_version: None,
+            },
+        ))
+    })
 }
 
 #[tauri::command]
@@ -72,5 +165,59 @@ pub fn export_document_markdown(request: ExportRequest) -> Result<PerfSnapshot,
         operation: "export_document_markdown".to_string(),
         elapsed_ms: start.elapsed().as_millis(),
         payload_bytes: payload_size,
+        auto_version: None,
+    })
+}
+
+/// Check a saved document against the tree-over-buffer invariants.
+/// With `repair`, fixable issues are corrected and the document is saved.
+#[tauri::command]
+pub fn check_document_consistency(
+    sessions: State<'_, DocumentSessions>,
+    request: ConsistencyRequest,
+) -> Result<ConsistencyReport, String> {
+    let path = PathBuf::from(request.path);
+    sessions.write_through(&path, || {
+        let mut payload = load_document(&path).map_err(|err| err.to_string())?;
+        if !request.repair {
+            let text = PieceTableContent {
+                base_text: payload.base_text.clone(),
+                chunks: payload.chunks.clone(),
+            }
+            .to_text();
+            let unresolved = payload
+                .document_tree
+                .as_ref()
+                .map(|tree| consistency::check(tree, &text))
+                .unwrap_or_default();
+            return Ok(ConsistencyReport {
+                repaired: Vec::new(),
+                unresolved,
+            });
+        }
+        let report = repair_payload(&mut payload).unwrap_or_default();
+        if !report.repaired.is_empty() {
+            sync_operation_log(&mut payload);
+            save_document(&path, &payload).map_err(|err| err.to_string())?;
+        }
+        Ok(report)
+    })
+}
+
+/// Resolve the formatting attributes applying at each requested offset.
+#[tauri::command]
+pub fn get_formatting_at(
+    sessions: State<'_, DocumentSessions>,
+    request: FormattingQuery,
+) -> Result<Vec<BTreeMap<String, Value>>, String> {
+    let path = PathBuf::from(request.path);
+    sessions.read_through(&path, || {
+        let payload = load_document(&path).map_err(|err| err.to_string())?;
+        let index = FormattingIndex::new(&payload.metadata.ranges);
+        Ok(request
+            .offsets
+            .into_iter()
+            .map(|offset| index.attrs_at(offset).cloned().unwrap_or_default())
+            .collect())
     })
 }
@@ -0,0 +1,605 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tauri::State;
+use thiserror::Error;
+
+use crate::commands::document::repair_payload;
+use crate::commands::session::DocumentSessions;
+use crate::commands::versioning::{load_or_create_payload, next_version_number, VersionError};
+use crate::model::document_tree::DocumentTree;
+use crate::model::piece_table::PieceChunk;
+use crate::model::version::{DocumentVersion, VersionAsset, VersionSummary};
+use crate::storage::checksum::sha256_hex;
+use crate::storage::version_store;
+use crate::storage::zip_container::{load_document, save_document, AssetRef, MetadataRange, StorageError};
+
+/// Commit trailer naming the version a commit was exported from.
+const VERSION_TRAILER: &str = "Yeno-Version";
+/// Repository directory holding the asset files.
+const ASSETS_DIR: &str = "assets";
+/// Commit author for versions without one; git requires a name.
+const UNKNOWN_AUTHOR: &str = "Unknown";
+
+#[derive(Debug, Error)]
+pub enum GitError {
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("version error: {0}")]
+    Version(#[from] VersionError),
+    #[error("serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("git {command} failed: {message}")]
+    Command { command: String, message: String },
+    #[error("no commits touch {0}")]
+    NoHistory(String),
+    #[error("bytes of asset {0} are missing")]
+    MissingAsset(String),
+    #[error("invalid asset name: {0:?}")]
+    InvalidAssetName(String),
+    #[error("{0} is not valid UTF-8 in commit {1}")]
+    NotUtf8(String, String),
+}
+
+impl Serialize for GitError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GitExportRequest {
+    pub path: String,
+    /// Repository directory; created and initialized if needed
+    pub repo_path: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GitExportResponse {
+    /// Markdown file holding the text, relative to the repository
+    pub file: String,
+    /// Commits made, oldest first
+    pub commits: Vec<ExportedCommit>,
+    /// Versions left out because an earlier export already committed them
+    pub skipped: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedCommit {
+    pub version_id: String,
+    pub commit: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GitImportRequest {
+    pub path: String,
+    pub repo_path: String,
+    /// Markdown file to import, relative to the repository (defaults to the
+    /// name `export_versions_to_git` uses)
+    #[serde(default)]
+    pub file: Option<String>,
+    /// Commit to import up to (defaults to HEAD)
+    #[serde(default)]
+    pub rev: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GitImportResponse {
+    /// Versions created, oldest first
+    pub imported: Vec<VersionSummary>,
+    pub all_versions: Vec<VersionSummary>,
+}
+
+/// What the Markdown cannot hold, written next to it: formatting, tree and
+/// asset placement for the text with `content_hash`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Sidecar {
+    content_hash: String,
+    #[serde(default)]
+    ranges: Vec<MetadataRange>,
+    #[serde(default)]
+    document_tree: Option<DocumentTree>,
+    #[serde(default)]
+    assets: Option<Vec<VersionAsset>>,
+}
+
+/// A commit read back from the repository.
+struct LoggedCommit {
+    hash: String,
+    author: String,
+    date: DateTime<Utc>,
+    subject: Option<String>,
+    description: Option<String>,
+    version_id: Option<String>,
+}
+
+/// Commit every version outside the trash, oldest first, to a git repository: the text as
+/// Markdown, the assets as files, and the version's label, description,
+/// author and date as commit metadata. Versions committed by an earlier
+/// export are skipped, so exporting again only adds the new ones.
+#[tauri::command]
+pub fn export_versions_to_git(
+    sessions: State<'_, DocumentSessions>,
+    request: GitExportRequest,
+) -> Result<GitExportResponse, GitError> {
+    let path = PathBuf::from(&request.path);
+    sessions.read_through(&path, || export_versions(&path, &request))
+}
+
+/// Create a version for every first-parent commit that changed the
+/// Markdown file, oldest first, on top of the current branch head.
+/// Commits exported from versions the document still has are matched up
+/// instead of imported again. Formatting, tree and assets come along only
+/// while the Markdown matches what was exported; text edited in git is
+/// imported as text, and Markdown that is not UTF-8 is rejected. A
+/// document that does not exist yet is created with the newest version as
+/// its content.
+#[tauri::command]
+pub fn import_versions_from_git(
+    sessions: State<'_, DocumentSessions>,
+    request: GitImportRequest,
+) -> Result<GitImportResponse, GitError> {
+    let path = PathBuf::from(&request.path);
+    sessions.write_through(&path, || import_versions(&path, request))
+}
+
+// ============================================================================
+// Helper Functions
+// ============================================================================
+
+fn export_versions(path: &Path, request: &GitExportRequest) -> Result<GitExportResponse, GitError> {
+    let repo = PathBuf::from(&request.repo_path);
+    let payload = load_document(path)?;
+    let mut versions = payload
+        .versions
+        .iter()
+        .cloned()
+        .map(serde_json::from_value)
+        .collect::<Result<Vec<DocumentVersion>, _>>()?;
+    versions.retain(|v| v.deleted_at.is_none());
+    versions.sort_by_key(|v| (v.version_number, v.created_at));
+
+    let mut asset_bytes: BTreeMap<String, &[u8]> = payload
+        .asset_history
+        .iter()
+        .map(|(hash, bytes)| (hash.clone(), bytes.as_slice()))
+        .collect();
+    for asset in &payload.assets {
+        asset_bytes.insert(sha256_hex(&asset.bytes), &asset.bytes);
+    }
+
+    fs::create_dir_all(&repo)?;
+    if !repo.join(".git").exists() {
+        run_git(&repo, &["init", "-q"], &[])?;
+    }
+    let exported: HashSet<String> = log_commits(&repo, "HEAD", None)?
+        .into_iter()
+        .filter_map(|commit| commit.version_id)
+        .collect();
+
+    let file = markdown_name(path);
+    let sidecar = sidecar_name(&file);
+    let mut commits = Vec::new();
+    let mut skipped = 0;
+    for version in &versions {
+        if exported.contains(&version.id) {
+            skipped += 1;
+            continue;
+        }
+        fs::write(repo.join(&file), &version.content)?;
+        let meta = Sidecar {
+            content_hash: version.content_hash.clone(),
+            ranges: version.ranges.clone(),
+            document_tree: version.document_tree.clone(),
+            assets: version.assets.clone(),
+        };
+        fs::write(repo.join(&sidecar), serde_json::to_vec_pretty(&meta)?)?;
+        // Versions that captured only their text leave the files as they are.
+        if let Some(assets) = &version.assets {
+            write_assets(&repo, assets, &asset_bytes)?;
+        }
+
+        let mut paths = vec![file.as_str(), sidecar.as_str()];
+        if repo.join(ASSETS_DIR).exists() || is_tracked(&repo, ASSETS_DIR)? {
+            paths.push(ASSETS_DIR);
+        }
+        let mut add = vec!["add", "-A", "--"];
+        add.extend(paths);
+        run_git(&repo, &add, &[])?;
+
+        let subject = version
+            .label
+            .clone()
+            .unwrap_or_else(|| format!("Version {}", version.version_number));
+        let trailer = format!("{VERSION_TRAILER}: {}", version.id);
+        let mut commit = vec!["commit", "-q", "--allow-empty", "--no-verify", "--no-gpg-sign", "-m", subject.as_str()];
+        if let Some(description) = version.description.as_deref().filter(|d| !d.trim().is_empty()) {
+            commit.extend(["-m", description]);
+        }
+        commit.extend(["-m", trailer.as_str()]);
+        let author = version.author.clone().unwrap_or_else(|| UNKNOWN_AUTHOR.to_string());
+        let date = version.created_at.to_rfc2822();
+        let env = [
+            ("GIT_AUTHOR_NAME", author.as_str()),
+            ("GIT_AUTHOR_EMAIL", ""),
+            ("GIT_AUTHOR_DATE", date.as_str()),
+            ("GIT_COMMITTER_NAME", author.as_str()),
+            ("GIT_COMMITTER_EMAIL", ""),
+            ("GIT_COMMITTER_DATE", date.as_str()),
+        ];
+        run_git(&repo, &commit, &env)?;
+        let hash = run_git(&repo, &["rev-parse", "HEAD"], &[])?;
+        commits.push(ExportedCommit {
+            version_id: version.id.clone(),
+            commit: String::from_utf8_lossy(&hash).trim().to_string(),
+        });
+    }
+
+    Ok(GitExportResponse {
+        file,
+        commits,
+        skipped,
+    })
+}
+
+fn import_versions(path: &Path, request: GitImportRequest) -> Result<GitImportResponse, GitError> {
+    let repo = PathBuf::from(&request.repo_path);
+    let created = !path.exists();
+    let mut payload = load_or_create_payload(path)?;
+
+    let file = request.file.unwrap_or_else(|| markdown_name(path));
+    let sidecar = sidecar_name(&file);
+    let commits = log_commits(&repo, request.rev.as_deref().unwrap_or("HEAD"), Some(&file))?;
+    if commits.is_empty() {
+        return Err(GitError::NoHistory(file));
+    }
+
+    let summaries = version_store::summaries(&payload.versions);
+    let known: HashSet<&str> = summaries.iter().map(|v| v.id.as_str()).collect();
+    let mut head = payload
+        .branches
+        .heads
+        .get(&payload.branches.current)
+        .or(summaries.iter().rev().find(|v| v.deleted_at.is_none()).map(|v| &v.id))
+        .cloned();
+    let mut imported = Vec::new();
+    for commit in commits {
+        if let Some(version_id) = commit.version_id.filter(|id| known.contains(id.as_str())) {
+            head = Some(version_id);
+            continue;
+        }
+        let Some(content) = show_file(&repo, &commit.hash, &file)? else {
+            // The commit deleted the file.
+            continue;
+        };
+        let content =
+            String::from_utf8(content).map_err(|_| GitError::NotUtf8(file.clone(), commit.hash.clone()))?;
+        let mut version = DocumentVersion::new(next_version_number(&payload.versions), content, commit.subject);
+        version.created_at = commit.date;
+        version.author = Some(commit.author).filter(|author| author != UNKNOWN_AUTHOR);
+        version.description = commit.description;
+        version.parent_ids = head.take().into_iter().collect();
+
+        if let Some(meta) = show_file(&repo, &commit.hash, &sidecar)? {
+            let meta: Sidecar = serde_json::from_slice(&meta)?;
+            if meta.content_hash == version.content_hash {
+                version.ranges = meta.ranges;
+                version.document_tree = meta.document_tree;
+                if let Some(assets) = meta.assets {
+                    let mut captured = Vec::with_capacity(assets.len());
+                    for mut asset in assets {
+                        check_asset_name(&asset.name)?;
+                        let entry = format!("{ASSETS_DIR}/{}", asset.name);
+                        let bytes = show_file(&repo, &commit.hash, &entry)?
+                            .ok_or_else(|| GitError::MissingAsset(asset.name.clone()))?;
+                        asset.hash = sha256_hex(&bytes);
+                        payload.asset_history.insert(asset.hash.clone(), bytes);
+                        captured.push(asset);
+                    }
+                    version.assets = Some(captured);
+                }
+            }
+        }
+
+        head = Some(version.id.clone());
+        payload.versions.push(serde_json::to_value(&version)?);
+        imported.push(version);
+    }
+
+    if let Some(newest) = imported.last() {
+        let current = payload.branches.current.clone();
+        payload.branches.heads.insert(current, newest.id.clone());
+        if created {
+            payload.base_text = newest.content.clone();
+            payload.chunks = vec![PieceChunk::original(&newest.content)];
+            payload.metadata.ranges = newest.ranges.clone();
+            payload.document_tree = newest.document_tree.clone();
+            payload.assets = newest
+                .assets
+                .iter()
+                .flatten()
+                .map(|asset| {
+                    let bytes = payload
+                        .asset_history
+                        .get(&asset.hash)
+                        .ok_or_else(|| GitError::MissingAsset(asset.name.clone()))?;
+                    Ok(AssetRef {
+                        name: asset.name.clone(),
+                        target_pos: asset.target_pos,
+                        alt: asset.alt.clone(),
+                        size: asset.size,
+                        bytes: bytes.clone(),
+                    })
+                })
+                .collect::<Result<_, GitError>>()?;
+            repair_payload(&mut payload);
+        }
+        save_document(path, &payload)?;
+    }
+
+    Ok(GitImportResponse {
+        imported: imported.iter().map(DocumentVersion::to_summary).collect(),
+        all_versions: version_store::live_summaries(&payload.versions),
+    })
+}
+
+fn run_git(repo: &Path, args: &[&str], env: &[(&str, &str)]) -> Result<Vec<u8>, GitError> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(args)
+        .envs(env.iter().copied())
+        .output()?;
+    if !output.status.success() {
+        return Err(GitError::Command {
+            command: args.first().copied().unwrap_or_default().to_string(),
+            message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    Ok(output.stdout)
+}
+
+/// `<document name>.md`, the file the text is exported to.
+fn markdown_name(path: &Path) -> String {
+    let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("document");
+    format!("{stem}.md")
+}
+
+fn sidecar_name(file: &str) -> String {
+    format!("{}.meta.json", file.strip_suffix(".md").unwrap_or(file))
+}
+
+/// Asset names become file names; reject anything that could leave the
+/// assets directory.
+fn check_asset_name(name: &str) -> Result<(), GitError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+        return Err(GitError::InvalidAssetName(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Make the assets directory hold exactly `assets`.
+fn write_assets(repo: &Path, assets: &[VersionAsset], bytes: &BTreeMap<String, &[u8]>) -> Result<(), GitError> {
+    let dir = repo.join(ASSETS_DIR);
+    for asset in assets {
+        check_asset_name(&asset.name)?;
+    }
+    if dir.exists() {
+        let names: HashSet<&str> = assets.iter().map(|asset| asset.name.as_str()).collect();
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            if !entry.file_name().to_str().is_some_and(|name| names.contains(name)) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+    }
+    if assets.is_empty() {
+        return Ok(());
+    }
+    fs::create_dir_all(&dir)?;
+    for asset in assets {
+        let data = bytes
+            .get(&asset.hash)
+            .ok_or_else(|| GitError::MissingAsset(asset.name.clone()))?;
+        fs::write(dir.join(&asset.name), data)?;
+    }
+    Ok(())
+}
+
+fn is_tracked(repo: &Path, path: &str) -> Result<bool, GitError> {
+    Ok(!run_git(repo, &["ls-files", "--", path], &[])?.is_empty())
+}
+
+/// Contents of `file` at `commit`, or `None` if it does not exist there.
+fn show_file(repo: &Path, commit: &str, file: &str) -> Result<Option<Vec<u8>>, GitError> {
+    let object = format!("{commit}:{file}");
+    if run_git(repo, &["cat-file", "-e", &object], &[]).is_err() {
+        return Ok(None);
+    }
+    run_git(repo, &["cat-file", "blob", &object], &[]).map(Some)
+}
+
+/// First-parent commits up to `rev`, oldest first, optionally limited to
+/// those touching `file`. Empty for a repository without commits.
+fn log_commits(repo: &Path, rev: &str, file: Option<&str>) -> Result<Vec<LoggedCommit>, GitError> {
+    if run_git(repo, &["rev-parse", "--verify", "-q", rev], &[]).is_err() {
+        if rev == "HEAD" {
+            return Ok(Vec::new());
+        }
+        return Err(GitError::Command {
+            command: "rev-parse".to_string(),
+            message: format!("unknown revision {rev}"),
+        });
+    }
+    let mut args = vec!["log", "--reverse", "--first-parent", "--format=%H%x1f%an%x1f%aI%x1f%B%x1e", rev];
+    if let Some(file) = file {
+        args.extend(["--", file]);
+    }
+    let output = run_git(repo, &args, &[])?;
+    let output = String::from_utf8_lossy(&output);
+
+    let trailer = format!("{VERSION_TRAILER}:");
+    let mut commits = Vec::new();
+    for record in output.split('\x1e') {
+        let mut fields = record.trim_start_matches('\n').splitn(4, '\x1f');
+        let (Some(hash), Some(author), Some(date), Some(message)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let Ok(date) = DateTime::parse_from_rfc3339(date) else {
+            continue;
+        };
+        let mut version_id = None;
+        let mut lines = Vec::new();
+        for line in message.lines() {
+            match line.strip_prefix(&trailer) {
+                Some(id) => version_id = Some(id.trim().to_string()),
+                None => lines.push(line),
+            }
+        }
+        let subject = lines.first().map(|line| line.trim().to_string()).filter(|s| !s.is_empty());
+        let description = lines
+            .get(1..)
+            .map(|rest| rest.join("\n").trim().to_string())
+            .filter(|d| !d.is_empty());
+        commits.push(LoggedCommit {
+            hash: hash.to_string(),
+            author: author.to_string(),
+            date: date.with_timezone(&Utc),
+            subject,
+            description,
+            version_id,
+        });
+    }
+    Ok(commits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `name` inside a fresh temporary directory.
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("yeno-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    fn remove_temp(paths: &[&Path]) {
+        for path in paths {
+            fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        }
+    }
+
+    /// A document with two versions, the second with formatting.
+    fn sample_document() -> (PathBuf, Vec<DocumentVersion>) {
+        let path = temp_path("notes.grokedoc");
+        let mut first = DocumentVersion::new(1, "Hello\n".to_string(), Some("First".to_string()));
+        first.author = Some("Ada".to_string());
+        let mut second = DocumentVersion::new(2, "Hello\nworld\n".to_string(), Some("Second".to_string()));
+        second.description = Some("More text".to_string());
+        second.parent_ids = vec![first.id.clone()];
+        second.ranges = vec![MetadataRange {
+            start: 6,
+            end: 11,
+            attrs: [("bold".to_string(), serde_json::json!(true))].into(),
+            r#type: None,
+            level: None,
+        }];
+
+        let mut payload = load_or_create_payload(&path).unwrap();
+        payload.base_text = second.content.clone();
+        payload.chunks = vec![PieceChunk::original(&second.content)];
+        payload.versions = vec![serde_json::to_value(&first).unwrap(), serde_json::to_value(&second).unwrap()];
+        save_document(&path, &payload).unwrap();
+        (path, vec![first, second])
+    }
+
+    fn export(path: &Path, repo: &Path) -> GitExportResponse {
+        let request = GitExportRequest {
+            path: path.to_string_lossy().to_string(),
+            repo_path: repo.to_string_lossy().to_string(),
+        };
+        export_versions(path, &request).unwrap()
+    }
+
+    fn import(path: &Path, repo: &Path, file: &str) -> Result<GitImportResponse, GitError> {
+        let request = GitImportRequest {
+            path: path.to_string_lossy().to_string(),
+            repo_path: repo.to_string_lossy().to_string(),
+            file: Some(file.to_string()),
+            rev: None,
+        };
+        import_versions(path, request)
+    }
+
+    #[test]
+    fn export_then_import_round_trips_versions() {
+        let (source, versions) = sample_document();
+        let repo = temp_path("repo");
+        let exported = export(&source, &repo);
+        assert_eq!(exported.file, "notes.md");
+        assert_eq!(exported.commits.len(), 2);
+        let again = export(&source, &repo);
+        assert_eq!((again.commits.len(), again.skipped), (0, 2));
+
+        // Importing into the exported document only matches its versions up.
+        assert!(import(&source, &repo, &exported.file).unwrap().imported.is_empty());
+
+        let target = temp_path("copy.grokedoc");
+        let imported = import(&target, &repo, &exported.file).unwrap().imported;
+        assert_eq!(imported.len(), 2);
+        let payload = load_document(&target).unwrap();
+        let copies: Vec<DocumentVersion> =
+            payload.versions.iter().map(|v| serde_json::from_value(v.clone()).unwrap()).collect();
+        for (copy, original) in copies.iter().zip(&versions) {
+            assert_eq!(copy.content, original.content);
+            assert_eq!(copy.label, original.label);
+            assert_eq!(copy.author, original.author);
+            assert_eq!(copy.description, original.description);
+            assert_eq!(serde_json::to_value(&copy.ranges).unwrap(), serde_json::to_value(&original.ranges).unwrap());
+            assert_eq!(copy.created_at.timestamp(), original.created_at.timestamp());
+        }
+        assert_eq!(copies[1].parent_ids, vec![copies[0].id.clone()]);
+        assert_eq!(payload.base_text, "Hello\nworld\n");
+        assert_eq!(payload.metadata.ranges.len(), 1);
+        assert_eq!((payload.metadata.ranges[0].start, payload.metadata.ranges[0].end), (6, 11));
+
+        remove_temp(&[&source, &target, &repo]);
+    }
+
+    #[test]
+    fn import_rejects_markdown_that_is_not_utf8() {
+        let (source, _) = sample_document();
+        let repo = temp_path("repo");
+        let exported = export(&source, &repo);
+        fs::write(repo.join(&exported.file), b"caf\xe9\n").unwrap();
+        let env = [
+            ("GIT_AUTHOR_NAME", "Ada"),
+            ("GIT_AUTHOR_EMAIL", ""),
+            ("GIT_COMMITTER_NAME", "Ada"),
+            ("GIT_COMMITTER_EMAIL", ""),
+        ];
+        run_git(&repo, &["commit", "-q", "-a", "--no-verify", "--no-gpg-sign", "-m", "Latin-1"], &env).unwrap();
+
+        let target = temp_path("copy.grokedoc");
+        let err = import(&target, &repo, &exported.file).unwrap_err();
+        assert!(matches!(err, GitError::NotUtf8(ref file, _) if *file == exported.file), "{err}");
+        assert!(!target.exists());
+
+        remove_temp(&[&source, &target, &repo]);
+    }
+}
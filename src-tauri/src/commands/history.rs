@@ -0,0 +1,245 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use tauri::State;
+use thiserror::Error;
+
+use crate::commands::session::DocumentSessions;
+use crate::model::operation::{DocumentState, Operation, OperationError, OperationLog};
+use crate::model::piece_table::PieceChunk;
+use crate::model::rebase::{rebase_through_chunks, RebaseOptions, RebaseReport};
+use crate::storage::zip_container::{load_document, save_document, DocumentPayload, StorageError};
+
+#[derive(Debug, Error)]
+pub enum HistoryError {
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("operation error: {0}")]
+    Operation(#[from] OperationError),
+    #[error("document has no document tree: {0}")]
+    NoDocumentTree(String),
+    #[error("no history entry {0}")]
+    EntryNotFound(u64),
+    #[error("open document: {0}")]
+    Session(String),
+}
+
+impl Serialize for HistoryError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AppendOperationsRequest {
+    pub path: String,
+    /// Operations forming one undoable user action
+    pub operations: Vec<Operation>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryStateRequest {
+    pub path: String,
+    /// Log sequence number to rebuild the document at (0 = before the first entry)
+    pub seq: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryStateResponse {
+    pub state: DocumentState,
+    /// Sequence number of the latest log entry
+    pub head: u64,
+    pub can_undo: bool,
+    pub can_redo: bool,
+    /// Effect of the edit on metadata ranges and asset anchors
+    #[serde(default)]
+    pub rebase: RebaseReport,
+}
+
+/// Apply operations to the saved document and append them to its operation log.
+#[tauri::command]
+pub fn append_operations(
+    sessions: State<'_, DocumentSessions>,
+    request: AppendOperationsRequest,
+) -> Result<HistoryStateResponse, HistoryError> {
+    let path = PathBuf::from(&request.path);
+    sessions.write_through(&path, || {
+        let mut payload = load_document(&path)?;
+        sync_operation_log(&mut payload);
+        let mut state = current_state(&payload, &request.path)?;
+        let mut log = payload
+            .operations
+            .take()
+            .unwrap_or_else(|| OperationLog::new(state.clone()));
+
+        log.record(&mut state, request.operations)?;
+
+        let rebase = store_state(&mut payload, state, log);
+        save_document(&path, &payload)?;
+        Ok(HistoryStateResponse {
+            rebase,
+            ..history_response(&payload)
+        })
+    })
+}
+
+/// Undo the most recent edit in the operation log.
+#[tauri::command]
+pub fn undo_operation(
+    sessions: State<'_, DocumentSessions>,
+    path: String,
+) -> Result<HistoryStateResponse, HistoryError> {
+    step_history(&sessions, path, |log, state| log.undo(state).map(|entry| entry.is_some()))
+}
+
+/// Redo the most recently undone edit in the operation log.
+#[tauri::command]
+pub fn redo_operation(
+    sessions: State<'_, DocumentSessions>,
+    path: String,
+) -> Result<HistoryStateResponse, HistoryError> {
+    step_history(&sessions, path, |log, state| log.redo(state).map(|entry| entry.is_some()))
+}
+
+/// Rebuild the document as it was right after a given log entry.
+#[tauri::command]
+pub fn get_history_state(
+    sessions: State<'_, DocumentSessions>,
+    request: HistoryStateRequest,
+) -> Result<HistoryStateResponse, HistoryError> {
+    let path = PathBuf::from(&request.path);
+    sessions.read_through(&path, || {
+        let payload = load_document(&path)?;
+        let log = payload
+            .operations
+            .as_ref()
+            .ok_or(HistoryError::EntryNotFound(request.seq))?;
+        if request.seq > log.head() {
+            return Err(HistoryError::EntryNotFound(request.seq));
+        }
+        Ok(HistoryStateResponse {
+            state: log.replay(request.seq)?,
+            head: log.head(),
+            can_undo: log.can_undo(),
+            can_redo: log.can_redo(),
+            rebase: RebaseReport::default(),
+        })
+    })
+}
+
+// ============================================================================
+// Helper Functions
+// ============================================================================
+
+fn step_history(
+    sessions: &DocumentSessions,
+    path: String,
+    step: impl FnOnce(&mut OperationLog, &mut DocumentState) -> Result<bool, OperationError>,
+) -> Result<HistoryStateResponse, HistoryError> {
+    let file = PathBuf::from(&path);
+    sessions.write_through(&file, || {
+        let mut payload = load_document(&file)?;
+        sync_operation_log(&mut payload);
+        let mut state = current_state(&payload, &path)?;
+        let Some(mut log) = payload.operations.take() else {
+            return Ok(history_response(&payload));
+        };
+
+        let changed = step(&mut log, &mut state)?;
+
+        let rebase = store_state(&mut payload, state, log);
+        if changed {
+            save_document(&file, &payload)?;
+        }
+        Ok(HistoryStateResponse {
+            rebase,
+            ..history_response(&payload)
+        })
+    })
+}
+
+pub(crate) fn current_state(payload: &DocumentPayload, path: &str) -> Result<DocumentState, HistoryError> {
+    let document_tree = payload
+        .document_tree
+        .clone()
+        .ok_or_else(|| HistoryError::NoDocumentTree(path.to_string()))?;
+    Ok(DocumentState {
+        base_text: payload.base_text.clone(),
+        chunks: payload.chunks.clone(),
+        document_tree,
+    })
+}
+
+/// Start a fresh operation log when the document is no longer in the state
+/// the log ends in, as after a save, restore or chunk edit that bypassed it.
+/// Documents without a tree cannot replay operations and drop the log.
+pub(crate) fn sync_operation_log(payload: &mut DocumentPayload) {
+    let Some(log) = payload.operations.as_ref() else {
+        return;
+    };
+    let state = payload.document_tree.clone().map(|document_tree| DocumentState {
+        base_text: payload.base_text.clone(),
+        chunks: payload.chunks.clone(),
+        document_tree,
+    });
+    match state {
+        Some(state) if log.matches(&state) => {}
+        Some(state) => {
+            log::info!("operation log no longer matches the document, starting a new one");
+            payload.operations = Some(OperationLog::new(state));
+        }
+        None => payload.operations = None,
+    }
+}
+
+/// Write the new state into the payload and rebase metadata ranges and
+/// asset anchors through the chunks the edit appended.
+pub(crate) fn store_state(payload: &mut DocumentPayload, state: DocumentState, log: OperationLog) -> RebaseReport {
+    let applied = state.chunks.get(payload.chunks.len()..).unwrap_or_default().to_vec();
+    let report = rebase_applied(payload, &applied);
+
+    payload.base_text = state.base_text;
+    payload.chunks = state.chunks;
+    payload.document_tree = Some(state.document_tree);
+    payload.operations = Some(log);
+    report
+}
+
+/// Rebase metadata ranges and asset anchors through newly applied chunks.
+/// Stickiness rules can be configured per document under
+/// `metadata.custom.rebase`.
+pub(crate) fn rebase_applied(payload: &mut DocumentPayload, applied: &[PieceChunk]) -> RebaseReport {
+    let options: RebaseOptions = payload
+        .metadata
+        .custom
+        .get("rebase")
+        .and_then(|value| serde_json::from_value(value.clone()).ok())
+        .unwrap_or_default();
+    let report = rebase_through_chunks(&mut payload.metadata.ranges, &mut payload.assets, applied, &options);
+    for name in &report.orphaned_assets {
+        log::warn!("asset {} lost its anchor", name);
+    }
+    report
+}
+
+fn history_response(payload: &DocumentPayload) -> HistoryStateResponse {
+    let (head, can_undo, can_redo) = payload
+        .operations
+        .as_ref()
+        .map(|log| (log.head(), log.can_undo(), log.can_redo()))
+        .unwrap_or((0, false, false));
+    HistoryStateResponse {
+        state: DocumentState {
+            base_text: payload.base_text.clone(),
+            chunks: payload.chunks.clone(),
+            document_tree: payload.document_tree.clone().unwrap_or_default(),
+        },
+        head,
+        can_undo,
+        can_redo,
+        rebase: RebaseReport::default(),
+    }
+}
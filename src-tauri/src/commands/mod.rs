@@ -1,2 +1,5 @@
 pub mod document;
+pub mod git;
+pub mod history;
+pub mod session;
 pub mod versioning;
@@ -0,0 +1,1009 @@
+//! Documents held open in backend state.
+//!
+//! A session owns the full payload of one document, versions and asset
+//! history included, and commands addressed by its handle work on that
+//! state in memory. The editor sends only its edits, new assets and changed
+//! ranges; the session stays the authoritative copy. The archive is written
+//! only when the session is flushed: explicitly, when it is closed, on its
+//! own once it has been unchanged for [`FLUSH_DELAY`], or when the app exits.
+//!
+//! Path-based commands on a document that is open go through
+//! [`DocumentSessions::read_through`] or [`DocumentSessions::write_through`],
+//! which flush the session first and reload it after a change, so neither
+//! copy overwrites the other. Changes that cannot be written to the file are
+//! kept in a recovery copy beside it rather than dropped.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tauri::ipc::{InvokeBody, Request};
+use tauri::State;
+use thiserror::Error;
+
+use crate::commands::document::repair_payload;
+use crate::commands::git::GitError;
+use crate::commands::history::{current_state, rebase_applied, store_state, sync_operation_log, HistoryError};
+use crate::commands::versioning::{
+    auto_snapshot, compute_diff, find_version, graph_response, load_or_create_payload, next_version_number, normalize_tags,
+    payload_head, payload_text, pick_diff_side, restore_into, working_copy_version, working_state_version,
+    CreateVersionResponse, DeleteVersionResponse, DiffSide, GetVersionResponse, ListVersionsResponse, VersionError,
+    VersionFilter,
+};
+use crate::model::document_tree::DocumentTree;
+use crate::model::formatting::normalize_ranges;
+use crate::model::operation::{Operation, OperationLog};
+use crate::model::piece_table::{utf16_len, ChunkType, PieceChunk};
+use crate::model::rebase::RebaseReport;
+use crate::model::version::{DiffGranularity, DocumentVersion, VersionDiff, VersionSummary};
+use crate::storage::version_store;
+use crate::storage::zip_container::{save_document, AssetRef, DocumentPayload, MetadataRange, StorageError};
+
+/// A changed session is flushed on its own once it has been unchanged this long.
+pub const FLUSH_DELAY: Duration = Duration::from_secs(2);
+/// How often the background flusher looks for sessions to flush.
+const FLUSH_POLL: Duration = Duration::from_millis(500);
+/// Header carrying the [`PutAssetRequest`] when `put_asset` is sent the
+/// asset bytes as its body.
+pub const ASSET_HEADER: &str = "x-asset";
+
+#[derive(Debug, Error)]
+pub enum SessionError {
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("version error: {0}")]
+    Version(#[from] VersionError),
+    #[error("serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("no open document with handle {0}")]
+    UnknownHandle(String),
+    #[error("{0} was changed on disk after it was opened")]
+    ModifiedOnDisk(String),
+    #[error("history error: {0}")]
+    History(#[from] HistoryError),
+    #[error("edit is based on {given} chunks but the document has {actual}")]
+    OutOfSync { given: usize, actual: usize },
+    #[error("invalid edit: {0}")]
+    InvalidEdit(String),
+    #[error("asset not found: {0}")]
+    AssetNotFound(String),
+}
+
+impl Serialize for SessionError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+/// For path-based commands that went through an open session.
+impl From<SessionError> for HistoryError {
+    fn from(err: SessionError) -> Self {
+        match err {
+            SessionError::Storage(err) => HistoryError::Storage(err),
+            SessionError::History(err) => err,
+            err => HistoryError::Session(err.to_string()),
+        }
+    }
+}
+
+impl From<SessionError> for VersionError {
+    fn from(err: SessionError) -> Self {
+        match err {
+            SessionError::Storage(err) => VersionError::Storage(err),
+            SessionError::Version(err) => err,
+            SessionError::Serialization(err) => VersionError::Serialization(err),
+            err => VersionError::Session(err.to_string()),
+        }
+    }
+}
+
+impl From<SessionError> for GitError {
+    fn from(err: SessionError) -> Self {
+        GitError::Version(err.into())
+    }
+}
+
+/// The document commands report errors as strings.
+impl From<SessionError> for String {
+    fn from(err: SessionError) -> Self {
+        err.to_string()
+    }
+}
+
+/// One open document.
+pub struct DocumentSession {
+    pub path: PathBuf,
+    /// Authoritative state of the document
+    pub payload: DocumentPayload,
+    /// Bumped by every change
+    revision: u64,
+    /// Revision last written to disk
+    flushed_revision: u64,
+    /// When the background flusher may retry after a failed write
+    retry_at: Option<Instant>,
+    /// Revision last saved to the recovery copy
+    recovered_revision: Option<u64>,
+    changed_at: Instant,
+    /// Modification time of the file when it was last read or written
+    disk_modified: Option<SystemTime>,
+}
+
+impl DocumentSession {
+    fn open(path: PathBuf) -> Result<Self, SessionError> {
+        let mut payload = load_or_create_payload(&path)?;
+        repair_payload(&mut payload);
+        Ok(Self {
+            disk_modified: modified_time(&path),
+            path,
+            payload,
+            revision: 0,
+            flushed_revision: 0,
+            retry_at: None,
+            recovered_revision: None,
+            changed_at: Instant::now(),
+        })
+    }
+
+    /// Record a change to the payload.
+    pub fn touch(&mut self) {
+        self.revision += 1;
+        self.changed_at = Instant::now();
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.revision != self.flushed_revision
+    }
+
+    /// Write the payload if it has changes, repairing it and taking an
+    /// automatic version first as `save_grokedoc` does. Refuses to overwrite
+    /// a file changed by someone else unless `force` is set.
+    fn flush(&mut self, force: bool, closing: bool) -> Result<bool, SessionError> {
+        if !self.is_dirty() {
+            return Ok(false);
+        }
+        if !force && modified_time(&self.path) != self.disk_modified {
+            return Err(SessionError::ModifiedOnDisk(self.path.display().to_string()));
+        }
+        repair_payload(&mut self.payload);
+        auto_snapshot(&mut self.payload, closing)?;
+        save_document(&self.path, &self.payload)?;
+        self.flushed_revision = self.revision;
+        self.retry_at = None;
+        self.disk_modified = modified_time(&self.path);
+        Ok(true)
+    }
+
+    /// Re-read the document after a command changed the archive directly.
+    fn reload(&mut self) -> Result<(), SessionError> {
+        let mut payload = load_or_create_payload(&self.path)?;
+        repair_payload(&mut payload);
+        self.payload = payload;
+        self.flushed_revision = self.revision;
+        self.disk_modified = modified_time(&self.path);
+        Ok(())
+    }
+
+    /// Save the payload beside the file as `<name>.recovered.grokedoc`, for
+    /// changes that cannot be written to the file itself.
+    fn recover(&mut self) -> Result<PathBuf, SessionError> {
+        let copy = self.path.with_extension("recovered.grokedoc");
+        save_document(&copy, &self.payload)?;
+        self.recovered_revision = Some(self.revision);
+        Ok(copy)
+    }
+
+    /// Flush in the background. A file changed on disk is not overwritten;
+    /// the changes go to the recovery copy instead. Other failures are
+    /// retried after [`FLUSH_DELAY`].
+    fn flush_or_recover(&mut self, closing: bool) {
+        let err = match self.flush(false, closing) {
+            Ok(_) => return,
+            Err(err) => err,
+        };
+        log::warn!("could not flush {}: {}", self.path.display(), err);
+        if closing || matches!(err, SessionError::ModifiedOnDisk(_)) {
+            match self.recover() {
+                Ok(copy) => {
+                    log::warn!("saved unflushed changes of {} to {}", self.path.display(), copy.display());
+                    return;
+                }
+                Err(err) => log::error!("could not save a recovery copy of {}: {}", self.path.display(), err),
+            }
+        }
+        self.retry_at = Some(Instant::now() + FLUSH_DELAY);
+    }
+
+    /// Whether the background flusher has anything to do.
+    fn needs_flush(&self) -> bool {
+        self.is_dirty()
+            && self.recovered_revision != Some(self.revision)
+            && self.retry_at.is_none_or(|at| Instant::now() >= at)
+    }
+}
+
+/// Open documents by handle, held in Tauri managed state. Cloning shares
+/// the same sessions.
+#[derive(Clone, Default)]
+pub struct DocumentSessions {
+    sessions: Arc<Mutex<OpenSessions>>,
+}
+
+/// The session map. Its lock is only held to look sessions up, never while
+/// a session is locked or the disk is accessed.
+#[derive(Default)]
+struct OpenSessions {
+    by_handle: HashMap<String, Arc<Mutex<DocumentSession>>>,
+    /// Handle of the session open on each canonical path
+    by_path: HashMap<PathBuf, String>,
+}
+
+impl DocumentSessions {
+    /// Open the document at `path` and return its handle. A document that
+    /// is already open keeps its session and handle.
+    pub fn open(&self, path: &Path) -> Result<String, SessionError> {
+        let path = canonical_path(path);
+        if let Some(handle) = lock(&self.sessions).by_path.get(&path) {
+            return Ok(handle.clone());
+        }
+        let session = DocumentSession::open(path.clone())?;
+        let mut sessions = lock(&self.sessions);
+        // Another command may have opened it while the file was read.
+        if let Some(handle) = sessions.by_path.get(&path) {
+            return Ok(handle.clone());
+        }
+        let handle = uuid::Uuid::new_v4().to_string();
+        sessions.by_path.insert(path, handle.clone());
+        sessions.by_handle.insert(handle.clone(), Arc::new(Mutex::new(session)));
+        Ok(handle)
+    }
+
+    /// Run `f` on the session with `handle`. Other sessions stay usable
+    /// meanwhile.
+    pub fn with<T>(
+        &self,
+        handle: &str,
+        f: impl FnOnce(&mut DocumentSession) -> Result<T, SessionError>,
+    ) -> Result<T, SessionError> {
+        let session = lock(&self.sessions)
+            .by_handle
+            .get(handle)
+            .cloned()
+            .ok_or_else(|| SessionError::UnknownHandle(handle.to_string()))?;
+        let mut session = lock(&session);
+        f(&mut session)
+    }
+
+    /// The session open on `path`, if any.
+    fn find(&self, path: &Path) -> Option<Arc<Mutex<DocumentSession>>> {
+        let path = canonical_path(path);
+        let sessions = lock(&self.sessions);
+        let handle = sessions.by_path.get(&path)?;
+        sessions.by_handle.get(handle).cloned()
+    }
+
+    /// Run `f`, a command that reads the archive at `path`. If the document
+    /// is open, its unflushed changes are written first.
+    pub fn read_through<T, E: From<SessionError>>(
+        &self,
+        path: &Path,
+        f: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let Some(session) = self.find(path) else {
+            return f();
+        };
+        let mut session = lock(&session);
+        session.flush(false, false)?;
+        f()
+    }
+
+    /// Run `f`, a command that changes the archive at `path`. If the
+    /// document is open, its unflushed changes are written first and the
+    /// session is reloaded afterwards. The session stays locked meanwhile.
+    pub fn write_through<T, E: From<SessionError>>(
+        &self,
+        path: &Path,
+        f: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let Some(session) = self.find(path) else {
+            return f();
+        };
+        let mut session = lock(&session);
+        session.flush(false, false)?;
+        let result = f();
+        session.reload()?;
+        result
+    }
+
+    /// Write the session's changes, if any. Returns whether it wrote.
+    pub fn flush(&self, handle: &str, force: bool) -> Result<bool, SessionError> {
+        self.with(handle, |session| session.flush(force, false))
+    }
+
+    /// Flush the session unless `discard` is set, then forget it. A session
+    /// that fails to flush stays open.
+    pub fn close(&self, handle: &str, discard: bool) -> Result<bool, SessionError> {
+        let flushed = if discard {
+            false
+        } else {
+            self.with(handle, |session| session.flush(false, true))?
+        };
+        let mut sessions = lock(&self.sessions);
+        sessions.by_handle.remove(handle);
+        sessions.by_path.retain(|_, open| open != handle);
+        Ok(flushed)
+    }
+
+    /// Flush every session whose last change is at least `delay` old.
+    pub fn flush_idle(&self, delay: Duration) {
+        let sessions: Vec<_> = lock(&self.sessions).by_handle.values().cloned().collect();
+        for session in sessions {
+            let mut session = lock(&session);
+            if session.needs_flush() && session.changed_at.elapsed() >= delay {
+                session.flush_or_recover(false);
+            }
+        }
+    }
+
+    /// Flush every changed session now, as when the app exits. Sessions
+    /// that cannot be written go to their recovery copy.
+    pub fn flush_all(&self) {
+        let sessions: Vec<_> = lock(&self.sessions).by_handle.values().cloned().collect();
+        for session in sessions {
+            let mut session = lock(&session);
+            if session.is_dirty() && session.recovered_revision != Some(session.revision) {
+                session.flush_or_recover(true);
+            }
+        }
+    }
+
+    /// Flush changed sessions in the background once they have been
+    /// unchanged for [`FLUSH_DELAY`].
+    pub fn start_autoflush(&self) {
+        let sessions = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(FLUSH_POLL);
+            sessions.flush_idle(FLUSH_DELAY);
+        });
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OpenDocumentResponse {
+    pub handle: String,
+    /// The document without its versions
+    pub payload: DocumentPayload,
+    pub versions: Vec<VersionSummary>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FlushDocumentRequest {
+    pub handle: String,
+    /// Overwrite the file even if it was changed on disk after it was opened
+    #[serde(default)]
+    pub force: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CloseDocumentRequest {
+    pub handle: String,
+    /// Drop unflushed changes instead of writing them
+    #[serde(default)]
+    pub discard: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FlushDocumentResponse {
+    /// Whether there were changes to write
+    pub flushed: bool,
+}
+
+/// Captures the session's current state; see `create_version`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionCreateVersionRequest {
+    pub handle: String,
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub author: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub pinned: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionVersionRequest {
+    pub handle: String,
+    pub version_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionDeleteVersionRequest {
+    pub handle: String,
+    pub version_id: String,
+    /// Remove the version for good instead of moving it to the trash
+    #[serde(default)]
+    pub permanent: bool,
+}
+
+/// See `diff_versions`. A `working` side without state is the session's
+/// current state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionDiffVersionsRequest {
+    pub handle: String,
+    #[serde(default)]
+    pub from_version_id: Option<String>,
+    #[serde(default)]
+    pub to_version_id: Option<String>,
+    #[serde(default)]
+    pub from: Option<DiffSide>,
+    #[serde(default)]
+    pub to: Option<DiffSide>,
+    #[serde(default)]
+    pub granularity: DiffGranularity,
+}
+
+/// An edit made in the editor.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum DocumentEdit {
+    /// Chunks the editor's buffer appended since it held `chunk_count`
+    /// chunks, with the tree they leave behind if it changed
+    Chunks {
+        chunk_count: usize,
+        chunks: Vec<PieceChunk>,
+        #[serde(default)]
+        document_tree: Option<DocumentTree>,
+    },
+    /// Operations forming one undoable user action; see `append_operations`
+    Operations { operations: Vec<Operation> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApplyEditsRequest {
+    pub handle: String,
+    pub edit: DocumentEdit,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApplyEditsResponse {
+    /// Chunks the document now has; the next chunk edit is based on this
+    pub chunk_count: usize,
+    pub can_undo: bool,
+    pub can_redo: bool,
+    /// Effect of the edit on metadata ranges and asset anchors
+    pub rebase: RebaseReport,
+}
+
+/// Adds an asset or updates one with the same name. Sent as the JSON
+/// arguments of `put_asset` when only the anchor, alt text or size changed,
+/// or URI-encoded in the [`ASSET_HEADER`] header with the asset bytes as the
+/// raw request body.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PutAssetRequest {
+    pub handle: String,
+    pub name: String,
+    pub target_pos: usize,
+    pub alt: String,
+    pub size: (u32, u32),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateMetadataRangesRequest {
+    pub handle: String,
+    /// Ranges laid over the existing ones: later attributes win and a
+    /// `null` value unsets a key
+    pub ranges: Vec<MetadataRange>,
+    /// Replace all ranges instead
+    #[serde(default)]
+    pub replace: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateMetadataRangesResponse {
+    /// All ranges of the document, normalized
+    pub ranges: Vec<MetadataRange>,
+}
+
+/// Open a document for editing in backend state.
+#[tauri::command]
+pub fn open_document(sessions: State<'_, DocumentSessions>, path: String) -> Result<OpenDocumentResponse, SessionError> {
+    let handle = sessions.open(Path::new(&path))?;
+    sessions.with(&handle, |session| {
+        let mut payload = session.payload.clone();
+        let versions = version_store::live_summaries(&std::mem::take(&mut payload.versions));
+        Ok(OpenDocumentResponse {
+            handle: handle.clone(),
+            payload,
+            versions,
+        })
+    })
+}
+
+#[tauri::command]
+pub fn flush_document(
+    sessions: State<'_, DocumentSessions>,
+    request: FlushDocumentRequest,
+) -> Result<FlushDocumentResponse, SessionError> {
+    let flushed = sessions.flush(&request.handle, request.force)?;
+    Ok(FlushDocumentResponse { flushed })
+}
+
+#[tauri::command]
+pub fn close_document(
+    sessions: State<'_, DocumentSessions>,
+    request: CloseDocumentRequest,
+) -> Result<FlushDocumentResponse, SessionError> {
+    let flushed = sessions.close(&request.handle, request.discard)?;
+    Ok(FlushDocumentResponse { flushed })
+}
+
+/// `create_version` on an open document: snapshots its current state.
+#[tauri::command]
+pub fn session_create_version(
+    sessions: State<'_, DocumentSessions>,
+    request: SessionCreateVersionRequest,
+) -> Result<CreateVersionResponse, SessionError> {
+    sessions.with(&request.handle, |session| {
+        let payload = &mut session.payload;
+        let mut version =
+            DocumentVersion::new(next_version_number(&payload.versions), payload_text(payload), request.label);
+        version.parent_ids = payload_head(payload).into_iter().collect();
+        version.capture_state(payload);
+        version.author = request.author;
+        version.description = request.description;
+        version.tags = normalize_tags(request.tags);
+        version.pinned = request.pinned;
+
+        payload.versions.push(serde_json::to_value(&version)?);
+        let current = payload.branches.current.clone();
+        payload.branches.heads.insert(current, version.id.clone());
+        session.touch();
+        Ok(CreateVersionResponse {
+            version,
+            all_versions: version_store::live_summaries(&session.payload.versions),
+        })
+    })
+}
+
+#[tauri::command]
+pub fn session_list_versions(
+    sessions: State<'_, DocumentSessions>,
+    handle: String,
+    filter: Option<VersionFilter>,
+) -> Result<ListVersionsResponse, SessionError> {
+    sessions.with(&handle, |session| {
+        let payload = &session.payload;
+        let mut response = graph_response(version_store::loaded_index(&payload.versions, &payload.branches));
+        if let Some(filter) = filter {
+            response.versions.retain(|v| filter.matches(v));
+        }
+        Ok(response)
+    })
+}
+
+#[tauri::command]
+pub fn session_get_version(
+    sessions: State<'_, DocumentSessions>,
+    handle: String,
+    version_id: String,
+) -> Result<GetVersionResponse, SessionError> {
+    sessions.with(&handle, |session| {
+        let version = find_version(&session.payload.versions, &version_id)?;
+        Ok(GetVersionResponse { version })
+    })
+}
+
+#[tauri::command]
+pub fn session_diff_versions(
+    sessions: State<'_, DocumentSessions>,
+    request: SessionDiffVersionsRequest,
+) -> Result<VersionDiff, SessionError> {
+    sessions.with(&request.handle, |session| {
+        let from = resolve_side(&session.payload, request.from, request.from_version_id, "from")?;
+        let to = resolve_side(&session.payload, request.to, request.to_version_id, "to")?;
+        Ok(compute_diff(from, to, request.granularity)?)
+    })
+}
+
+/// `restore_version` on an open document.
+#[tauri::command]
+pub fn session_restore_version(
+    sessions: State<'_, DocumentSessions>,
+    request: SessionVersionRequest,
+) -> Result<CreateVersionResponse, SessionError> {
+    sessions.with(&request.handle, |session| {
+        let version = restore_into(&mut session.payload, &request.version_id)?;
+        session.touch();
+        Ok(CreateVersionResponse {
+            version,
+            all_versions: version_store::live_summaries(&session.payload.versions),
+        })
+    })
+}
+
+/// `delete_version` on an open document.
+#[tauri::command]
+pub fn session_delete_version(
+    sessions: State<'_, DocumentSessions>,
+    request: SessionDeleteVersionRequest,
+) -> Result<DeleteVersionResponse, SessionError> {
+    sessions.with(&request.handle, |session| {
+        let payload = &mut session.payload;
+        let found = if request.permanent {
+            version_store::remove_loaded(&mut payload.versions, &mut payload.branches, &request.version_id)?
+        } else {
+            version_store::trash_loaded(&mut payload.versions, &mut payload.branches, &request.version_id, Utc::now())?
+        };
+        if !found {
+            return Err(VersionError::NotFound(request.version_id).into());
+        }
+        session.touch();
+        Ok(DeleteVersionResponse {
+            versions: version_store::live_summaries(&session.payload.versions),
+        })
+    })
+}
+
+/// Apply an edit from the editor to an open document. Chunk edits must be
+/// based on the document's current chunks; metadata ranges and asset
+/// anchors are rebased through them either way.
+#[tauri::command]
+pub fn apply_edits(
+    sessions: State<'_, DocumentSessions>,
+    request: ApplyEditsRequest,
+) -> Result<ApplyEditsResponse, SessionError> {
+    sessions.with(&request.handle, |session| {
+        let path = session.path.display().to_string();
+        let payload = &mut session.payload;
+        let rebase = match request.edit {
+            DocumentEdit::Chunks {
+                chunk_count,
+                chunks,
+                document_tree,
+            } => {
+                if chunk_count != payload.chunks.len() {
+                    return Err(SessionError::OutOfSync {
+                        given: chunk_count,
+                        actual: payload.chunks.len(),
+                    });
+                }
+                check_chunks(&chunks, utf16_len(&payload_text(payload)))?;
+                let rebase = rebase_applied(payload, &chunks);
+                payload.chunks.extend(chunks);
+                if let Some(tree) = document_tree {
+                    payload.document_tree = Some(tree);
+                }
+                // Versions taken before the next flush capture this tree, so
+                // it is fitted to the edited text now rather than on flush.
+                repair_payload(payload);
+                sync_operation_log(payload);
+                rebase
+            }
+            DocumentEdit::Operations { operations } => {
+                let mut state = current_state(payload, &path)?;
+                let mut log = payload
+                    .operations
+                    .take()
+                    .unwrap_or_else(|| OperationLog::new(state.clone()));
+                if let Err(err) = log.record(&mut state, operations) {
+                    payload.operations = Some(log);
+                    return Err(HistoryError::from(err).into());
+                }
+                store_state(payload, state, log)
+            }
+        };
+        session.touch();
+
+        let log = session.payload.operations.as_ref();
+        Ok(ApplyEditsResponse {
+            chunk_count: session.payload.chunks.len(),
+            can_undo: log.is_some_and(OperationLog::can_undo),
+            can_redo: log.is_some_and(OperationLog::can_redo),
+            rebase,
+        })
+    })
+}
+
+/// Add or update an asset of an open document. New asset bytes come as
+/// the raw request body rather than a JSON array of numbers.
+#[tauri::command]
+pub fn put_asset(sessions: State<'_, DocumentSessions>, request: Request<'_>) -> Result<(), SessionError> {
+    let (request, bytes) = match request.body() {
+        InvokeBody::Json(args) => (PutAssetRequest::deserialize(args)?, None),
+        InvokeBody::Raw(bytes) => {
+            let header = request
+                .headers()
+                .get(ASSET_HEADER)
+                .and_then(|value| percent_decode(value.as_bytes()))
+                .ok_or_else(|| SessionError::InvalidEdit(format!("missing or malformed {ASSET_HEADER} header")))?;
+            (serde_json::from_str::<PutAssetRequest>(&header)?, Some(bytes.clone()))
+        }
+    };
+    if request.name.is_empty()
+        || request.name == "."
+        || request.name == ".."
+        || request.name.contains(['/', '\\'])
+        || request.name == "rels.json"
+    {
+        return Err(SessionError::InvalidEdit(format!("invalid asset name {}", request.name)));
+    }
+    sessions.with(&request.handle, |session| {
+        let payload = &mut session.payload;
+        let len = utf16_len(&payload_text(payload));
+        if request.target_pos > len {
+            return Err(SessionError::InvalidEdit(format!(
+                "asset anchor {} is past the end of the text ({})",
+                request.target_pos, len
+            )));
+        }
+        match payload.assets.iter_mut().find(|asset| asset.name == request.name) {
+            Some(asset) => {
+                asset.target_pos = request.target_pos;
+                asset.alt = request.alt;
+                asset.size = request.size;
+                if let Some(bytes) = bytes {
+                    asset.bytes = bytes;
+                }
+            }
+            None => {
+                let bytes = bytes.ok_or_else(|| SessionError::AssetNotFound(request.name.clone()))?;
+                payload.assets.push(AssetRef {
+                    name: request.name,
+                    target_pos: request.target_pos,
+                    alt: request.alt,
+                    size: request.size,
+                    bytes,
+                });
+            }
+        }
+        session.touch();
+        Ok(())
+    })
+}
+
+/// Lay formatting ranges over those of an open document, or replace them.
+#[tauri::command]
+pub fn update_metadata_ranges(
+    sessions: State<'_, DocumentSessions>,
+    request: UpdateMetadataRangesRequest,
+) -> Result<UpdateMetadataRangesResponse, SessionError> {
+    sessions.with(&request.handle, |session| {
+        let payload = &mut session.payload;
+        let len = utf16_len(&payload_text(payload));
+        if let Some(range) = request.ranges.iter().find(|range| range.start > range.end || range.end > len) {
+            return Err(SessionError::InvalidEdit(format!(
+                "range {}..{} does not fit a text of length {}",
+                range.start, range.end, len
+            )));
+        }
+        if request.replace {
+            payload.metadata.ranges = request.ranges;
+        } else {
+            payload.metadata.ranges.extend(request.ranges);
+        }
+        normalize_ranges(&mut payload.metadata.ranges);
+        session.touch();
+        Ok(UpdateMetadataRangesResponse {
+            ranges: session.payload.metadata.ranges.clone(),
+        })
+    })
+}
+
+// ============================================================================
+// Helper Functions
+// ============================================================================
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// `path` made absolute with symlinks resolved, so that one file always
+/// maps to one session. For a file that does not exist yet, its directory
+/// is resolved instead.
+fn canonical_path(path: &Path) -> PathBuf {
+    if let Ok(path) = fs::canonicalize(path) {
+        return path;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => {
+            let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+            fs::canonicalize(dir).map_or_else(|_| path.to_path_buf(), |dir| dir.join(name))
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Undo the `%XX` escapes of `encodeURIComponent`.
+fn percent_decode(input: &[u8]) -> Option<String> {
+    let mut decoded = Vec::with_capacity(input.len());
+    let mut rest = input;
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail.get(..2).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+            decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            decoded.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn resolve_side(
+    payload: &DocumentPayload,
+    side: Option<DiffSide>,
+    version_id: Option<String>,
+    name: &str,
+) -> Result<DocumentVersion, VersionError> {
+    match pick_diff_side(side, version_id, name)? {
+        DiffSide::Version { version_id } => find_version(&payload.versions, &version_id),
+        DiffSide::Working { state: Some(state) } => Ok(working_state_version(state)),
+        DiffSide::Working { state: None } => Ok(working_copy_version(payload)),
+    }
+}
+
+/// Check that `chunks` apply cleanly, in order, to a text `len` UTF-16
+/// units long.
+fn check_chunks(chunks: &[PieceChunk], mut len: usize) -> Result<(), SessionError> {
+    for chunk in chunks {
+        let fits = match chunk.kind {
+            ChunkType::Insert => match (chunk.pos, &chunk.data) {
+                (Some(pos), Some(data)) if pos <= len => {
+                    len += utf16_len(data);
+                    true
+                }
+                _ => false,
+            },
+            ChunkType::Delete => match (chunk.pos, chunk.len) {
+                (Some(pos), Some(deleted)) if pos.checked_add(deleted).is_some_and(|end| end <= len) => {
+                    len -= deleted;
+                    true
+                }
+                _ => false,
+            },
+            // Original chunks are only ever the base text of the document
+            ChunkType::Original => {
+                return Err(SessionError::InvalidEdit("original chunks cannot be sent as edits".to_string()));
+            }
+        };
+        if !fits {
+            return Err(SessionError::InvalidEdit(format!(
+                "{:?} chunk at {:?} does not fit a text of length {}",
+                chunk.kind, chunk.pos, len
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::zip_container::load_document;
+
+    /// A saved document with `text`, in a fresh temporary directory.
+    fn temp_document(text: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("yeno-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("doc.grokedoc");
+        write_text(&path, text);
+        path
+    }
+
+    /// Replace the text of the archive at `path`, as another program would.
+    fn write_text(path: &Path, text: &str) {
+        let mut payload = load_or_create_payload(path).unwrap();
+        payload.base_text = text.to_string();
+        payload.chunks = vec![PieceChunk::original(text)];
+        save_document(path, &payload).unwrap();
+    }
+
+    fn set_text(sessions: &DocumentSessions, handle: &str, text: &str) {
+        sessions
+            .with(handle, |session| {
+                session.payload.base_text = text.to_string();
+                session.payload.chunks = vec![PieceChunk::original(text)];
+                session.touch();
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    fn session_text(sessions: &DocumentSessions, handle: &str) -> String {
+        sessions.with(handle, |session| Ok(session.payload.base_text.clone())).unwrap()
+    }
+
+    #[test]
+    fn flush_writes_changes_and_write_through_reloads() {
+        let path = temp_document("one");
+        let sessions = DocumentSessions::default();
+        let handle = sessions.open(&path).unwrap();
+        assert_eq!(sessions.open(&path.parent().unwrap().join(".").join("doc.grokedoc")).unwrap(), handle);
+
+        set_text(&sessions, &handle, "two");
+        assert!(sessions.flush(&handle, false).unwrap());
+        assert!(!sessions.flush(&handle, false).unwrap());
+        assert_eq!(load_document(&path).unwrap().base_text, "two");
+
+        // A command changing the archive sees the flushed text and leaves
+        // the session with its result.
+        set_text(&sessions, &handle, "three");
+        let seen = sessions
+            .write_through(&path, || -> Result<String, SessionError> {
+                let text = load_document(&path)?.base_text;
+                write_text(&path, "four");
+                Ok(text)
+            })
+            .unwrap();
+        assert_eq!(seen, "three");
+        assert_eq!(session_text(&sessions, &handle), "four");
+        assert!(!sessions.with(&handle, |session| Ok(session.is_dirty())).unwrap());
+
+        assert!(!sessions.close(&handle, false).unwrap());
+        assert!(matches!(sessions.with(&handle, |_| Ok(())), Err(SessionError::UnknownHandle(_))));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn changes_on_disk_are_not_overwritten() {
+        let path = temp_document("one");
+        let sessions = DocumentSessions::default();
+        let handle = sessions.open(&path).unwrap();
+        set_text(&sessions, &handle, "ours");
+
+        write_text(&path, "theirs");
+        let later = SystemTime::now() + Duration::from_secs(5);
+        fs::File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+
+        assert!(matches!(sessions.flush(&handle, false), Err(SessionError::ModifiedOnDisk(_))));
+        let read = sessions.read_through(&path, || Ok::<_, SessionError>(()));
+        assert!(matches!(read, Err(SessionError::ModifiedOnDisk(_))));
+        let mut ran = false;
+        let write = sessions.write_through(&path, || {
+            ran = true;
+            Ok::<_, SessionError>(())
+        });
+        assert!(matches!(write, Err(SessionError::ModifiedOnDisk(_))));
+        assert!(!ran);
+
+        // Unflushed changes go to the recovery copy and the file keeps
+        // the other program's text.
+        sessions.flush_all();
+        let copy = path.with_extension("recovered.grokedoc");
+        assert_eq!(load_document(&copy).unwrap().base_text, "ours");
+        assert_eq!(load_document(&path).unwrap().base_text, "theirs");
+        assert!(!sessions.with(&handle, |session| Ok(session.needs_flush())).unwrap());
+
+        // Forcing the flush overwrites it, after which commands go through.
+        assert!(sessions.flush(&handle, true).unwrap());
+        assert_eq!(load_document(&path).unwrap().base_text, "ours");
+        assert!(sessions.read_through(&path, || Ok::<_, SessionError>(())).is_ok());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
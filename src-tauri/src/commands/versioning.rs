@@ -1,13 +1,37 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
-use similar::{ChangeTag, TextDiff};
+use similar::{ChangeTag, DiffOp, TextDiff};
+use tauri::State;
 use thiserror::Error;
 
-use crate::model::piece_table::{ChunkType, PieceChunk};
-use crate::model::version::{DiffHunk, DiffLine, DiffLineKind, DocumentVersion, VersionDiff, VersionSummary};
-use crate::storage::zip_container::{load_document, save_document, DocumentPayload, StorageError};
+use crate::commands::document::repair_payload;
+use crate::commands::history::sync_operation_log;
+use crate::commands::session::DocumentSessions;
+use crate::model::blame::{self, BlameLine};
+use crate::model::consistency;
+use crate::model::document_tree::{BlockNode, DocumentTree};
+use crate::model::formatting::normalize_ranges;
+use crate::model::merge::{self, MergeConflict, MergeSide, Resolution};
+use crate::model::partial_restore::{self, PartialRestoreError, RestoreSelection};
+use crate::model::piece_table::{utf16_len, PieceChunk, PieceTableContent};
+use crate::model::rebase::RebaseOptions;
+use crate::model::retention::{expired_trash, plan_pruning, trash_expiry, PrunedVersion, RetentionPolicy};
+use crate::model::stats::{self, StatsInterval, TargetProgress, TimelineBucket, VersionStats};
+use crate::model::tree_diff::{self, TreeDiff};
+use crate::model::version::{
+    AutoVersionPolicy, DiffGranularity, DiffHunk, DiffLine, DiffLineKind, DiffSpan, DocumentVersion,
+    VersionAsset, VersionBranches, VersionDiff, VersionSummary,
+};
+use crate::storage::checksum::sha256_hex;
+use crate::storage::version_store::{self, VersionIndex};
+use crate::storage::zip_container::{
+    load_document, load_metadata, load_working_copy, save_document, AssetRef, DocumentPayload, MetadataPayload,
+    MetadataRange, StorageError,
+};
 
 #[derive(Debug, Error)]
 pub enum VersionError {
@@ -17,6 +41,36 @@ pub enum VersionError {
     NotFound(String),
     #[error("serialization error: {0}")]
     Serialization(#[from] serde_json::Error),
+    #[error("branch not found: {0}")]
+    BranchNotFound(String),
+    #[error("branch already exists: {0}")]
+    BranchExists(String),
+    #[error("invalid branch name: {0:?}")]
+    InvalidBranchName(String),
+    #[error("cannot delete the current branch: {0}")]
+    CurrentBranch(String),
+    #[error("versions {0} and {1} have no common ancestor")]
+    NoCommonAncestor(String, String),
+    #[error("bytes of asset {0} are missing")]
+    MissingAsset(String),
+    #[error("no version or working copy given for the {0} side of the diff")]
+    MissingDiffSide(String),
+    #[error("version has no document tree: {0}")]
+    NoDocumentTree(String),
+    #[error("version is not in the trash: {0}")]
+    NotInTrash(String),
+    #[error("cannot squash: {0}")]
+    InvalidSquash(String),
+    #[error("partial restore failed: {0}")]
+    PartialRestore(#[from] PartialRestoreError),
+    #[error("open document: {0}")]
+    Session(String),
+}
+
+impl Serialize for VersionError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -25,6 +79,17 @@ pub struct CreateVersionRequest {
     pub path: String,
     pub content: String,
     pub label: Option<String>,
+    /// Formatting ranges over `content`
+    #[serde(default)]
+    pub ranges: Vec<MetadataRange>,
+    #[serde(default)]
+    pub author: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub pinned: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -34,11 +99,27 @@ pub struct CreateVersionResponse {
     pub all_versions: Vec<VersionSummary>,
 }
 
+/// Narrows `list_versions`; every given criterion must match.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionFilter {
+    #[serde(default)]
+    pub tag: Option<String>,
+    #[serde(default)]
+    pub author: Option<String>,
+    #[serde(default)]
+    pub pinned: Option<bool>,
+    #[serde(default)]
+    pub auto: Option<bool>,
+}
+
+/// The version graph: versions link to their parents, branches name heads.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ListVersionsResponse {
     pub versions: Vec<VersionSummary>,
     pub current_version_number: u32,
+    pub branches: VersionBranches,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -47,12 +128,62 @@ pub struct GetVersionResponse {
     pub version: DocumentVersion,
 }
 
+/// Either side of a diff is a stored version or the working copy. The
+/// version IDs are shorthand for a `version` side.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DiffVersionsRequest {
     pub path: String,
-    pub from_version_id: String,
-    pub to_version_id: String,
+    #[serde(default)]
+    pub from_version_id: Option<String>,
+    #[serde(default)]
+    pub to_version_id: Option<String>,
+    /// Takes precedence over `fromVersionId`
+    #[serde(default)]
+    pub from: Option<DiffSide>,
+    /// Takes precedence over `toVersionId`
+    #[serde(default)]
+    pub to: Option<DiffSide>,
+    /// Unit of the intraline spans on changed lines (words unless `char`)
+    #[serde(default)]
+    pub granularity: DiffGranularity,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiffTreesRequest {
+    pub path: String,
+    #[serde(default)]
+    pub from_version_id: Option<String>,
+    #[serde(default)]
+    pub to_version_id: Option<String>,
+    #[serde(default)]
+    pub from: Option<DiffSide>,
+    #[serde(default)]
+    pub to: Option<DiffSide>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum DiffSide {
+    Version { version_id: String },
+    /// The working copy as given, or as saved when `state` is omitted
+    Working {
+        #[serde(default)]
+        state: Option<WorkingState>,
+    },
+}
+
+/// Unsaved working state sent by the editor.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkingState {
+    pub base_text: String,
+    pub chunks: Vec<PieceChunk>,
+    #[serde(default)]
+    pub metadata: MetadataPayload,
+    #[serde(default)]
+    pub document_tree: Option<DocumentTree>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -62,11 +193,86 @@ pub struct RestoreVersionRequest {
     pub version_id: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RestoreSelectionRequest {
+    pub path: String,
+    pub version_id: String,
+    pub selection: RestoreSelection,
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BlameRequest {
+    pub path: String,
+    /// Version to annotate (defaults to the working copy)
+    #[serde(default)]
+    pub version_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BlameResponse {
+    pub lines: Vec<BlameLine>,
+    /// The versions lines are attributed to, oldest first
+    pub versions: Vec<VersionSummary>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionStatsRequest {
+    pub path: String,
+    /// Width of the timeline buckets
+    #[serde(default)]
+    pub interval: StatsInterval,
+    /// Target length in words to report progress toward
+    #[serde(default)]
+    pub target_words: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionStatsResponse {
+    /// Oldest first
+    pub versions: Vec<VersionStats>,
+    pub timeline: Vec<TimelineBucket>,
+    /// Net words written per day recently
+    pub velocity: f64,
+    /// Measured against the working copy
+    #[serde(default)]
+    pub target: Option<TargetProgress>,
+}
+
+/// Edits a version's descriptive fields. Omitted fields are left as they
+/// are; an empty string clears `label`, `author` or `description`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateVersionMetadataRequest {
+    pub path: String,
+    pub version_id: String,
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub author: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Replaces the version's tags
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+    #[serde(default)]
+    pub pinned: Option<bool>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DeleteVersionRequest {
     pub path: String,
     pub version_id: String,
+    /// Remove the version for good instead of moving it to the trash
+    #[serde(default)]
+    pub permanent: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -75,158 +281,1074 @@ pub struct DeleteVersionResponse {
     pub versions: Vec<VersionSummary>,
 }
 
-fn load_or_create_payload(path: impl AsRef<Path>) -> Result<DocumentPayload, VersionError> {
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SquashVersionsRequest {
+    pub path: String,
+    /// First version of the run
+    pub from_version_id: String,
+    /// Last version of the run; the squashed version takes its place
+    pub to_version_id: String,
+    /// Label of the squashed version (defaults to the run's labels combined)
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SquashVersionsResponse {
+    pub version: DocumentVersion,
+    /// The versions folded into it, oldest first
+    pub squashed: Vec<VersionSummary>,
+    pub all_versions: Vec<VersionSummary>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrashRequest {
+    pub path: String,
+    pub version_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrashedVersion {
+    #[serde(flatten)]
+    pub version: VersionSummary,
+    /// When the retention policy purges the version; absent if it is kept
+    /// until the trash is emptied
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListTrashResponse {
+    /// Oldest first
+    pub versions: Vec<TrashedVersion>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EmptyTrashRequest {
+    pub path: String,
+    /// Trashed versions to purge (defaults to all of them)
+    #[serde(default)]
+    pub version_ids: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EmptyTrashResponse {
+    pub purged: Vec<VersionSummary>,
+    /// Versions that remain outside the trash
+    pub versions: Vec<VersionSummary>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PruneVersionsRequest {
+    pub path: String,
+    /// Overrides the policy configured in the document
+    #[serde(default)]
+    pub policy: Option<RetentionPolicy>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PruneVersionsResponse {
+    /// The policy that was applied
+    pub policy: RetentionPolicy,
+    /// Pruned versions, oldest first
+    pub pruned: Vec<PrunedVersion>,
+    pub freed_bytes: u64,
+    /// Versions that remain
+    pub versions: Vec<VersionSummary>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateBranchRequest {
+    pub path: String,
+    pub name: String,
+    /// Version the branch starts at (defaults to the current branch head)
+    #[serde(default)]
+    pub from_version_id: Option<String>,
+    /// Make the new branch the current one
+    #[serde(default)]
+    pub checkout: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BranchRequest {
+    pub path: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MoveBranchRequest {
+    pub path: String,
+    pub name: String,
+    pub version_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MergeVersionsRequest {
+    pub path: String,
+    /// Common ancestor (defaults to the nearest one in the version graph)
+    #[serde(default)]
+    pub base_version_id: Option<String>,
+    pub ours_version_id: String,
+    pub theirs_version_id: String,
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Text conflict ID -> resolution, from an earlier conflicted attempt
+    #[serde(default)]
+    pub resolutions: BTreeMap<usize, Resolution>,
+    /// Side whose formatting wins where both set the same attribute
+    #[serde(default)]
+    pub prefer_formatting: Option<MergeSide>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MergeVersionsResponse {
+    /// The merge version; absent while conflicts are unresolved
+    pub version: Option<DocumentVersion>,
+    /// Every conflict found, when the merge could not be completed
+    pub conflicts: Vec<MergeConflict>,
+    pub base_version_id: String,
+    pub all_versions: Vec<VersionSummary>,
+}
+
+/// Version ID under which diffs report the working copy.
+pub const WORKING_COPY_ID: &str = "working";
+
+pub(crate) fn load_or_create_payload(path: impl AsRef<Path>) -> Result<DocumentPayload, VersionError> {
     match load_document(path.as_ref()) {
         Ok(p) => Ok(p),
         Err(StorageError::Io(e)) if e.kind() == ErrorKind::NotFound => Ok(DocumentPayload {
             base_text: String::new(),
-            chunks: vec![PieceChunk {
-                kind: ChunkType::Original,
-                offset: Some(0),
-                len: Some(0),
-                source: Some("baseText".to_string()),
-                pos: None,
-                data: None,
-            }],
+            chunks: vec![PieceChunk::original("")],
             metadata: Default::default(),
             versions: vec![],
             assets: vec![],
+            document_tree: None,
+            operations: None,
+            branches: Default::default(),
+            asset_history: Default::default(),
         }),
         Err(e) => Err(e.into()),
     }
 }
 
-/// Create a new version of the document.
+/// Create a new version of the document on top of the current branch head.
 /// This captures the current state without modifying the working content.
 /// If the document file does not exist, creates it with the version as the first version.
 #[tauri::command]
-pub fn create_version(request: CreateVersionRequest) -> Result<CreateVersionResponse, VersionError> {
+pub fn create_version(
+    sessions: State<'_, DocumentSessions>,
+    request: CreateVersionRequest,
+) -> Result<CreateVersionResponse, VersionError> {
     let path = PathBuf::from(&request.path);
-    let mut payload = load_or_create_payload(&path)?;
-
-    let next_version_number = next_version_number(&payload.versions);
-    let version = DocumentVersion::new(next_version_number, request.content, request.label);
+    sessions.write_through(&path, || {
+        if !path.exists() {
+            let mut payload = load_or_create_payload(&path)?;
+            let mut version = DocumentVersion::new(1, request.content, request.label);
+            version.ranges = request.ranges;
+            version.author = request.author;
+            version.description = request.description;
+            version.tags = normalize_tags(request.tags);
+            version.pinned = request.pinned;
+            payload.versions.push(serde_json::to_value(&version)?);
+            let current = payload.branches.current.clone();
+            payload.branches.heads.insert(current, version.id.clone());
+            save_document(&path, &payload)?;
+            let all_versions = vec![version.to_summary()];
+            return Ok(CreateVersionResponse {
+                version,
+                all_versions,
+            });
+        }
 
-    let version_json = serde_json::to_value(&version)?;
-    payload.versions.push(version_json);
+        let index = version_store::read_index(&path)?;
+        let next_version_number = index
+            .versions
+            .iter()
+            .map(|v| v.summary.version_number)
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let mut version = DocumentVersion::new(next_version_number, request.content, request.label);
+        version.parent_ids = branch_head(&index).into_iter().collect();
+        // The saved document describes this text only if it has not been
+        // edited since; otherwise only the given ranges are kept.
+        let working = load_working_copy(&path)?;
+        if payload_text(&working) == version.content {
+            version.capture_state(&working);
+        }
+        if !request.ranges.is_empty() {
+            version.ranges = request.ranges;
+        }
+        version.author = request.author;
+        version.description = request.description;
+        version.tags = normalize_tags(request.tags);
+        version.pinned = request.pinned;
 
-    save_document(&path, &payload)?;
+        let index = version_store::append_version(&path, &version, |branches| {
+            branches.heads.insert(branches.current.clone(), version.id.clone());
+        })?;
 
-    let all_versions = build_version_summaries(&payload.versions);
+        Ok(CreateVersionResponse {
+            version,
+            all_versions: index.summaries(),
+        })
+    })
+}
 
-    Ok(CreateVersionResponse {
-        version,
-        all_versions,
+/// List the version graph of a document, optionally only the versions
+/// matching `filter`.
+#[tauri::command]
+pub fn list_versions(
+    sessions: State<'_, DocumentSessions>,
+    path: String,
+    filter: Option<VersionFilter>,
+) -> Result<ListVersionsResponse, VersionError> {
+    let path = PathBuf::from(path);
+    sessions.read_through(&path, || {
+        let index = version_store::read_index(&path)?;
+        let mut response = graph_response(index);
+        if let Some(filter) = filter {
+            response.versions.retain(|v| filter.matches(v));
+        }
+        Ok(response)
     })
 }
 
-/// List all versions of a document.
+/// Get a specific version by ID.
 #[tauri::command]
-pub fn list_versions(path: String) -> Result<ListVersionsResponse, VersionError> {
+pub fn get_version(
+    sessions: State<'_, DocumentSessions>,
+    path: String,
+    version_id: String,
+) -> Result<GetVersionResponse, VersionError> {
     let path = PathBuf::from(path);
-    let payload = load_document(&path)?;
+    sessions.read_through(&path, || {
+        let version = read_version(&path, &version_id)?;
+        Ok(GetVersionResponse { version })
+    })
+}
 
-    let versions = build_version_summaries(&payload.versions);
-    let current_version_number = versions
-        .iter()
-        .map(|v| v.version_number)
-        .max()
-        .unwrap_or(0);
+/// Compare two versions, or a version and the working copy, and return the
+/// diff. The working copy is reported with the ID `working`.
+#[tauri::command]
+pub fn diff_versions(
+    sessions: State<'_, DocumentSessions>,
+    request: DiffVersionsRequest,
+) -> Result<VersionDiff, VersionError> {
+    let path = PathBuf::from(&request.path);
+    sessions.read_through(&path, || {
+        let from_version = resolve_diff_side(&path, request.from, request.from_version_id, "from")?;
+        let to_version = resolve_diff_side(&path, request.to, request.to_version_id, "to")?;
 
-    Ok(ListVersionsResponse {
-        versions,
-        current_version_number,
+        compute_diff(from_version, to_version, request.granularity)
     })
 }
 
-/// Get a specific version by ID.
+/// Attribute every line of the working copy, or of a version, to the
+/// version, author and time that last changed it. Lines are traced through
+/// the version graph, including merges and restores; each line also names
+/// the block that starts on it.
 #[tauri::command]
-pub fn get_version(path: String, version_id: String) -> Result<GetVersionResponse, VersionError> {
-    let path = PathBuf::from(path);
-    let payload = load_document(&path)?;
+pub fn blame_document(
+    sessions: State<'_, DocumentSessions>,
+    request: BlameRequest,
+) -> Result<BlameResponse, VersionError> {
+    let path = PathBuf::from(&request.path);
+    sessions.read_through(&path, || {
+        let (values, branches) = version_store::load_versions(&path)?;
+        let versions = values
+            .into_iter()
+            .map(serde_json::from_value)
+            .collect::<Result<Vec<DocumentVersion>, _>>()?;
 
-    let version = find_version(&payload.versions, &version_id)?;
-    Ok(GetVersionResponse { version })
+        let (content, tree, parents) = match request.version_id {
+            Some(version_id) => {
+                let version = versions
+                    .iter()
+                    .find(|v| v.id == version_id)
+                    .ok_or_else(|| VersionError::NotFound(version_id.clone()))?;
+                (version.content.clone(), version.document_tree.clone(), vec![version_id])
+            }
+            None => {
+                let working = load_working_copy(&path)?;
+                let head = branches
+                    .heads
+                    .get(&branches.current)
+                    .cloned()
+                    .or_else(|| versions.iter().max_by_key(|v| v.version_number).map(|v| v.id.clone()));
+                (payload_text(&working), working.document_tree, head.into_iter().collect())
+            }
+        };
+
+        let lines = blame::blame(&versions, &parents, &content, tree.as_ref());
+        let referenced: HashSet<&str> = lines.iter().filter_map(|line| line.version_id.as_deref()).collect();
+        let mut summaries: Vec<VersionSummary> = versions
+            .iter()
+            .filter(|v| referenced.contains(v.id.as_str()))
+            .map(DocumentVersion::to_summary)
+            .collect();
+        summaries.sort_by_key(|v| v.version_number);
+
+        Ok(BlameResponse {
+            lines,
+            versions: summaries,
+        })
+    })
 }
 
-/// Compare two versions and return the diff.
+/// Writing statistics for every live version, with word changes measured
+/// against each version's first parent, bucketed into a timeline, and the
+/// recent writing velocity. With `targetWords`, also reports progress of the
+/// working copy toward that length.
 #[tauri::command]
-pub fn diff_versions(request: DiffVersionsRequest) -> Result<VersionDiff, VersionError> {
+pub fn version_statistics(
+    sessions: State<'_, DocumentSessions>,
+    request: VersionStatsRequest,
+) -> Result<VersionStatsResponse, VersionError> {
     let path = PathBuf::from(&request.path);
-    let payload = load_document(&path)?;
+    sessions.read_through(&path, || {
+        let (values, _) = version_store::load_versions(&path)?;
+        let mut versions = values
+            .into_iter()
+            .map(serde_json::from_value)
+            .collect::<Result<Vec<DocumentVersion>, _>>()?;
+        versions.sort_by_key(|v| (v.version_number, v.created_at));
 
-    let from_version = find_version(&payload.versions, &request.from_version_id)?;
-    let to_version = find_version(&payload.versions, &request.to_version_id)?;
+        let by_id: HashMap<&str, &DocumentVersion> = versions.iter().map(|v| (v.id.as_str(), v)).collect();
+        let mut previous: Option<&DocumentVersion> = None;
+        let mut version_stats = Vec::with_capacity(versions.len());
+        for version in &versions {
+            // Legacy versions without parents follow the previous one.
+            let base = match version.parent_ids.first() {
+                Some(parent_id) => by_id.get(parent_id.as_str()).copied(),
+                None => previous,
+            };
+            previous = Some(version);
+            // Trashed versions still serve as the base of their children.
+            if version.deleted_at.is_some() {
+                continue;
+            }
+            let (words_added, words_removed) = stats::word_changes(base.map_or("", |b| b.content.as_str()), &version.content);
+            version_stats.push(VersionStats {
+                version_id: version.id.clone(),
+                version_number: version.version_number,
+                created_at: version.created_at,
+                author: version.author.clone(),
+                stats: stats::text_stats(&version.content, version.document_tree.as_ref()),
+                words_added,
+                words_removed,
+            });
+        }
+
+        let timeline = stats::timeline(&version_stats, request.interval);
+        let velocity = stats::velocity(&version_stats);
+        let target = match request.target_words {
+            Some(target_words) => {
+                let working = load_working_copy(&path)?;
+                let current = stats::text_stats(&payload_text(&working), working.document_tree.as_ref());
+                Some(stats::target_progress(target_words, current.words, velocity, Utc::now()))
+            }
+            None => None,
+        };
+
+        Ok(VersionStatsResponse {
+            versions: version_stats,
+            timeline,
+            velocity,
+            target,
+        })
+    })
+}
 
-    compute_diff(from_version, to_version)
+/// Compare the document trees of two versions, or of a version and the
+/// working copy, block by block.
+#[tauri::command]
+pub fn diff_document_trees(
+    sessions: State<'_, DocumentSessions>,
+    request: DiffTreesRequest,
+) -> Result<TreeDiff, VersionError> {
+    let path = PathBuf::from(&request.path);
+    sessions.read_through(&path, || {
+        let from_version = resolve_diff_side(&path, request.from, request.from_version_id, "from")?;
+        let to_version = resolve_diff_side(&path, request.to, request.to_version_id, "to")?;
+        for version in [&from_version, &to_version] {
+            if version.document_tree.is_none() {
+                return Err(VersionError::NoDocumentTree(version.id.clone()));
+            }
+        }
+
+        Ok(tree_diff::diff_trees(&from_version, &to_version))
+    })
 }
 
 /// Restore the document to a previous version.
-/// Creates a new version with the restored content.
+/// Brings back the version's text and, for versions that captured them, its
+/// formatting, document tree and assets. Creates a new version with the
+/// restored state on top of the current branch head, linked to the version
+/// it was restored from.
 #[tauri::command]
-pub fn restore_version(request: RestoreVersionRequest) -> Result<CreateVersionResponse, VersionError> {
+pub fn restore_version(
+    sessions: State<'_, DocumentSessions>,
+    request: RestoreVersionRequest,
+) -> Result<CreateVersionResponse, VersionError> {
     let path = PathBuf::from(&request.path);
-    let mut payload = load_document(&path)?;
+    sessions.write_through(&path, || {
+        let mut payload = load_document(&path)?;
+        let restored = restore_into(&mut payload, &request.version_id)?;
+        save_document(&path, &payload)?;
 
-    let target_version = find_version(&payload.versions, &request.version_id)?;
-    let next_version_number = next_version_number(&payload.versions);
+        Ok(CreateVersionResponse {
+            version: restored,
+            all_versions: version_store::live_summaries(&payload.versions),
+        })
+    })
+}
 
-    let label = Some(format!("Restored from version {}", target_version.version_number));
-    let restored =
-        DocumentVersion::new(next_version_number, target_version.content.clone(), label);
+/// Bring back part of a version: a set of diff hunks or a section of its
+/// document tree. Only the selected text is spliced into the working
+/// content, together with the version's formatting, blocks and images for
+/// it; everything else stays as it is. The result is recorded as a new
+/// version on top of the current branch head.
+#[tauri::command]
+pub fn restore_selection(
+    sessions: State<'_, DocumentSessions>,
+    request: RestoreSelectionRequest,
+) -> Result<CreateVersionResponse, VersionError> {
+    let path = PathBuf::from(&request.path);
+    sessions.write_through(&path, || {
+        let mut payload = load_document(&path)?;
 
-    payload.base_text = target_version.content.clone();
-    payload.chunks = vec![PieceChunk {
-        kind: ChunkType::Original,
-        offset: Some(0),
-        len: Some(target_version.content.len()),
-        source: Some("baseText".to_string()),
-        pos: None,
-        data: None,
-    }];
+        let source = find_version(&payload.versions, &request.version_id)?;
+        let working = payload_text(&payload);
+        let replacements =
+            partial_restore::plan(&request.selection, &source, &working, payload.document_tree.as_ref())?;
+        let options: RebaseOptions = payload
+            .metadata
+            .custom
+            .get("rebase")
+            .and_then(|value| serde_json::from_value(value.clone()).ok())
+            .unwrap_or_default();
+        let report = partial_restore::apply(&mut payload, &source, &replacements, &options);
+        for name in &report.orphaned_assets {
+            log::warn!("asset {} lost its anchor", name);
+        }
+        repair_payload(&mut payload);
+        normalize_ranges(&mut payload.metadata.ranges);
+        sync_operation_log(&mut payload);
 
-    let version_json = serde_json::to_value(&restored)?;
-    payload.versions.push(version_json);
+        let label = request
+            .label
+            .or_else(|| Some(format!("Restored part of version {}", source.version_number)));
+        let summaries = version_store::live_summaries(&payload.versions);
+        let mut restored = DocumentVersion::new(next_version_number(&payload.versions), payload_text(&payload), label);
+        restored.parent_ids = payload
+            .branches
+            .heads
+            .get(&payload.branches.current)
+            .or(summaries.last().map(|v| &v.id))
+            .cloned()
+            .into_iter()
+            .collect();
+        restored.restored_from = Some(source.id.clone());
+        restored.capture_state(&payload);
 
-    save_document(&path, &payload)?;
+        payload.versions.push(serde_json::to_value(&restored)?);
+        let current = payload.branches.current.clone();
+        payload.branches.heads.insert(current, restored.id.clone());
+        save_document(&path, &payload)?;
 
-    let all_versions = build_version_summaries(&payload.versions);
+        Ok(CreateVersionResponse {
+            version: restored,
+            all_versions: version_store::live_summaries(&payload.versions),
+        })
+    })
+}
 
-    Ok(CreateVersionResponse {
-        version: restored,
-        all_versions,
+/// Edit a version's label, author, description, tags or pin. Content and
+/// `contentHash` are never touched.
+#[tauri::command]
+pub fn update_version_metadata(
+    sessions: State<'_, DocumentSessions>,
+    request: UpdateVersionMetadataRequest,
+) -> Result<CreateVersionResponse, VersionError> {
+    let path = PathBuf::from(&request.path);
+    sessions.write_through(&path, || {
+        let non_empty = |value: String| Some(value).filter(|v| !v.trim().is_empty());
+        let (version, index) = version_store::update_version(&path, &request.version_id, |version| {
+            if let Some(label) = request.label {
+                version.label = non_empty(label);
+            }
+            if let Some(author) = request.author {
+                version.author = non_empty(author);
+            }
+            if let Some(description) = request.description {
+                version.description = non_empty(description);
+            }
+            if let Some(tags) = request.tags {
+                version.tags = normalize_tags(tags);
+            }
+            if let Some(pinned) = request.pinned {
+                version.pinned = pinned;
+            }
+            version.updated_at = Some(Utc::now());
+        })?
+        .ok_or(VersionError::NotFound(request.version_id))?;
+
+        Ok(CreateVersionResponse {
+            version,
+            all_versions: index.summaries(),
+        })
     })
 }
 
 /// Delete a specific version.
 #[tauri::command]
-pub fn delete_version(request: DeleteVersionRequest) -> Result<DeleteVersionResponse, VersionError> {
+pub fn delete_version(
+    sessions: State<'_, DocumentSessions>,
+    request: DeleteVersionRequest,
+) -> Result<DeleteVersionResponse, VersionError> {
     let path = PathBuf::from(&request.path);
-    let mut payload = load_document(&path)?;
+    sessions.write_through(&path, || {
+        let index = if request.permanent {
+            version_store::remove_version(&path, &request.version_id)?
+        } else {
+            version_store::trash_version(&path, &request.version_id, Utc::now())?
+        }
+        .ok_or(VersionError::NotFound(request.version_id))?;
+        let index = purge_expired_trash(&path, index)?;
+
+        Ok(DeleteVersionResponse {
+            versions: index.summaries(),
+        })
+    })
+}
+
+/// Trashed versions with the time each is due to be purged.
+#[tauri::command]
+pub fn list_trash(sessions: State<'_, DocumentSessions>, path: String) -> Result<ListTrashResponse, VersionError> {
+    let path = PathBuf::from(path);
+    sessions.read_through(&path, || {
+        let policy = retention_policy(&path)?;
+        let index = version_store::read_index(&path)?;
+        let versions = index
+            .trash()
+            .into_iter()
+            .map(|version| TrashedVersion {
+                expires_at: version.deleted_at.and_then(|deleted_at| trash_expiry(&policy, deleted_at)),
+                version,
+            })
+            .collect();
+        Ok(ListTrashResponse { versions })
+    })
+}
 
-    let initial_len = payload.versions.len();
-    payload.versions.retain(|v| {
-        v.get("id")
-            .and_then(|id| id.as_str())
-            .map(|id| id != request.version_id)
-            .unwrap_or(true)
-    });
+/// Take a version out of the trash. Branches it was the head of point back
+/// at it if they have not moved on since.
+#[tauri::command]
+pub fn restore_from_trash(
+    sessions: State<'_, DocumentSessions>,
+    request: TrashRequest,
+) -> Result<ListVersionsResponse, VersionError> {
+    let path = PathBuf::from(&request.path);
+    sessions.write_through(&path, || {
+        let index = version_store::read_index(&path)?;
+        if !index.trash().iter().any(|v| v.id == request.version_id) {
+            return Err(VersionError::NotInTrash(request.version_id));
+        }
+        let index = version_store::untrash_version(&path, &request.version_id)?
+            .ok_or(VersionError::NotFound(request.version_id))?;
+        Ok(graph_response(index))
+    })
+}
 
-    if payload.versions.len() == initial_len {
-        return Err(VersionError::NotFound(request.version_id));
+/// Permanently remove trashed versions, all of them unless `version_ids`
+/// names some.
+#[tauri::command]
+pub fn empty_trash(
+    sessions: State<'_, DocumentSessions>,
+    request: EmptyTrashRequest,
+) -> Result<EmptyTrashResponse, VersionError> {
+    let path = PathBuf::from(&request.path);
+    sessions.write_through(&path, || {
+        let index = version_store::read_index(&path)?;
+        let mut purged = index.trash();
+        if let Some(version_ids) = &request.version_ids {
+            if let Some(missing) = version_ids.iter().find(|id| !purged.iter().any(|v| &v.id == *id)) {
+                return Err(VersionError::NotInTrash(missing.clone()));
+            }
+            purged.retain(|v| version_ids.contains(&v.id));
+        }
+        if purged.is_empty() {
+            return Ok(EmptyTrashResponse {
+                purged,
+                versions: index.summaries(),
+            });
+        }
+        let ids: HashSet<String> = purged.iter().map(|v| v.id.clone()).collect();
+        let index = version_store::remove_versions(&path, &ids)?;
+        Ok(EmptyTrashResponse {
+            purged,
+            versions: index.summaries(),
+        })
+    })
+}
+
+/// Collapse a run of versions into one. The run goes from `from_version_id`
+/// to `to_version_id` along first parents; every version in it but the
+/// first must have that single parent, and every version but the last
+/// that single child. The last version becomes the squashed one: it keeps
+/// its ID, content, hash, formatting, tree, assets, date and version
+/// number, so numbers are never reused and the gap shows where versions
+/// were folded. It takes the parents of the first version, the combined
+/// label, descriptions and tags of the run, and is pinned if any of the run
+/// was. Branch heads inside the run move to it.
+#[tauri::command]
+pub fn squash_versions(
+    sessions: State<'_, DocumentSessions>,
+    request: SquashVersionsRequest,
+) -> Result<SquashVersionsResponse, VersionError> {
+    let path = PathBuf::from(&request.path);
+    sessions.write_through(&path, || {
+        let index = version_store::read_index(&path)?;
+        let run = squash_run(&index, &request.from_version_id, &request.to_version_id)?;
+        let mut versions = Vec::with_capacity(run.len());
+        for summary in &run {
+            versions.push(read_version(&path, &summary.id)?);
+        }
+
+        let label = request
+            .label
+            .filter(|label| !label.trim().is_empty())
+            .or_else(|| {
+                let mut labels: Vec<&str> = Vec::new();
+                for label in versions.iter().filter_map(|v| v.label.as_deref()) {
+                    if !labels.contains(&label) {
+                        labels.push(label);
+                    }
+                }
+                (!labels.is_empty()).then(|| labels.join("; "))
+            })
+            .unwrap_or_else(|| format!("Versions {}-{}", run[0].version_number, run[run.len() - 1].version_number));
+        let descriptions: Vec<&str> = versions
+            .iter()
+            .filter_map(|v| v.description.as_deref())
+            .filter(|d| !d.trim().is_empty())
+            .collect();
+        let description = (!descriptions.is_empty()).then(|| descriptions.join("\n\n"));
+        let tags = normalize_tags(versions.iter().flat_map(|v| v.tags.clone()).collect());
+        let pinned = versions.iter().any(|v| v.pinned);
+        let auto = versions.iter().all(|v| v.auto);
+
+        let to_id = run[run.len() - 1].id.clone();
+        let removed_summaries = run[..run.len() - 1].to_vec();
+        let removed: HashSet<String> = removed_summaries.iter().map(|v| v.id.clone()).collect();
+        let (version, index) = version_store::squash_versions(&path, &removed, &to_id, |version| {
+            version.label = Some(label);
+            version.description = description;
+            version.tags = tags;
+            version.pinned = pinned;
+            version.auto = auto;
+            version.updated_at = Some(Utc::now());
+        })?
+        .ok_or(VersionError::NotFound(to_id))?;
+
+        Ok(SquashVersionsResponse {
+            version,
+            squashed: removed_summaries,
+            all_versions: index.summaries(),
+        })
+    })
+}
+
+/// Append an automatic snapshot of the payload's current text to its
+/// versions if the document's `autoVersions` policy calls for one: enough
+/// time has passed since the current branch head, the text has drifted far
+/// enough from it, or the app is closing. Nothing is taken when the text
+/// matches the head.
+pub fn auto_snapshot(
+    payload: &mut DocumentPayload,
+    closing: bool,
+) -> Result<Option<DocumentVersion>, VersionError> {
+    let version_number = next_version_number(&payload.versions);
+    let snapshot = plan_auto_snapshot(payload, version_number, closing, || {
+        payload_head(payload)
+            .map(|head_id| find_version(&payload.versions, &head_id))
+            .transpose()
+    })?;
+    let Some(snapshot) = snapshot else {
+        return Ok(None);
+    };
+    payload.versions.push(serde_json::to_value(&snapshot)?);
+    let current = payload.branches.current.clone();
+    payload.branches.heads.insert(current, snapshot.id.clone());
+    Ok(Some(snapshot))
+}
+
+/// [`auto_snapshot`] for a saved document whose versions are not loaded.
+/// Only the current branch head is read, and the snapshot is appended to
+/// the stored versions.
+pub(crate) fn auto_snapshot_stored(
+    path: &Path,
+    payload: &DocumentPayload,
+    closing: bool,
+) -> Result<Option<DocumentVersion>, VersionError> {
+    let index = version_store::read_index(path)?;
+    let version_number = index
+        .versions
+        .iter()
+        .map(|entry| entry.summary.version_number)
+        .max()
+        .unwrap_or(0)
+        + 1;
+    let snapshot = plan_auto_snapshot(payload, version_number, closing, || {
+        branch_head(&index)
+            .map(|head_id| read_version(path, &head_id))
+            .transpose()
+    })?;
+    if let Some(snapshot) = &snapshot {
+        version_store::append_version(path, snapshot, |branches| {
+            branches.heads.insert(branches.current.clone(), snapshot.id.clone());
+        })?;
+    }
+    Ok(snapshot)
+}
+
+/// Build the automatic snapshot the policy calls for, if any. `head` loads
+/// the current branch head and is only called when the policy is enabled.
+fn plan_auto_snapshot(
+    payload: &DocumentPayload,
+    version_number: u32,
+    closing: bool,
+    head: impl FnOnce() -> Result<Option<DocumentVersion>, VersionError>,
+) -> Result<Option<DocumentVersion>, VersionError> {
+    let policy: AutoVersionPolicy = payload
+        .metadata
+        .custom
+        .get("autoVersions")
+        .and_then(|value| serde_json::from_value(value.clone()).ok())
+        .unwrap_or_default();
+    if !policy.enabled {
+        return Ok(None);
+    }
+
+    let head = head()?;
+    let mut snapshot = DocumentVersion::new(version_number, payload_text(payload), None);
+
+    let due = match &head {
+        None => !snapshot.content.is_empty(),
+        Some(head) => {
+            if head.content_hash == snapshot.content_hash {
+                return Ok(None);
+            }
+            let elapsed = Utc::now() - head.created_at;
+            (closing && policy.on_close)
+                || policy
+                    .interval_minutes
+                    .is_some_and(|minutes| elapsed >= Duration::minutes(i64::from(minutes)))
+                || match policy.min_similarity {
+                    Some(min) => compute_diff(head.clone(), snapshot.clone(), DiffGranularity::Line)?.similarity <= min,
+                    None => false,
+                }
+        }
+    };
+    if !due {
+        return Ok(None);
     }
 
-    save_document(&path, &payload)?;
-    let versions = build_version_summaries(&payload.versions);
+    snapshot.auto = true;
+    snapshot.parent_ids = head.map(|head| head.id).into_iter().collect();
+    snapshot.capture_state(payload);
+    Ok(Some(snapshot))
+}
 
-    Ok(DeleteVersionResponse { versions })
+/// Show which versions `prune_versions` would remove, without changing the
+/// document.
+#[tauri::command]
+pub fn preview_prune_versions(
+    sessions: State<'_, DocumentSessions>,
+    request: PruneVersionsRequest,
+) -> Result<PruneVersionsResponse, VersionError> {
+    let path = PathBuf::from(&request.path);
+    sessions.read_through(&path, || {
+        let (index, response) = plan_prune(&path, request.policy)?;
+        let pruned: HashSet<&str> = response.pruned.iter().map(|v| v.version.id.as_str()).collect();
+        let versions = index
+            .summaries()
+            .into_iter()
+            .filter(|v| !pruned.contains(v.id.as_str()))
+            .collect();
+        Ok(PruneVersionsResponse { versions, ..response })
+    })
+}
+
+/// Remove the versions the retention policy no longer keeps, in a single
+/// rewrite of the document. The policy comes from the request or from
+/// `metadata.custom.retention`. Branch heads and the newest version are
+/// always kept.
+#[tauri::command]
+pub fn prune_versions(
+    sessions: State<'_, DocumentSessions>,
+    request: PruneVersionsRequest,
+) -> Result<PruneVersionsResponse, VersionError> {
+    let path = PathBuf::from(&request.path);
+    sessions.write_through(&path, || {
+        let (index, response) = plan_prune(&path, request.policy)?;
+        if response.pruned.is_empty() {
+            return Ok(PruneVersionsResponse {
+                versions: index.summaries(),
+                ..response
+            });
+        }
+        let ids: HashSet<String> = response.pruned.iter().map(|v| v.version.id.clone()).collect();
+        let index = version_store::remove_versions(&path, &ids)?;
+        Ok(PruneVersionsResponse {
+            versions: index.summaries(),
+            ..response
+        })
+    })
+}
+
+/// Start a named branch at a version.
+#[tauri::command]
+pub fn create_branch(
+    sessions: State<'_, DocumentSessions>,
+    request: CreateBranchRequest,
+) -> Result<ListVersionsResponse, VersionError> {
+    let path = PathBuf::from(&request.path);
+    sessions.write_through(&path, || {
+        let index = version_store::read_index(&path)?;
+        if request.name.trim().is_empty() {
+            return Err(VersionError::InvalidBranchName(request.name));
+        }
+        if branch_exists(&index, &request.name) {
+            return Err(VersionError::BranchExists(request.name));
+        }
+        let head = match request.from_version_id {
+            Some(id) if index.versions.iter().any(|v| v.summary.id == id) => id,
+            Some(id) => return Err(VersionError::NotFound(id)),
+            None => branch_head(&index).ok_or_else(|| VersionError::NotFound(index.branches.current.clone()))?,
+        };
+
+        let index = version_store::update_branches(&path, |branches| {
+            branches.heads.insert(request.name.clone(), head);
+            if request.checkout {
+                branches.current = request.name;
+            }
+        })?;
+        Ok(graph_response(index))
+    })
+}
+
+/// Make a branch the current one, so new versions extend it.
+#[tauri::command]
+pub fn checkout_branch(
+    sessions: State<'_, DocumentSessions>,
+    request: BranchRequest,
+) -> Result<ListVersionsResponse, VersionError> {
+    let path = PathBuf::from(&request.path);
+    sessions.write_through(&path, || {
+        if !branch_exists(&version_store::read_index(&path)?, &request.name) {
+            return Err(VersionError::BranchNotFound(request.name));
+        }
+        let index = version_store::update_branches(&path, |branches| branches.current = request.name)?;
+        Ok(graph_response(index))
+    })
+}
+
+/// Point a branch at another version.
+#[tauri::command]
+pub fn move_branch(
+    sessions: State<'_, DocumentSessions>,
+    request: MoveBranchRequest,
+) -> Result<ListVersionsResponse, VersionError> {
+    let path = PathBuf::from(&request.path);
+    sessions.write_through(&path, || {
+        let index = version_store::read_index(&path)?;
+        if !branch_exists(&index, &request.name) {
+            return Err(VersionError::BranchNotFound(request.name));
+        }
+        if !index.versions.iter().any(|v| v.summary.id == request.version_id) {
+            return Err(VersionError::NotFound(request.version_id));
+        }
+        let index = version_store::update_branches(&path, |branches| {
+            branches.heads.insert(request.name, request.version_id);
+        })?;
+        Ok(graph_response(index))
+    })
+}
+
+/// Delete a branch pointer. Its versions are kept.
+#[tauri::command]
+pub fn delete_branch(
+    sessions: State<'_, DocumentSessions>,
+    request: BranchRequest,
+) -> Result<ListVersionsResponse, VersionError> {
+    let path = PathBuf::from(&request.path);
+    sessions.write_through(&path, || {
+        let index = version_store::read_index(&path)?;
+        if index.branches.current == request.name {
+            return Err(VersionError::CurrentBranch(request.name));
+        }
+        if !index.branches.heads.contains_key(&request.name) {
+            return Err(VersionError::BranchNotFound(request.name));
+        }
+        let index = version_store::update_branches(&path, |branches| {
+            branches.heads.remove(&request.name);
+        })?;
+        Ok(graph_response(index))
+    })
+}
+
+/// Three-way merge of two versions against their common ancestor.
+///
+/// Content is merged line by line and formatting ranges are merged on top of
+/// the merged content. When every conflict is resolved (by `resolutions` and
+/// `preferFormatting`), a merge version with both versions as parents is
+/// created and branches pointing at `ours` advance to it; it takes its
+/// document tree and assets from `ours`, re-fitted to the merged text.
+/// Otherwise nothing is written and the conflicts are returned with each
+/// side's text.
+#[tauri::command]
+pub fn merge_versions(
+    sessions: State<'_, DocumentSessions>,
+    request: MergeVersionsRequest,
+) -> Result<MergeVersionsResponse, VersionError> {
+    let path = PathBuf::from(&request.path);
+    sessions.write_through(&path, || {
+        let index = version_store::read_index(&path)?;
+        let base_id = match request.base_version_id {
+            Some(id) => id,
+            None => merge_base(&index, &request.ours_version_id, &request.theirs_version_id).ok_or_else(|| {
+                VersionError::NoCommonAncestor(request.ours_version_id.clone(), request.theirs_version_id.clone())
+            })?,
+        };
+        let base = read_version(&path, &base_id)?;
+        let ours = read_version(&path, &request.ours_version_id)?;
+        let theirs = read_version(&path, &request.theirs_version_id)?;
+
+        let text = merge::merge_text(&base.content, &ours.content, &theirs.content, &request.resolutions);
+        let (ranges, formatting_conflicts) = merge::merge_ranges(
+            [
+                (&base.content, &base.ranges),
+                (&ours.content, &ours.ranges),
+                (&theirs.content, &theirs.ranges),
+            ],
+            &text,
+            request.prefer_formatting,
+        );
+        let unresolved = text.unresolved || (request.prefer_formatting.is_none() && !formatting_conflicts.is_empty());
+        if unresolved {
+            let mut conflicts = text.conflicts;
+            conflicts.extend(formatting_conflicts);
+            return Ok(MergeVersionsResponse {
+                version: None,
+                conflicts,
+                base_version_id: base_id,
+                all_versions: index.summaries(),
+            });
+        }
+
+        let next_version_number = index
+            .versions
+            .iter()
+            .map(|v| v.summary.version_number)
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let label = request.label.or_else(|| {
+            Some(format!(
+                "Merged version {} into version {}",
+                theirs.version_number, ours.version_number
+            ))
+        });
+        let mut version = DocumentVersion::new(next_version_number, text.text(), label);
+        version.parent_ids = vec![ours.id.clone(), theirs.id.clone()];
+        version.ranges = ranges;
+        // Structure and assets come from ours, re-fitted to the merged text.
+        version.assets = ours.assets.clone();
+        if let Some(mut tree) = ours.document_tree.clone() {
+            consistency::repair(&mut tree, &version.content);
+            anchor_assets(version.assets.iter_mut().flatten(), &tree);
+            version.document_tree = Some(tree);
+        }
+
+        let index = version_store::append_version(&path, &version, |branches| {
+            for head in branches.heads.values_mut() {
+                if *head == ours.id {
+                    *head = version.id.clone();
+                }
+            }
+        })?;
+
+        Ok(MergeVersionsResponse {
+            version: Some(version),
+            conflicts: Vec::new(),
+            base_version_id: base_id,
+            all_versions: index.summaries(),
+        })
+    })
 }
 
 // ============================================================================
 // Helper Functions
 // ============================================================================
 
-fn next_version_number(versions: &[serde_json::Value]) -> u32 {
+impl VersionFilter {
+    pub(crate) fn matches(&self, version: &VersionSummary) -> bool {
+        self.tag.as_ref().is_none_or(|tag| version.tags.contains(tag))
+            && self.author.as_ref().is_none_or(|author| version.author.as_ref() == Some(author))
+            && self.pinned.is_none_or(|pinned| version.pinned == pinned)
+            && self.auto.is_none_or(|auto| version.auto == auto)
+    }
+}
+
+/// Anchor captured assets at the images that show them in `tree`.
+fn anchor_assets<'a>(assets: impl Iterator<Item = &'a mut VersionAsset>, tree: &DocumentTree) {
+    for asset in assets {
+        let image = tree.blocks().find_map(|block| match block {
+            BlockNode::Image(image) if image.asset_ref.name == asset.name => Some(image),
+            _ => None,
+        });
+        if let Some(image) = image {
+            asset.target_pos = image.buffer_position;
+        }
+    }
+}
+
+/// Trimmed, de-duplicated tags in their original order.
+pub(crate) fn normalize_tags(tags: Vec<String>) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.trim();
+        if !tag.is_empty() && !normalized.iter().any(|t| t == tag) {
+            normalized.push(tag.to_string());
+        }
+    }
+    normalized
+}
+
+pub(crate) fn payload_text(payload: &DocumentPayload) -> String {
+    PieceTableContent {
+        base_text: payload.base_text.clone(),
+        chunks: payload.chunks.clone(),
+    }
+    .to_text()
+}
+
+pub(crate) fn next_version_number(versions: &[serde_json::Value]) -> u32 {
     versions
         .iter()
         .filter_map(|v| v.get("versionNumber").and_then(|n| n.as_u64()))
@@ -235,18 +1357,244 @@ fn next_version_number(versions: &[serde_json::Value]) -> u32 {
         + 1
 }
 
-fn build_version_summaries(versions: &[serde_json::Value]) -> Vec<VersionSummary> {
-    versions
+/// Head of the current branch. Archives written before branches existed
+/// have no heads; their current branch ends at the newest version.
+fn branch_head(index: &VersionIndex) -> Option<String> {
+    index
+        .branches
+        .heads
+        .get(&index.branches.current)
+        .cloned()
+        .or_else(|| index.summaries().last().map(|v| v.id.clone()))
+}
+
+/// Head of the current branch of a loaded document; see [`branch_head`].
+pub(crate) fn payload_head(payload: &DocumentPayload) -> Option<String> {
+    payload
+        .branches
+        .heads
+        .get(&payload.branches.current)
+        .cloned()
+        .or_else(|| version_store::live_summaries(&payload.versions).pop().map(|v| v.id))
+}
+
+/// Bring back a version's state in a loaded document, as `restore_version`
+/// does, and record it as a new version on the current branch.
+pub(crate) fn restore_into(payload: &mut DocumentPayload, version_id: &str) -> Result<DocumentVersion, VersionError> {
+    let target_version = find_version(&payload.versions, version_id)?;
+
+    // Resolve every asset before changing anything, so a missing one leaves
+    // the document as it was.
+    let current: BTreeMap<String, &[u8]> = payload
+        .assets
         .iter()
-        .filter_map(|v| {
-            serde_json::from_value::<DocumentVersion>(v.clone())
-                .ok()
-                .map(|ver| ver.to_summary())
+        .map(|asset| (sha256_hex(&asset.bytes), asset.bytes.as_slice()))
+        .collect();
+    let restored_assets = target_version
+        .assets
+        .as_ref()
+        .map(|assets| {
+            assets
+                .iter()
+                .map(|asset| {
+                    let bytes = payload
+                        .asset_history
+                        .get(&asset.hash)
+                        .map(Vec::as_slice)
+                        .or_else(|| current.get(&asset.hash).copied())
+                        .ok_or_else(|| VersionError::MissingAsset(asset.name.clone()))?;
+                    Ok(AssetRef {
+                        name: asset.name.clone(),
+                        target_pos: asset.target_pos,
+                        alt: asset.alt.clone(),
+                        size: asset.size,
+                        bytes: bytes.to_vec(),
+                    })
+                })
+                .collect::<Result<Vec<_>, VersionError>>()
         })
-        .collect()
+        .transpose()?;
+
+    payload.base_text = target_version.content.clone();
+    payload.chunks = vec![PieceChunk::original(&target_version.content)];
+    if let Some(assets) = restored_assets {
+        payload.metadata.ranges = target_version.ranges.clone();
+        payload.document_tree = target_version.document_tree.clone();
+        // Keep the outgoing assets restorable.
+        for asset in std::mem::replace(&mut payload.assets, assets) {
+            payload.asset_history.insert(sha256_hex(&asset.bytes), asset.bytes);
+        }
+    }
+    // Versions without a captured tree keep the current one, re-fitted to the text.
+    repair_payload(payload);
+    sync_operation_log(payload);
+
+    let label = Some(format!("Restored from version {}", target_version.version_number));
+    let mut restored =
+        DocumentVersion::new(next_version_number(&payload.versions), target_version.content.clone(), label);
+    restored.parent_ids = payload_head(payload).into_iter().collect();
+    restored.restored_from = Some(target_version.id.clone());
+    restored.capture_state(payload);
+
+    payload.versions.push(serde_json::to_value(&restored)?);
+    let current = payload.branches.current.clone();
+    payload.branches.heads.insert(current, restored.id.clone());
+    Ok(restored)
+}
+
+/// Evaluate the retention policy against the stored history. The returned
+/// response has no remaining `versions` filled in.
+fn plan_prune(
+    path: &Path,
+    policy: Option<RetentionPolicy>,
+) -> Result<(VersionIndex, PruneVersionsResponse), VersionError> {
+    let policy = match policy {
+        Some(policy) => policy,
+        None => retention_policy(path)?,
+    };
+    let index = version_store::read_index(path)?;
+    let sizes = version_store::stored_sizes(path)?;
+    let mut protected: HashSet<String> = index.branches.heads.values().cloned().collect();
+    protected.extend(branch_head(&index));
+
+    let now = Utc::now();
+    let mut pruned = plan_pruning(&policy, &index.summaries(), &sizes, &protected, now);
+    pruned.extend(expired_trash(&policy, &index.trash(), &sizes, now));
+    pruned.sort_by_key(|v| v.version.created_at);
+    let freed_bytes = pruned.iter().map(|v| v.size).sum();
+    Ok((
+        index,
+        PruneVersionsResponse {
+            policy,
+            pruned,
+            freed_bytes,
+            versions: Vec::new(),
+        },
+    ))
+}
+
+/// The versions from `from` to `to`, oldest first, if they form a run that
+/// can be squashed.
+fn squash_run(index: &VersionIndex, from: &str, to: &str) -> Result<Vec<VersionSummary>, VersionError> {
+    let summaries: HashMap<&str, &VersionSummary> =
+        index.versions.iter().map(|v| (v.summary.id.as_str(), &v.summary)).collect();
+    let mut children: HashMap<&str, usize> = HashMap::new();
+    for version in index.versions.iter() {
+        for parent in &version.summary.parent_ids {
+            *children.entry(parent.as_str()).or_default() += 1;
+        }
+    }
+    let lookup = |id: &str| summaries.get(id).copied().ok_or_else(|| VersionError::NotFound(id.to_string()));
+    lookup(from)?;
+
+    let mut run = vec![lookup(to)?.clone()];
+    while run[run.len() - 1].id != from {
+        let current = &run[run.len() - 1];
+        let parent = match current.parent_ids.as_slice() {
+            [parent] => parent,
+            [] => return Err(VersionError::InvalidSquash(format!("version {to} does not descend from {from}"))),
+            _ => {
+                return Err(VersionError::InvalidSquash(format!(
+                    "version {} is a merge",
+                    current.version_number
+                )))
+            }
+        };
+        let parent = lookup(parent)?;
+        if children.get(parent.id.as_str()).copied().unwrap_or(0) > 1 {
+            return Err(VersionError::InvalidSquash(format!(
+                "version {} has other children",
+                parent.version_number
+            )));
+        }
+        run.push(parent.clone());
+    }
+    if run.len() < 2 {
+        return Err(VersionError::InvalidSquash("the range holds a single version".to_string()));
+    }
+    if let Some(trashed) = run.iter().find(|v| v.deleted_at.is_some()) {
+        return Err(VersionError::InvalidSquash(format!("version {} is in the trash", trashed.version_number)));
+    }
+    run.reverse();
+    Ok(run)
+}
+
+/// The document's `retention` policy, or the default one.
+fn retention_policy(path: &Path) -> Result<RetentionPolicy, VersionError> {
+    Ok(load_metadata(path)?
+        .custom
+        .get("retention")
+        .and_then(|value| serde_json::from_value(value.clone()).ok())
+        .unwrap_or_default())
+}
+
+/// Remove trashed versions whose time in the trash has run out.
+fn purge_expired_trash(path: &Path, index: VersionIndex) -> Result<VersionIndex, VersionError> {
+    let sizes = HashMap::new();
+    let expired = expired_trash(&retention_policy(path)?, &index.trash(), &sizes, Utc::now());
+    if expired.is_empty() {
+        return Ok(index);
+    }
+    let ids: HashSet<String> = expired.into_iter().map(|v| v.version.id).collect();
+    Ok(version_store::remove_versions(path, &ids)?)
 }
 
-fn find_version(
+/// Nearest common ancestor of two versions: the first ancestor of `theirs`,
+/// in breadth-first order, that is also an ancestor of `ours`.
+fn merge_base(index: &VersionIndex, ours: &str, theirs: &str) -> Option<String> {
+    let ours_ancestors: HashSet<String> = ancestors(index, ours).into_iter().collect();
+    ancestors(index, theirs)
+        .into_iter()
+        .find(|id| ours_ancestors.contains(id))
+}
+
+/// A version and all its ancestors, breadth-first.
+fn ancestors(index: &VersionIndex, version_id: &str) -> Vec<String> {
+    let parents: HashMap<&str, &[String]> = index
+        .versions
+        .iter()
+        .map(|v| (v.summary.id.as_str(), v.summary.parent_ids.as_slice()))
+        .collect();
+    let mut seen = HashSet::new();
+    let mut order = Vec::new();
+    let mut queue = VecDeque::from([version_id.to_string()]);
+    while let Some(id) = queue.pop_front() {
+        let Some(parent_ids) = parents.get(id.as_str()) else {
+            continue;
+        };
+        if seen.insert(id.clone()) {
+            queue.extend(parent_ids.iter().cloned());
+            order.push(id);
+        }
+    }
+    order
+}
+
+fn branch_exists(index: &VersionIndex, name: &str) -> bool {
+    index.branches.current == name || index.branches.heads.contains_key(name)
+}
+
+pub(crate) fn graph_response(index: VersionIndex) -> ListVersionsResponse {
+    let versions = index.summaries();
+    let current_version_number = versions
+        .iter()
+        .map(|v| v.version_number)
+        .max()
+        .unwrap_or(0);
+    ListVersionsResponse {
+        versions,
+        current_version_number,
+        branches: index.branches,
+    }
+}
+
+fn read_version(path: &Path, version_id: &str) -> Result<DocumentVersion, VersionError> {
+    let value = version_store::read_version(path, version_id)?
+        .ok_or_else(|| VersionError::NotFound(version_id.to_string()))?;
+    Ok(serde_json::from_value(value)?)
+}
+
+pub(crate) fn find_version(
     versions: &[serde_json::Value],
     version_id: &str,
 ) -> Result<DocumentVersion, VersionError> {
@@ -261,6 +1609,74 @@ fn find_version(
     Err(VersionError::NotFound(version_id.to_string()))
 }
 
+/// Materialize a diff side, given directly or as a version ID, as a
+/// version. The working copy gets the ID [`WORKING_COPY_ID`] and version
+/// number 0.
+fn resolve_diff_side(
+    path: &Path,
+    side: Option<DiffSide>,
+    version_id: Option<String>,
+    name: &str,
+) -> Result<DocumentVersion, VersionError> {
+    match pick_diff_side(side, version_id, name)? {
+        DiffSide::Version { version_id } => read_version(path, &version_id),
+        DiffSide::Working { state: Some(state) } => Ok(working_state_version(state)),
+        DiffSide::Working { state: None } => Ok(working_copy_version(&load_working_copy(path)?)),
+    }
+}
+
+/// The side of a diff, from `side` or else `version_id`.
+pub(crate) fn pick_diff_side(
+    side: Option<DiffSide>,
+    version_id: Option<String>,
+    name: &str,
+) -> Result<DiffSide, VersionError> {
+    side.or(version_id.map(|version_id| DiffSide::Version { version_id }))
+        .ok_or_else(|| VersionError::MissingDiffSide(name.to_string()))
+}
+
+/// Unsaved working state as a transient version.
+pub(crate) fn working_state_version(state: WorkingState) -> DocumentVersion {
+    let text = PieceTableContent {
+        base_text: state.base_text,
+        chunks: state.chunks,
+    }
+    .to_text();
+    working_version(text, state.metadata.ranges, state.document_tree, None)
+}
+
+/// A document's working copy as a transient version.
+pub(crate) fn working_copy_version(payload: &DocumentPayload) -> DocumentVersion {
+    working_version(
+        payload_text(payload),
+        payload.metadata.ranges.clone(),
+        payload.document_tree.clone(),
+        Some(payload.assets.iter().map(VersionAsset::from).collect()),
+    )
+}
+
+fn working_version(
+    text: String,
+    ranges: Vec<MetadataRange>,
+    document_tree: Option<DocumentTree>,
+    assets: Option<Vec<VersionAsset>>,
+) -> DocumentVersion {
+    let mut version = DocumentVersion::new(0, text, Some("Working copy".to_string()));
+    version.id = WORKING_COPY_ID.to_string();
+    version.ranges = ranges;
+    version.document_tree = document_tree;
+    version.assets = assets;
+    version
+}
+
+fn diff_label(version: &DocumentVersion) -> String {
+    if version.id == WORKING_COPY_ID {
+        "Working copy".to_string()
+    } else {
+        format!("Version {}", version.version_number)
+    }
+}
+
 fn split_change_into_lines(value: &str) -> Vec<String> {
     let lines: Vec<&str> = value.lines().collect();
     if lines.is_empty() && !value.is_empty() {
@@ -270,7 +1686,100 @@ fn split_change_into_lines(value: &str) -> Vec<String> {
     }
 }
 
-fn compute_diff(from: DocumentVersion, to: DocumentVersion) -> Result<VersionDiff, VersionError> {
+/// Fill in `spans` for each run of deleted lines directly followed by added
+/// lines, in words for `Line` and in characters for `Char`. Each run is
+/// compared as a whole, so rewrapped text lines up.
+fn mark_intraline(lines: &mut [DiffLine], granularity: DiffGranularity) {
+    let mut idx = 0;
+    while idx < lines.len() {
+        let start = idx;
+        while idx < lines.len() && lines[idx].kind == DiffLineKind::Deletion {
+            idx += 1;
+        }
+        let split = idx;
+        while idx < lines.len() && lines[idx].kind == DiffLineKind::Addition {
+            idx += 1;
+        }
+        if split > start && idx > split {
+            let (old, new) = lines[start..idx].split_at_mut(split - start);
+            let join = |lines: &[DiffLine]| lines.iter().map(|l| l.content.as_str()).collect::<Vec<_>>().join("\n");
+            let (old_text, new_text) = (join(old), join(new));
+            let diff = match granularity {
+                DiffGranularity::Char => TextDiff::from_chars(old_text.as_str(), new_text.as_str()),
+                DiffGranularity::Line => TextDiff::from_words(old_text.as_str(), new_text.as_str()),
+            };
+            let offsets = |tokens: &[&str]| {
+                let mut offsets = vec![0];
+                for token in tokens {
+                    offsets.push(offsets[offsets.len() - 1] + utf16_len(token));
+                }
+                offsets
+            };
+            let (old_offsets, new_offsets) = (offsets(diff.old_slices()), offsets(diff.new_slices()));
+            let mut old_spans: Vec<DiffSpan> = Vec::new();
+            let mut new_spans: Vec<DiffSpan> = Vec::new();
+            for op in diff.ops() {
+                if matches!(op, DiffOp::Equal { .. }) {
+                    continue;
+                }
+                let (removed, added) = (op.old_range(), op.new_range());
+                push_span(&mut old_spans, old_offsets[removed.start], old_offsets[removed.end]);
+                push_span(&mut new_spans, new_offsets[added.start], new_offsets[added.end]);
+            }
+            split_spans(old, &old_spans);
+            split_spans(new, &new_spans);
+        }
+        if idx == start {
+            idx += 1;
+        }
+    }
+}
+
+/// Append `[start, end)`, merging it into the previous span if they touch.
+fn push_span(spans: &mut Vec<DiffSpan>, start: usize, end: usize) {
+    if start == end {
+        return;
+    }
+    match spans.last_mut() {
+        Some(last) if last.end == start => last.end = end,
+        _ => spans.push(DiffSpan { start, end }),
+    }
+}
+
+/// Distribute spans over the `\n`-joined text of `lines` to each line.
+fn split_spans(lines: &mut [DiffLine], spans: &[DiffSpan]) {
+    let mut line_start = 0;
+    for line in lines {
+        let line_end = line_start + utf16_len(&line.content);
+        for span in spans {
+            let (start, end) = (span.start.max(line_start), span.end.min(line_end));
+            if start < end {
+                line.spans.push(DiffSpan {
+                    start: start - line_start,
+                    end: end - line_start,
+                });
+            }
+        }
+        line_start = line_end + 1;
+    }
+}
+
+/// Line ranges (old, new) covered by a hunk's ops.
+fn hunk_ranges(ops: &[DiffOp]) -> (std::ops::Range<usize>, std::ops::Range<usize>) {
+    match (ops.first(), ops.last()) {
+        (Some(first), Some(last)) => (
+            first.old_range().start..last.old_range().end,
+            first.new_range().start..last.new_range().end,
+        ),
+        _ => (0..0, 0..0),
+    }
+}
+
+pub(crate) fn compute_diff(
+    from: DocumentVersion,
+    to: DocumentVersion,
+    granularity: DiffGranularity,
+) -> Result<VersionDiff, VersionError> {
     let old_text = &from.content;
     let new_text = &to.content;
 
@@ -278,27 +1787,36 @@ fn compute_diff(from: DocumentVersion, to: DocumentVersion) -> Result<VersionDif
 
     let mut additions = 0;
     let mut deletions = 0;
-    let mut unchanged = 0;
+    // Count every unchanged line, not only the context lines shown in hunks.
+    let unchanged: usize = text_diff
+        .ops()
+        .iter()
+        .filter_map(|op| match op {
+            DiffOp::Equal { len, .. } => Some(*len),
+            _ => None,
+        })
+        .sum();
     let mut hunks: Vec<DiffHunk> = Vec::new();
 
     let mut unified_diff = String::new();
-    unified_diff.push_str(&format!("--- Version {}\n", from.version_number));
-    unified_diff.push_str(&format!("+++ Version {}\n", to.version_number));
+    unified_diff.push_str(&format!("--- {}\n", diff_label(&from)));
+    unified_diff.push_str(&format!("+++ {}\n", diff_label(&to)));
 
     for hunk in text_diff.unified_diff().context_radius(3).iter_hunks() {
+        let (old_range, new_range) = hunk_ranges(hunk.ops());
         let header = format!(
             "@@ -{},{} +{},{} @@",
-            hunk.old_range().start + 1,
-            hunk.old_range().len,
-            hunk.new_range().start + 1,
-            hunk.new_range().len
+            old_range.start + 1,
+            old_range.len(),
+            new_range.start + 1,
+            new_range.len()
         );
         unified_diff.push_str(&header);
         unified_diff.push('\n');
 
         let mut diff_lines: Vec<DiffLine> = Vec::new();
-        let mut old_line = hunk.old_range().start + 1;
-        let mut new_line = hunk.new_range().start + 1;
+        let mut old_line = old_range.start + 1;
+        let mut new_line = new_range.start + 1;
 
         for change in hunk.iter_changes() {
             let value = change.value();
@@ -328,7 +1846,6 @@ fn compute_diff(from: DocumentVersion, to: DocumentVersion) -> Result<VersionDif
                         )
                     }
                     ChangeTag::Equal => {
-                        unchanged += 1;
                         (
                             DiffLineKind::Context,
                             ' ',
@@ -347,6 +1864,7 @@ fn compute_diff(from: DocumentVersion, to: DocumentVersion) -> Result<VersionDif
                     content: line_content.clone(),
                     old_line: old_line_num,
                     new_line: new_line_num,
+                    spans: Vec::new(),
                 });
             }
 
@@ -360,12 +1878,14 @@ fn compute_diff(from: DocumentVersion, to: DocumentVersion) -> Result<VersionDif
             }
         }
 
+        mark_intraline(&mut diff_lines, granularity);
+
         hunks.push(DiffHunk {
             header,
-            old_start: hunk.old_range().start + 1,
-            old_lines: hunk.old_range().len,
-            new_start: hunk.new_range().start + 1,
-            new_lines: hunk.new_range().len,
+            old_start: old_range.start + 1,
+            old_lines: old_range.len(),
+            new_start: new_range.start + 1,
+            new_lines: new_range.len(),
             lines: diff_lines,
         });
     }
@@ -388,3 +1908,62 @@ fn compute_diff(from: DocumentVersion, to: DocumentVersion) -> Result<VersionDif
         hunks,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spans(from: &str, to: &str, granularity: DiffGranularity) -> (Vec<DiffSpan>, Vec<DiffSpan>) {
+        let diff = compute_diff(
+            DocumentVersion::new(1, from.to_string(), None),
+            DocumentVersion::new(2, to.to_string(), None),
+            granularity,
+        )
+        .unwrap();
+        let lines = &diff.hunks[0].lines;
+        let of = |kind| lines.iter().filter(|l| l.kind == kind).flat_map(|l| l.spans.clone()).collect();
+        (of(DiffLineKind::Deletion), of(DiffLineKind::Addition))
+    }
+
+    #[test]
+    fn line_granularity_marks_changed_words() {
+        let (old, new) = spans("intro\nthe lazy dog, then naps\n", "intro\nthe lazy dog then naps\n", DiffGranularity::Line);
+        assert_eq!(old, vec![DiffSpan { start: 9, end: 13 }]);
+        assert_eq!(new, vec![DiffSpan { start: 9, end: 12 }]);
+    }
+
+    #[test]
+    fn char_granularity_marks_changed_characters() {
+        let (old, new) = spans("intro\nthe lazy dog, then naps\n", "intro\nthe lazy dog then naps\n", DiffGranularity::Char);
+        assert_eq!(old, vec![DiffSpan { start: 12, end: 13 }]);
+        assert!(new.is_empty());
+    }
+
+    #[test]
+    fn word_is_read_as_line_granularity() {
+        let granularity: DiffGranularity = serde_json::from_str("\"word\"").unwrap();
+        assert_eq!(granularity, DiffGranularity::Line);
+    }
+
+    #[test]
+    fn spans_count_utf16_code_units() {
+        let (from, to) = ("\u{1F389} party time \u{1F600}x\n", "\u{1F389} party hour \u{1F600}y\n");
+        let (old, new) = spans(from, to, DiffGranularity::Line);
+        assert_eq!(old, vec![DiffSpan { start: 9, end: 13 }, DiffSpan { start: 14, end: 17 }]);
+        assert_eq!(new, old);
+        let (old, _) = spans(from, to, DiffGranularity::Char);
+        assert_eq!(old, vec![DiffSpan { start: 9, end: 13 }, DiffSpan { start: 16, end: 17 }]);
+    }
+
+    #[test]
+    fn rewrapped_lines_line_up() {
+        let (old, new) = spans(
+            "intro\nthe quick brown fox jumps\nend\n",
+            "intro\nthe quick brown\nfox jumps\nend\n",
+            DiffGranularity::Char,
+        );
+        // only the space that became a line break differs
+        assert_eq!(old, vec![DiffSpan { start: 15, end: 16 }]);
+        assert!(new.is_empty());
+    }
+}
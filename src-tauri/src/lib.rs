@@ -4,7 +4,12 @@ mod storage;
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
+  let sessions = commands::session::DocumentSessions::default();
+  sessions.start_autoflush();
+  let open_sessions = sessions.clone();
+
   tauri::Builder::default()
+    .manage(sessions)
     .setup(|app| {
       if cfg!(debug_assertions) {
         app.handle().plugin(
@@ -19,13 +24,54 @@ pub fn run() {
       commands::document::save_grokedoc,
       commands::document::load_grokedoc,
       commands::document::export_document_markdown,
+      commands::document::check_document_consistency,
+      commands::document::get_formatting_at,
+      commands::history::append_operations,
+      commands::history::undo_operation,
+      commands::history::redo_operation,
+      commands::history::get_history_state,
+      commands::session::open_document,
+      commands::session::flush_document,
+      commands::session::close_document,
+      commands::session::apply_edits,
+      commands::session::put_asset,
+      commands::session::update_metadata_ranges,
+      commands::session::session_create_version,
+      commands::session::session_list_versions,
+      commands::session::session_get_version,
+      commands::session::session_diff_versions,
+      commands::session::session_restore_version,
+      commands::session::session_delete_version,
       commands::versioning::create_version,
       commands::versioning::list_versions,
       commands::versioning::get_version,
       commands::versioning::diff_versions,
+      commands::versioning::diff_document_trees,
+      commands::versioning::blame_document,
+      commands::versioning::version_statistics,
       commands::versioning::restore_version,
-      commands::versioning::delete_version
+      commands::versioning::restore_selection,
+      commands::versioning::update_version_metadata,
+      commands::versioning::delete_version,
+      commands::versioning::squash_versions,
+      commands::versioning::list_trash,
+      commands::versioning::restore_from_trash,
+      commands::versioning::empty_trash,
+      commands::versioning::preview_prune_versions,
+      commands::versioning::prune_versions,
+      commands::versioning::create_branch,
+      commands::versioning::checkout_branch,
+      commands::versioning::move_branch,
+      commands::versioning::delete_branch,
+      commands::versioning::merge_versions,
+      commands::git::export_versions_to_git,
+      commands::git::import_versions_from_git
     ])
-    .run(tauri::generate_context!())
-    .expect("error while running tauri application");
+    .build(tauri::generate_context!())
+    .expect("error while running tauri application")
+    .run(move |_app, event| {
+      if let tauri::RunEvent::Exit = event {
+        open_sessions.flush_all();
+      }
+    });
 }
@@ -0,0 +1,196 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use serde::{Deserialize, Serialize};
+use similar::{DiffOp, TextDiff};
+
+use super::document_tree::{DocumentTree, NodeId};
+use super::piece_table::utf16_len;
+use super::version::DocumentVersion;
+
+/// One line of blamed content.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BlameLine {
+    /// Line number (1-indexed)
+    pub line: usize,
+    /// The line without its terminator
+    pub content: String,
+    /// Version that last changed the line; absent for edits not yet
+    /// captured in any version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version_id: Option<String>,
+    /// Top-level block starting on this line
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<NodeId>,
+}
+
+/// Attribute every line of `content` to the version that last changed it.
+/// `parents` are the versions `content` derives from. A version inherits
+/// lines from its parents first and then from the version it restored, so
+/// merges and restores keep the original attribution. Versions without
+/// parents (other than the first) are taken to follow the previous version,
+/// as in archives written before the version graph.
+pub fn blame(
+    versions: &[DocumentVersion],
+    parents: &[String],
+    content: &str,
+    tree: Option<&DocumentTree>,
+) -> Vec<BlameLine> {
+    let mut order: Vec<usize> = (0..versions.len()).collect();
+    order.sort_by_key(|&idx| (versions[idx].version_number, versions[idx].created_at));
+    let by_id: HashMap<&str, usize> = versions.iter().enumerate().map(|(idx, v)| (v.id.as_str(), idx)).collect();
+
+    let mut sources = vec![Vec::new(); versions.len()];
+    for (pos, &idx) in order.iter().enumerate() {
+        let version = &versions[idx];
+        let mut from: Vec<usize> = version.parent_ids.iter().filter_map(|id| by_id.get(id.as_str()).copied()).collect();
+        if version.parent_ids.is_empty() && pos > 0 {
+            from.push(order[pos - 1]);
+        }
+        from.extend(version.restored_from.as_deref().and_then(|id| by_id.get(id).copied()));
+        sources[idx] = from;
+    }
+
+    let roots: Vec<usize> = parents.iter().filter_map(|id| by_id.get(id.as_str()).copied()).collect();
+    let mut needed = HashSet::new();
+    let mut queue: VecDeque<usize> = roots.iter().copied().collect();
+    while let Some(idx) = queue.pop_front() {
+        if needed.insert(idx) {
+            queue.extend(sources[idx].iter().copied());
+        }
+    }
+
+    // Sources are always older, so one pass in version order suffices.
+    let mut origins: HashMap<usize, Vec<Option<usize>>> = HashMap::new();
+    for &idx in order.iter().filter(|idx| needed.contains(*idx)) {
+        let inherited = inherit(&versions[idx].content, &sources[idx], versions, &origins, Some(idx));
+        origins.insert(idx, inherited);
+    }
+    let lines = inherit(content, &roots, versions, &origins, None);
+
+    let tokens = line_tokens(content);
+    let mut node_ids: Vec<Option<NodeId>> = vec![None; tokens.len()];
+    if let Some(tree) = tree {
+        let mut starts = Vec::with_capacity(tokens.len());
+        let mut pos = 0;
+        for token in &tokens {
+            starts.push(pos);
+            pos += utf16_len(token);
+        }
+        for block in tree.blocks() {
+            let Some(span) = block.span() else {
+                continue;
+            };
+            let line = starts.partition_point(|&start| start <= span.start).saturating_sub(1);
+            if let Some(slot) = node_ids.get_mut(line).filter(|slot| slot.is_none()) {
+                *slot = Some(block.id().to_string());
+            }
+        }
+    }
+
+    tokens
+        .into_iter()
+        .zip(lines)
+        .zip(node_ids)
+        .enumerate()
+        .map(|(idx, ((token, origin), node_id))| BlameLine {
+            line: idx + 1,
+            content: token.trim_end_matches(['\n', '\r']).to_string(),
+            version_id: origin.map(|origin| versions[origin].id.clone()),
+            node_id,
+        })
+        .collect()
+}
+
+// ============================================================================
+// Helper Functions
+// ============================================================================
+
+fn line_tokens(content: &str) -> Vec<&str> {
+    TextDiff::from_lines("", content).new_slices().to_vec()
+}
+
+/// Origins of the lines of `content`: each line unchanged from a source
+/// takes that source's origin, in source order; the rest get `own`.
+fn inherit(
+    content: &str,
+    sources: &[usize],
+    versions: &[DocumentVersion],
+    origins: &HashMap<usize, Vec<Option<usize>>>,
+    own: Option<usize>,
+) -> Vec<Option<usize>> {
+    let mut lines: Vec<Option<Option<usize>>> = vec![None; line_tokens(content).len()];
+    for source in sources {
+        let Some(source_origins) = origins.get(source) else {
+            continue;
+        };
+        let diff = TextDiff::from_lines(versions[*source].content.as_str(), content);
+        for op in diff.ops() {
+            if let DiffOp::Equal {
+                old_index,
+                new_index,
+                len,
+            } = *op
+            {
+                for offset in 0..len {
+                    let slot = &mut lines[new_index + offset];
+                    if slot.is_none() {
+                        *slot = Some(source_origins[old_index + offset]);
+                    }
+                }
+            }
+        }
+    }
+    lines.into_iter().map(|line| line.unwrap_or(own)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(number: u32, content: &str, parents: &[&DocumentVersion]) -> DocumentVersion {
+        let mut version = DocumentVersion::new(number, content.to_string(), None);
+        version.parent_ids = parents.iter().map(|parent| parent.id.clone()).collect();
+        version
+    }
+
+    fn origins(versions: &[DocumentVersion], head: &DocumentVersion, content: &str) -> Vec<Option<u32>> {
+        blame(versions, std::slice::from_ref(&head.id), content, None)
+            .into_iter()
+            .map(|line| {
+                let id = line.version_id?;
+                versions.iter().find(|v| v.id == id).map(|v| v.version_number)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn legacy_versions_follow_the_previous_one() {
+        let v1 = version(1, "a\nb\n", &[]);
+        let v2 = version(2, "a\nB\n", &[]);
+        let v3 = version(3, "a\nB\nc\n", &[]);
+        let versions = vec![v3.clone(), v1, v2];
+        assert_eq!(origins(&versions, &v3, "a\nB\nc\nd\n"), vec![Some(1), Some(2), Some(3), None]);
+        assert!(blame(&versions, &[], "a\n", None)[0].version_id.is_none());
+    }
+
+    #[test]
+    fn merges_inherit_from_both_parents() {
+        let v1 = version(1, "a\nb\nc\n", &[]);
+        let v2 = version(2, "A\nb\nc\n", &[&v1]);
+        let v3 = version(3, "a\nb\nC\n", &[&v1]);
+        let v4 = version(4, "A\nb\nC\n", &[&v2, &v3]);
+        let versions = vec![v1, v2, v3, v4.clone()];
+        assert_eq!(origins(&versions, &v4, &v4.content), vec![Some(2), Some(1), Some(3)]);
+    }
+
+    #[test]
+    fn restores_keep_the_original_attribution() {
+        let v1 = version(1, "a\nb\n", &[]);
+        let v2 = version(2, "x\n", &[&v1]);
+        let mut v3 = version(3, "a\nb\nnew\n", &[&v2]);
+        v3.restored_from = Some(v1.id.clone());
+        let versions = vec![v1, v2, v3.clone()];
+        assert_eq!(origins(&versions, &v3, &v3.content), vec![Some(1), Some(1), Some(3)]);
+    }
+}
@@ -0,0 +1,586 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::document_tree::{
+    BlockNode, DocumentTree, InlineMark, InlineObjectRef, NodeId, TextBlock, TextRange, OBJECT_REPLACEMENT_CHAR,
+};
+
+/// Category of a tree-over-buffer invariant violation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum IssueKind {
+    /// Node range ends past the end of the buffer
+    RangeOutOfBounds,
+    /// Node range has `start > end`
+    InvertedRange,
+    /// Node range starts before the previous node ends
+    Overlap,
+    /// Node starts before a node that precedes it in the tree
+    Misordered,
+    /// Inline mark does not fit inside its parent block
+    MarkOutOfBounds,
+    /// Image position does not hold a U+FFFC sentinel
+    MissingSentinel,
+    /// Table range does not contain exactly one sentinel
+    SentinelCount,
+    /// Sentinel in the buffer that no node owns
+    OrphanSentinel,
+    /// Sentinel claimed by more than one node
+    DuplicateSentinelOwner,
+    /// Image `assetRef.targetPos` differs from its buffer position
+    AssetPositionMismatch,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConsistencyIssue {
+    pub kind: IssueKind,
+    /// Offending node, if the issue is tied to one
+    pub node_id: Option<NodeId>,
+    /// Buffer offset the issue refers to, if any
+    pub position: Option<usize>,
+    pub message: String,
+}
+
+/// Outcome of [`repair`]: what was fixed, and what still needs attention.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConsistencyReport {
+    pub repaired: Vec<ConsistencyIssue>,
+    pub unresolved: Vec<ConsistencyIssue>,
+}
+
+impl ConsistencyIssue {
+    /// Whether two issues describe the same problem, ignoring the message.
+    fn same_problem(&self, other: &Self) -> bool {
+        self.kind == other.kind
+            && self.node_id == other.node_id
+            && (self.node_id.is_some() || self.position == other.position)
+    }
+
+    fn new(kind: IssueKind, node_id: Option<&str>, position: Option<usize>, message: String) -> Self {
+        Self {
+            kind,
+            node_id: node_id.map(str::to_string),
+            position,
+            message,
+        }
+    }
+}
+
+/// Leaf node that occupies buffer space, in document order.
+enum Leaf<'a> {
+    Text {
+        id: &'a str,
+        range: TextRange,
+        marks: &'a [InlineMark],
+        inline_objects: Vec<usize>,
+    },
+    Table {
+        id: &'a str,
+        range: TextRange,
+    },
+    Image {
+        id: &'a str,
+        pos: usize,
+        target_pos: usize,
+    },
+}
+
+enum LeafMut<'a> {
+    Text {
+        range: &'a mut TextRange,
+        marks: &'a mut Vec<InlineMark>,
+        inline_objects: Option<&'a BTreeMap<String, InlineObjectRef>>,
+    },
+    Table {
+        range: &'a mut TextRange,
+    },
+    Image {
+        pos: &'a mut usize,
+        target_pos: &'a mut usize,
+    },
+}
+
+impl Leaf<'_> {
+    fn id(&self) -> &str {
+        match self {
+            Self::Text { id, .. } | Self::Table { id, .. } | Self::Image { id, .. } => id,
+        }
+    }
+
+    fn span(&self) -> TextRange {
+        match self {
+            Self::Text { range, .. } | Self::Table { range, .. } => *range,
+            Self::Image { pos, .. } => TextRange::new(*pos, pos + 1),
+        }
+    }
+}
+
+fn text_leaf(block: &TextBlock) -> Leaf<'_> {
+    match block {
+        TextBlock::Paragraph(p) => paragraph_leaf(&p.id, p.text_range, &p.marks, p.inline_objects.as_ref()),
+        TextBlock::Heading(h) => paragraph_leaf(&h.id, h.text_range, &h.marks, None),
+    }
+}
+
+fn paragraph_leaf<'a>(
+    id: &'a str,
+    range: TextRange,
+    marks: &'a [InlineMark],
+    inline_objects: Option<&BTreeMap<String, InlineObjectRef>>,
+) -> Leaf<'a> {
+    Leaf::Text {
+        id,
+        range,
+        marks,
+        inline_objects: inline_object_positions(range, inline_objects),
+    }
+}
+
+/// Buffer positions of a paragraph's inline object sentinels. Inline object
+/// keys are paragraph-relative offsets.
+fn inline_object_positions(range: TextRange, inline_objects: Option<&BTreeMap<String, InlineObjectRef>>) -> Vec<usize> {
+    inline_objects
+        .into_iter()
+        .flat_map(|objects| objects.keys())
+        .filter_map(|key| key.parse::<usize>().ok())
+        .map(|offset| range.start.saturating_add(offset))
+        .collect()
+}
+
+fn leaves(tree: &DocumentTree) -> Vec<Leaf<'_>> {
+    let mut out = Vec::new();
+    for block in tree.blocks() {
+        match block {
+            BlockNode::Paragraph(p) => {
+                out.push(paragraph_leaf(&p.id, p.text_range, &p.marks, p.inline_objects.as_ref()))
+            }
+            BlockNode::Heading(h) => out.push(paragraph_leaf(&h.id, h.text_range, &h.marks, None)),
+            BlockNode::Table(t) => out.push(Leaf::Table {
+                id: &t.id,
+                range: t.text_range,
+            }),
+            BlockNode::Image(i) => out.push(Leaf::Image {
+                id: &i.id,
+                pos: i.buffer_position,
+                target_pos: i.asset_ref.target_pos,
+            }),
+            BlockNode::Blockquote(q) => out.extend(q.children.iter().map(text_leaf)),
+            BlockNode::List(l) => out.extend(l.items.iter().map(|item| text_leaf(&item.content))),
+        }
+    }
+    out
+}
+
+fn text_leaf_mut(block: &mut TextBlock) -> LeafMut<'_> {
+    match block {
+        TextBlock::Paragraph(p) => LeafMut::Text {
+            range: &mut p.text_range,
+            marks: &mut p.marks,
+            inline_objects: p.inline_objects.as_ref(),
+        },
+        TextBlock::Heading(h) => LeafMut::Text {
+            range: &mut h.text_range,
+            marks: &mut h.marks,
+            inline_objects: None,
+        },
+    }
+}
+
+fn leaves_mut(tree: &mut DocumentTree) -> Vec<LeafMut<'_>> {
+    let mut out = Vec::new();
+    for section in &mut tree.root.children {
+        for block in &mut section.children {
+            match block {
+                BlockNode::Paragraph(p) => out.push(LeafMut::Text {
+                    range: &mut p.text_range,
+                    marks: &mut p.marks,
+                    inline_objects: p.inline_objects.as_ref(),
+                }),
+                BlockNode::Heading(h) => out.push(LeafMut::Text {
+                    range: &mut h.text_range,
+                    marks: &mut h.marks,
+                    inline_objects: None,
+                }),
+                BlockNode::Table(t) => out.push(LeafMut::Table {
+                    range: &mut t.text_range,
+                }),
+                BlockNode::Image(i) => out.push(LeafMut::Image {
+                    pos: &mut i.buffer_position,
+                    target_pos: &mut i.asset_ref.target_pos,
+                }),
+                BlockNode::Blockquote(q) => out.extend(q.children.iter_mut().map(text_leaf_mut)),
+                BlockNode::List(l) => out.extend(l.items.iter_mut().map(|item| text_leaf_mut(&mut item.content))),
+            }
+        }
+    }
+    out
+}
+
+/// Buffer length and sentinel positions, in UTF-16 code units.
+fn scan_buffer(text: &str) -> (usize, Vec<usize>) {
+    let mut len = 0;
+    let mut sentinels = Vec::new();
+    for ch in text.chars() {
+        if ch == OBJECT_REPLACEMENT_CHAR {
+            sentinels.push(len);
+        }
+        len += ch.len_utf16();
+    }
+    (len, sentinels)
+}
+
+/// Verify the tree-over-buffer invariants from `docs/fileformat.md`:
+/// node ranges are in bounds, ordered and non-overlapping, marks fit inside
+/// their parent, and every image/table owns exactly one U+FFFC sentinel.
+pub fn check(tree: &DocumentTree, text: &str) -> Vec<ConsistencyIssue> {
+    let (buffer_len, sentinels) = scan_buffer(text);
+    let leaves = leaves(tree);
+    let mut issues = Vec::new();
+
+    let mut prev: Option<(TextRange, &str)> = None;
+    for leaf in &leaves {
+        let span = leaf.span();
+        let id = leaf.id();
+        if span.start > span.end {
+            issues.push(ConsistencyIssue::new(
+                IssueKind::InvertedRange,
+                Some(id),
+                Some(span.start),
+                format!("range {}..{} is inverted", span.start, span.end),
+            ));
+        }
+        if span.end > buffer_len {
+            issues.push(ConsistencyIssue::new(
+                IssueKind::RangeOutOfBounds,
+                Some(id),
+                Some(span.end),
+                format!("range {}..{} exceeds buffer length {}", span.start, span.end, buffer_len),
+            ));
+        }
+        if let Some((prev_span, prev_id)) = prev {
+            if span.start < prev_span.start {
+                issues.push(ConsistencyIssue::new(
+                    IssueKind::Misordered,
+                    Some(id),
+                    Some(span.start),
+                    format!("starts at {} before preceding node {} at {}", span.start, prev_id, prev_span.start),
+                ));
+            } else if span.start < prev_span.end {
+                issues.push(ConsistencyIssue::new(
+                    IssueKind::Overlap,
+                    Some(id),
+                    Some(span.start),
+                    format!("starts at {} inside preceding node {} ending at {}", span.start, prev_id, prev_span.end),
+                ));
+            }
+        }
+        prev = Some((span, id));
+
+        match leaf {
+            Leaf::Text { range, marks, .. } => {
+                for mark in marks.iter() {
+                    if mark.start > mark.end || mark.end > range.len() {
+                        issues.push(ConsistencyIssue::new(
+                            IssueKind::MarkOutOfBounds,
+                            Some(id),
+                            Some(range.start + mark.start),
+                            format!("mark {}..{} does not fit in block of length {}", mark.start, mark.end, range.len()),
+                        ));
+                    }
+                }
+            }
+            Leaf::Table { range, .. } => {
+                let count = sentinels.iter().filter(|&&s| s >= range.start && s < range.end).count();
+                if count != 1 {
+                    issues.push(ConsistencyIssue::new(
+                        IssueKind::SentinelCount,
+                        Some(id),
+                        Some(range.start),
+                        format!("table range {}..{} holds {} sentinels, expected 1", range.start, range.end, count),
+                    ));
+                }
+            }
+            Leaf::Image { pos, target_pos, .. } => {
+                if sentinels.binary_search(pos).is_err() {
+                    issues.push(ConsistencyIssue::new(
+                        IssueKind::MissingSentinel,
+                        Some(id),
+                        Some(*pos),
+                        format!("no sentinel at image position {}", pos),
+                    ));
+                }
+                if target_pos != pos {
+                    issues.push(ConsistencyIssue::new(
+                        IssueKind::AssetPositionMismatch,
+                        Some(id),
+                        Some(*pos),
+                        format!("asset targetPos {} differs from buffer position {}", target_pos, pos),
+                    ));
+                }
+            }
+        }
+    }
+
+    // Every sentinel must have exactly one owner.
+    for &sentinel in &sentinels {
+        let owners: Vec<&str> = leaves
+            .iter()
+            .filter(|leaf| match leaf {
+                Leaf::Image { pos, .. } => *pos == sentinel,
+                Leaf::Table { range, .. } => sentinel >= range.start && sentinel < range.end,
+                Leaf::Text { inline_objects, .. } => inline_objects.contains(&sentinel),
+            })
+            .map(Leaf::id)
+            .collect();
+        match owners.len() {
+            0 => issues.push(ConsistencyIssue::new(
+                IssueKind::OrphanSentinel,
+                None,
+                Some(sentinel),
+                format!("sentinel at {} has no owning node", sentinel),
+            )),
+            1 => {}
+            _ => issues.push(ConsistencyIssue::new(
+                IssueKind::DuplicateSentinelOwner,
+                None,
+                Some(sentinel),
+                format!("sentinel at {} is claimed by {}", sentinel, owners.join(", ")),
+            )),
+        }
+    }
+
+    issues
+}
+
+/// Fix what can be fixed without touching the buffer, then report the rest.
+///
+/// Repairs clamp ranges to the buffer, un-invert ranges, trim overlaps,
+/// clamp or drop marks that leave their parent, move images onto the nearest
+/// unowned sentinel and sync `assetRef.targetPos`. Misordered nodes, orphan
+/// sentinels and table sentinel counts are left for the caller.
+pub fn repair(tree: &mut DocumentTree, text: &str) -> ConsistencyReport {
+    let before = check(tree, text);
+    if before.is_empty() {
+        return ConsistencyReport::default();
+    }
+
+    let (buffer_len, sentinels) = scan_buffer(text);
+    let mut leaves = leaves_mut(tree);
+
+    let mut prev_span: Option<TextRange> = None;
+    for leaf in &mut leaves {
+        if let LeafMut::Text { range, .. } | LeafMut::Table { range, .. } = leaf {
+            if range.start > range.end {
+                std::mem::swap(&mut range.start, &mut range.end);
+            }
+            range.end = range.end.min(buffer_len);
+            range.start = range.start.min(range.end);
+            if let Some(prev) = prev_span {
+                // Trim a partial overlap; misordered or contained nodes are left alone.
+                if range.start >= prev.start && range.start < prev.end && range.end >= prev.end {
+                    range.start = prev.end;
+                }
+            }
+        }
+        if let LeafMut::Text { range, marks, .. } = leaf {
+            let len = range.len();
+            for mark in marks.iter_mut() {
+                if mark.start > mark.end {
+                    std::mem::swap(&mut mark.start, &mut mark.end);
+                }
+                mark.end = mark.end.min(len);
+            }
+            marks.retain(|mark| !TextRange::new(mark.start, mark.end).is_empty());
+        }
+        prev_span = Some(match leaf {
+            LeafMut::Text { range, .. } | LeafMut::Table { range, .. } => **range,
+            LeafMut::Image { pos, .. } => TextRange::new(**pos, **pos + 1),
+        });
+    }
+
+    // Images whose sentinel moved: snap to the nearest sentinel no other node
+    // owns, as `check` counts owners.
+    let claimed: Vec<usize> = leaves
+        .iter()
+        .filter_map(|leaf| match leaf {
+            LeafMut::Image { pos, .. } if sentinels.binary_search(pos).is_ok() => Some(**pos),
+            _ => None,
+        })
+        .collect();
+    let mut free: Vec<usize> = sentinels
+        .iter()
+        .copied()
+        .filter(|s| !claimed.contains(s))
+        .filter(|s| {
+            !leaves.iter().any(|leaf| match leaf {
+                LeafMut::Table { range, .. } => *s >= range.start && *s < range.end,
+                LeafMut::Text {
+                    range, inline_objects, ..
+                } => inline_object_positions(**range, *inline_objects).contains(s),
+                LeafMut::Image { .. } => false,
+            })
+        })
+        .collect();
+    for leaf in &mut leaves {
+        if let LeafMut::Image { pos, target_pos, .. } = leaf {
+            if sentinels.binary_search(pos).is_err() {
+                if let Some((idx, _)) = free.iter().enumerate().min_by_key(|(_, s)| s.abs_diff(**pos)) {
+                    **pos = free.remove(idx);
+                }
+            }
+            **target_pos = **pos;
+        }
+    }
+
+    let unresolved = check(tree, text);
+    let repaired = before
+        .into_iter()
+        .filter(|issue| !unresolved.iter().any(|other| issue.same_problem(other)))
+        .collect();
+    ConsistencyReport { repaired, unresolved }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(children: serde_json::Value) -> DocumentTree {
+        serde_json::from_value(serde_json::json!({
+            "version": 2,
+            "root": {"children": [{"id": "s1", "type": "section", "children": children}]}
+        }))
+        .unwrap()
+    }
+
+    fn image(id: &str, pos: usize, target_pos: usize) -> serde_json::Value {
+        serde_json::json!({"id": id, "type": "image", "bufferPosition": pos,
+            "assetRef": {"name": "a.png", "targetPos": target_pos, "alt": "", "size": [1, 1], "bytes": []},
+            "alt": "", "size": [1, 1]})
+    }
+
+    fn kinds(issues: &[ConsistencyIssue]) -> Vec<(IssueKind, Option<&str>)> {
+        issues.iter().map(|issue| (issue.kind, issue.node_id.as_deref())).collect()
+    }
+
+    #[test]
+    fn check_accepts_a_consistent_tree_measured_in_utf16() {
+        // The emoji is two UTF-16 code units, so "b😀c" spans 0..4.
+        let tree = tree(serde_json::json!([
+            {"id": "p1", "type": "paragraph", "textRange": {"start": 0, "end": 4},
+                "marks": [{"start": 1, "end": 3, "attrs": {"b": true}}]},
+            image("i1", 5, 5),
+            {"id": "t1", "type": "table", "textRange": {"start": 7, "end": 9}, "rows": 1, "cols": 1}
+        ]));
+        assert_eq!(check(&tree, "b😀c\n\u{FFFC}\n\u{FFFC}x"), Vec::new());
+    }
+
+    #[test]
+    fn check_reports_every_kind_of_issue() {
+        let tree = tree(serde_json::json!([
+            {"id": "p1", "type": "paragraph", "textRange": {"start": 3, "end": 1}, "marks": []},
+            {"id": "p2", "type": "paragraph", "textRange": {"start": 2, "end": 5},
+                "marks": [{"start": 0, "end": 9}]},
+            {"id": "p3", "type": "paragraph", "textRange": {"start": 0, "end": 1}, "marks": []},
+            image("i1", 7, 6),
+            {"id": "t1", "type": "table", "textRange": {"start": 8, "end": 9}, "rows": 1, "cols": 1},
+            image("i2", 10, 10),
+            {"id": "p4", "type": "paragraph", "textRange": {"start": 10, "end": 40}, "marks": [],
+                "inlineObjects": {"0": {"assetId": "x.png"}}}
+        ]));
+        let text = "abcdef\u{FFFC}xy\u{FFFC}\u{FFFC}";
+        let issues = check(&tree, text);
+        let found = kinds(&issues);
+        for expected in [
+            (IssueKind::InvertedRange, Some("p1")),
+            (IssueKind::Misordered, Some("p2")),
+            (IssueKind::MarkOutOfBounds, Some("p2")),
+            (IssueKind::Misordered, Some("p3")),
+            (IssueKind::MissingSentinel, Some("i1")),
+            (IssueKind::AssetPositionMismatch, Some("i1")),
+            (IssueKind::SentinelCount, Some("t1")),
+            (IssueKind::RangeOutOfBounds, Some("p4")),
+            (IssueKind::Overlap, Some("p4")),
+            (IssueKind::OrphanSentinel, None),
+            (IssueKind::DuplicateSentinelOwner, None),
+        ] {
+            assert!(found.contains(&expected), "{expected:?} missing from {found:?}");
+        }
+    }
+
+    #[test]
+    fn repair_fixes_ranges_and_marks_and_leaves_the_rest() {
+        let mut tree = tree(serde_json::json!([
+            {"id": "p1", "type": "paragraph", "textRange": {"start": 4, "end": 0},
+                "marks": [{"start": 3, "end": 1}, {"start": 2, "end": 9}]},
+            {"id": "p2", "type": "paragraph", "textRange": {"start": 2, "end": 30}, "marks": []},
+            image("i1", 5, 5)
+        ]));
+        let text = "abcd\n\u{FFFC}\n\u{FFFC}";
+        let report = repair(&mut tree, text);
+
+        let repaired = kinds(&report.repaired);
+        for expected in [
+            (IssueKind::InvertedRange, Some("p1")),
+            (IssueKind::MarkOutOfBounds, Some("p1")),
+            // trimmed to start where the repaired p1 ends
+            (IssueKind::Misordered, Some("p2")),
+            (IssueKind::RangeOutOfBounds, Some("p2")),
+        ] {
+            assert!(repaired.contains(&expected), "{expected:?} missing from {repaired:?}");
+        }
+        // p2 now covers the image and the last sentinel; that needs the caller
+        assert!(!report.unresolved.is_empty());
+        assert_eq!(report.unresolved, check(&tree, text));
+
+        let spans: Vec<_> = tree.blocks().filter_map(BlockNode::span).collect();
+        assert_eq!(spans[0], TextRange::new(0, 4));
+        assert_eq!(spans[1], TextRange::new(4, 8));
+        let marks = match tree.blocks().next() {
+            Some(BlockNode::Paragraph(p)) => p.marks.iter().map(|m| (m.start, m.end)).collect::<Vec<_>>(),
+            _ => Vec::new(),
+        };
+        assert_eq!(marks, vec![(1, 3), (2, 4)]);
+    }
+
+    #[test]
+    fn repair_leaves_a_consistent_tree_alone() {
+        let mut tree = tree(serde_json::json!([
+            {"id": "p1", "type": "paragraph", "textRange": {"start": 0, "end": 2}, "marks": []},
+            image("i1", 3, 3)
+        ]));
+        let before = tree.clone();
+        let report = repair(&mut tree, "ab\n\u{FFFC}");
+        assert!(report.repaired.is_empty() && report.unresolved.is_empty());
+        assert_eq!(tree, before);
+    }
+
+    #[test]
+    fn repair_does_not_snap_images_onto_inline_objects() {
+        let mut tree: DocumentTree = serde_json::from_value(serde_json::json!({
+            "version": 2,
+            "root": {"children": [{"id": "s1", "type": "section", "children": [
+                {"id": "p1", "type": "paragraph", "textRange": {"start": 0, "end": 5}, "marks": [],
+                    "inlineObjects": {"2": {"assetId": "inline.png"}}},
+                {"id": "i1", "type": "image", "bufferPosition": 3,
+                    "assetRef": {"name": "a.png", "targetPos": 3, "alt": "", "size": [1, 1], "bytes": []},
+                    "alt": "", "size": [1, 1]}
+            ]}]}
+        }))
+        .unwrap();
+        let text = "ab\u{FFFC}cd\n\u{FFFC}";
+
+        let report = repair(&mut tree, text);
+        assert!(report.unresolved.is_empty(), "{:?}", report.unresolved);
+        let positions: Vec<_> = tree
+            .blocks()
+            .filter_map(|block| match block {
+                BlockNode::Image(image) => Some((image.buffer_position, image.asset_ref.target_pos)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(positions, vec![(6, 6)]);
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::storage::zip_container::AssetRef;
+
+/// Placeholder character for non-text objects (images, tables) in the buffer.
+pub const OBJECT_REPLACEMENT_CHAR: char = '\u{FFFC}';
+
+/// Stable node identifier (UUID v4).
+pub type NodeId = String;
+
+/// Logical layer of a document (`documentTree.json`).
+/// Mirrors the TypeScript schema in `app/lib/doc/schema.ts`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DocumentTree {
+    /// Tree schema version (currently 2)
+    pub version: u32,
+    pub root: RootNode,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RootNode {
+    pub children: Vec<SectionNode>,
+}
+
+/// Absolute range into the text buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TextRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl TextRange {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    pub fn len(&self) -> usize {
+        self.end.saturating_sub(self.start)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Inline formatting span, relative to the start of its parent block.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InlineMark {
+    pub start: usize,
+    pub end: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attrs: Option<MarkAttrs>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MarkAttrs {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub b: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub i: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub font: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub font_size: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InlineObjectRef {
+    pub asset_id: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParagraphNode {
+    pub id: NodeId,
+    pub text_range: TextRange,
+    #[serde(default)]
+    pub marks: Vec<InlineMark>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inline_objects: Option<BTreeMap<String, InlineObjectRef>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HeadingNode {
+    pub id: NodeId,
+    /// Heading level (1-3)
+    pub level: u8,
+    pub text_range: TextRange,
+    #[serde(default)]
+    pub marks: Vec<InlineMark>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageNode {
+    pub id: NodeId,
+    /// Absolute position of the U+FFFC sentinel for this image
+    pub buffer_position: usize,
+    pub asset_ref: AssetRef,
+    pub alt: String,
+    pub size: (u32, u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "listItem", rename_all = "camelCase")]
+pub struct ListItemNode {
+    pub id: NodeId,
+    pub content: TextBlock,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListType {
+    Ordered,
+    Unordered,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListNode {
+    pub id: NodeId,
+    pub list_type: ListType,
+    pub items: Vec<ListItemNode>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockquoteNode {
+    pub id: NodeId,
+    pub children: Vec<TextBlock>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TableNode {
+    pub id: NodeId,
+    pub text_range: TextRange,
+    pub rows: u32,
+    pub cols: u32,
+}
+
+/// Text-bearing block allowed inside list items and blockquotes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum TextBlock {
+    Paragraph(ParagraphNode),
+    Heading(HeadingNode),
+}
+
+/// Content block inside a section.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum BlockNode {
+    Paragraph(ParagraphNode),
+    Heading(HeadingNode),
+    Image(ImageNode),
+    Blockquote(BlockquoteNode),
+    List(ListNode),
+    Table(TableNode),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SectionMargins {
+    pub top: f64,
+    pub right: f64,
+    pub bottom: f64,
+    pub left: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Orientation {
+    Portrait,
+    Landscape,
+}
+
+/// Layout container; sections are flat, non-nesting siblings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "section", rename_all = "camelCase")]
+pub struct SectionNode {
+    pub id: NodeId,
+    pub children: Vec<BlockNode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub margins: Option<SectionMargins>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub orientation: Option<Orientation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub headers: Option<BTreeMap<String, String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub footers: Option<BTreeMap<String, String>>,
+}
+
+impl Default for DocumentTree {
+    fn default() -> Self {
+        Self {
+            version: 2,
+            root: RootNode::default(),
+        }
+    }
+}
+
+impl DocumentTree {
+    /// Iterate all top-level blocks in document order.
+    pub fn blocks(&self) -> impl Iterator<Item = &BlockNode> {
+        self.root.children.iter().flat_map(|section| section.children.iter())
+    }
+}
+
+impl TextBlock {
+    pub fn id(&self) -> &str {
+        match self {
+            Self::Paragraph(p) => &p.id,
+            Self::Heading(h) => &h.id,
+        }
+    }
+
+    pub fn text_range(&self) -> TextRange {
+        match self {
+            Self::Paragraph(p) => p.text_range,
+            Self::Heading(h) => h.text_range,
+        }
+    }
+}
+
+impl BlockNode {
+    pub fn id(&self) -> &str {
+        match self {
+            Self::Paragraph(p) => &p.id,
+            Self::Heading(h) => &h.id,
+            Self::Image(i) => &i.id,
+            Self::Blockquote(q) => &q.id,
+            Self::List(l) => &l.id,
+            Self::Table(t) => &t.id,
+        }
+    }
+
+    /// Buffer span covered by this block, mirroring `getBlockSpan` in `treeUtils.ts`.
+    /// Containers span from their first to their last child; empty containers have no span.
+    pub fn span(&self) -> Option<TextRange> {
+        let children = match self {
+            Self::Paragraph(p) => return Some(p.text_range),
+            Self::Heading(h) => return Some(h.text_range),
+            Self::Table(t) => return Some(t.text_range),
+            Self::Image(i) => return Some(TextRange::new(i.buffer_position, i.buffer_position + 1)),
+            Self::Blockquote(q) => q.children.iter().map(TextBlock::text_range).collect::<Vec<_>>(),
+            Self::List(l) => l.items.iter().map(|item| item.content.text_range()).collect(),
+        };
+        match (children.first(), children.last()) {
+            (Some(first), Some(last)) => Some(TextRange::new(first.start, last.end)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_json() -> serde_json::Value {
+        serde_json::json!({
+            "version": 2,
+            "root": {"children": [{
+                "id": "s1",
+                "type": "section",
+                "margins": {"top": 72.5, "right": 54.5, "bottom": 72.5, "left": 54.5},
+                "orientation": "portrait",
+                "children": [
+                    {"id": "h1", "type": "heading", "level": 1, "textRange": {"start": 0, "end": 5}, "marks": []},
+                    {"id": "p1", "type": "paragraph", "textRange": {"start": 6, "end": 18},
+                        "marks": [{"start": 0, "end": 4, "attrs": {"b": true, "fontSize": 12.5}}],
+                        "inlineObjects": {"11": {"assetId": "b.png"}}},
+                    {"id": "i1", "type": "image", "bufferPosition": 19,
+                        "assetRef": {"name": "a.png", "targetPos": 19, "alt": "x", "size": [10, 20], "bytes": []},
+                        "alt": "x", "size": [10, 20]},
+                    {"id": "l1", "type": "list", "listType": "ordered", "items": [
+                        {"id": "li1", "type": "listItem", "content": {"id": "p2", "type": "paragraph", "textRange": {"start": 21, "end": 24}, "marks": []}}
+                    ]},
+                    {"id": "q1", "type": "blockquote", "children": [
+                        {"id": "p3", "type": "paragraph", "textRange": {"start": 25, "end": 28}, "marks": []}
+                    ]},
+                    {"id": "t1", "type": "table", "textRange": {"start": 29, "end": 30}, "rows": 2, "cols": 2}
+                ]
+            }]}
+        })
+    }
+
+    #[test]
+    fn document_tree_json_round_trips() {
+        let json = sample_json();
+        let tree: DocumentTree = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&tree).unwrap(), json);
+
+        let bytes = serde_json::to_vec(&tree).unwrap();
+        let back: DocumentTree = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(back, tree);
+    }
+
+    #[test]
+    fn block_spans_cover_their_children() {
+        let tree: DocumentTree = serde_json::from_value(sample_json()).unwrap();
+        let spans: Vec<_> = tree.blocks().map(|block| (block.id(), block.span())).collect();
+        assert_eq!(spans[2], ("i1", Some(TextRange::new(19, 20))));
+        assert_eq!(spans[3], ("l1", Some(TextRange::new(21, 24))));
+        assert_eq!(spans[4], ("q1", Some(TextRange::new(25, 28))));
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+use crate::storage::zip_container::MetadataRange;
+
+type Attrs = BTreeMap<String, Value>;
+
+/// Ranges sharing a `type` and `level` form one layer; layers may overlap
+/// each other, ranges inside a layer may not after normalization.
+type LayerKey = (Option<String>, Option<u8>);
+
+/// Normalize metadata ranges into a compact, canonical form.
+///
+/// Within each layer, overlapping ranges are split into disjoint segments
+/// whose attributes are resolved in list order (later ranges win, a `null`
+/// value unsets the key), and adjacent segments with identical attributes are
+/// merged. Untyped segments that end up with no attributes are dropped; empty
+/// typed ranges are kept as point anchors. The result is sorted by position.
+pub fn normalize_ranges(ranges: &mut Vec<MetadataRange>) {
+    let mut layers: BTreeMap<LayerKey, Vec<&MetadataRange>> = BTreeMap::new();
+    let mut anchors = Vec::new();
+    for range in ranges.iter() {
+        if range.start >= range.end {
+            if range.r#type.is_some() {
+                anchors.push(range.clone());
+            }
+            continue;
+        }
+        layers
+            .entry((range.r#type.clone(), range.level))
+            .or_default()
+            .push(range);
+    }
+
+    let mut normalized = anchors;
+    for ((kind, level), members) in layers {
+        for (start, end, attrs) in segments(&members) {
+            if kind.is_none() && attrs.is_empty() {
+                continue;
+            }
+            normalized.push(MetadataRange {
+                start,
+                end,
+                attrs,
+                r#type: kind.clone(),
+                level,
+            });
+        }
+    }
+    normalized.sort_by(|a, b| {
+        (a.start, a.end, &a.r#type, a.level).cmp(&(b.start, b.end, &b.r#type, b.level))
+    });
+    *ranges = normalized;
+}
+
+/// Disjoint, sorted attribute segments for answering point queries in O(log n).
+#[derive(Debug, Clone, Default)]
+pub struct FormattingIndex {
+    segments: Vec<(usize, usize, Attrs)>,
+}
+
+impl FormattingIndex {
+    /// Build an index over all ranges. Attributes from every layer are
+    /// folded together in list order.
+    pub fn new(ranges: &[MetadataRange]) -> Self {
+        let members: Vec<&MetadataRange> = ranges.iter().filter(|r| r.start < r.end).collect();
+        let segments = segments(&members)
+            .into_iter()
+            .filter(|(_, _, attrs)| !attrs.is_empty())
+            .collect();
+        Self { segments }
+    }
+
+    /// Attributes applying to the code unit at `offset`.
+    pub fn attrs_at(&self, offset: usize) -> Option<&Attrs> {
+        let idx = self.segments.partition_point(|(start, _, _)| *start <= offset);
+        let (_, end, attrs) = self.segments.get(idx.checked_sub(1)?)?;
+        (offset < *end).then_some(attrs)
+    }
+}
+
+/// Sweep over range boundaries, resolving the attributes of each elementary
+/// interval and merging neighbours that resolve identically.
+fn segments(ranges: &[&MetadataRange]) -> Vec<(usize, usize, Attrs)> {
+    let mut events: Vec<(usize, bool, usize)> = Vec::with_capacity(ranges.len() * 2);
+    for (idx, range) in ranges.iter().enumerate() {
+        events.push((range.start, true, idx));
+        events.push((range.end, false, idx));
+    }
+    events.sort_unstable_by_key(|(pos, _, _)| *pos);
+
+    let mut active: BTreeMap<usize, &Attrs> = BTreeMap::new();
+    let mut out: Vec<(usize, usize, Attrs)> = Vec::new();
+    let mut cursor = 0;
+    let mut events = events.into_iter().peekable();
+    while let Some(&(pos, _, _)) = events.peek() {
+        if !active.is_empty() && pos > cursor {
+            let attrs = resolve(active.values().copied());
+            match out.last_mut() {
+                Some((_, end, last)) if *end == cursor && *last == attrs => *end = pos,
+                _ => out.push((cursor, pos, attrs)),
+            }
+        }
+        while let Some((_, opens, idx)) = events.next_if(|(at, _, _)| *at == pos) {
+            if opens {
+                active.insert(idx, &ranges[idx].attrs);
+            } else {
+                active.remove(&idx);
+            }
+        }
+        cursor = pos;
+    }
+    out
+}
+
+fn resolve<'a>(layers: impl Iterator<Item = &'a Attrs>) -> Attrs {
+    let mut resolved = Attrs::new();
+    for attrs in layers {
+        for (key, value) in attrs {
+            if value.is_null() {
+                resolved.remove(key);
+            } else {
+                resolved.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn range(start: usize, end: usize, attrs: Value, kind: Option<&str>) -> MetadataRange {
+        MetadataRange {
+            start,
+            end,
+            attrs: serde_json::from_value(attrs).unwrap(),
+            r#type: kind.map(str::to_string),
+            level: None,
+        }
+    }
+
+    fn spans(ranges: &[MetadataRange]) -> Vec<(usize, usize, Value)> {
+        ranges
+            .iter()
+            .map(|r| (r.start, r.end, serde_json::to_value(&r.attrs).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn overlapping_ranges_split_into_segments() {
+        let mut ranges = vec![
+            range(0, 6, json!({"bold": true}), None),
+            range(3, 9, json!({"italic": true}), None),
+        ];
+        normalize_ranges(&mut ranges);
+        assert_eq!(
+            spans(&ranges),
+            vec![
+                (0, 3, json!({"bold": true})),
+                (3, 6, json!({"bold": true, "italic": true})),
+                (6, 9, json!({"italic": true})),
+            ]
+        );
+    }
+
+    #[test]
+    fn null_unsets_earlier_values() {
+        let mut ranges = vec![
+            range(0, 6, json!({"bold": true}), None),
+            range(2, 4, json!({"bold": null}), None),
+        ];
+        normalize_ranges(&mut ranges);
+        assert_eq!(spans(&ranges), vec![(0, 2, json!({"bold": true})), (4, 6, json!({"bold": true}))]);
+    }
+
+    #[test]
+    fn adjacent_equal_segments_merge() {
+        let mut ranges = vec![
+            range(3, 6, json!({"bold": true}), None),
+            range(0, 3, json!({"bold": true}), None),
+            range(2, 5, json!({"bold": true}), None),
+        ];
+        normalize_ranges(&mut ranges);
+        assert_eq!(spans(&ranges), vec![(0, 6, json!({"bold": true}))]);
+    }
+
+    #[test]
+    fn layers_overlap_without_splitting_each_other() {
+        let mut ranges = vec![
+            range(2, 4, json!({"bold": true}), None),
+            range(0, 5, json!({}), Some("heading")),
+            range(7, 7, json!({}), Some("comment")),
+            range(8, 8, json!({"bold": true}), None),
+        ];
+        normalize_ranges(&mut ranges);
+        let kinds: Vec<_> = ranges.iter().map(|r| (r.start, r.end, r.r#type.as_deref())).collect();
+        assert_eq!(kinds, vec![(0, 5, Some("heading")), (2, 4, None), (7, 7, Some("comment"))]);
+    }
+
+    #[test]
+    fn attrs_at_respects_segment_boundaries() {
+        let index = FormattingIndex::new(&[
+            range(0, 3, json!({"bold": true}), None),
+            range(3, 6, json!({"italic": true}), None),
+            range(8, 10, json!({"underline": true}), None),
+        ]);
+        let at = |offset| index.attrs_at(offset).map(|attrs| serde_json::to_value(attrs).unwrap());
+        assert_eq!(at(0), Some(json!({"bold": true})));
+        assert_eq!(at(2), Some(json!({"bold": true})));
+        assert_eq!(at(3), Some(json!({"italic": true})));
+        assert_eq!(at(5), Some(json!({"italic": true})));
+        assert_eq!(at(6), None);
+        assert_eq!(at(7), None);
+        assert_eq!(at(8), Some(json!({"underline": true})));
+        assert_eq!(at(10), None);
+    }
+
+    #[test]
+    fn attrs_at_folds_every_layer() {
+        let index = FormattingIndex::new(&[
+            range(0, 10, json!({"size": 2}), Some("heading")),
+            range(2, 4, json!({"bold": true, "size": null}), None),
+        ]);
+        let at = |offset| index.attrs_at(offset).map(|attrs| serde_json::to_value(attrs).unwrap());
+        assert_eq!(at(1), Some(json!({"size": 2})));
+        assert_eq!(at(2), Some(json!({"bold": true})));
+        assert_eq!(at(4), Some(json!({"size": 2})));
+    }
+}
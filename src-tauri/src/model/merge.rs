@@ -0,0 +1,544 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use similar::{DiffOp, TextDiff};
+
+use super::formatting::normalize_ranges;
+use super::piece_table::utf16_len;
+use crate::storage::zip_container::MetadataRange;
+
+/// One side of a conflicting region.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConflictSide {
+    /// First line of the region in that input (1-indexed)
+    pub start_line: usize,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum MergeConflict {
+    /// Both sides changed the same lines differently
+    Text {
+        id: usize,
+        base: ConflictSide,
+        ours: ConflictSide,
+        theirs: ConflictSide,
+    },
+    /// Both sides set the same attribute to different values on overlapping
+    /// text. Offsets are in the merged content.
+    Formatting {
+        start: usize,
+        end: usize,
+        key: String,
+        ours: Value,
+        theirs: Value,
+    },
+}
+
+/// How to resolve a text conflict.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "choice", rename_all = "camelCase")]
+pub enum Resolution {
+    Ours,
+    Theirs,
+    Base,
+    /// Our lines followed by theirs
+    Both,
+    Custom { text: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MergeSide {
+    Ours,
+    Theirs,
+}
+
+/// Where a merged line came from, as line indices into each input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LineOrigin {
+    pub base: Option<usize>,
+    pub ours: Option<usize>,
+    pub theirs: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MergedLine {
+    pub text: String,
+    pub origin: LineOrigin,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TextMerge {
+    pub lines: Vec<MergedLine>,
+    /// All text conflicts, resolved or not
+    pub conflicts: Vec<MergeConflict>,
+    /// Whether any conflict is still without a resolution
+    pub unresolved: bool,
+}
+
+impl TextMerge {
+    pub fn text(&self) -> String {
+        self.lines.iter().map(|line| line.text.as_str()).collect()
+    }
+}
+
+/// A changed region of the base, and the lines replacing it on one side.
+#[derive(Debug, Clone, Copy)]
+struct Hunk {
+    ours: bool,
+    base: (usize, usize),
+    side: (usize, usize),
+}
+
+/// One input of the merge, split into lines.
+struct Input<'a> {
+    lines: Vec<&'a str>,
+    /// base line -> line in this input, where unchanged
+    from_base: Vec<Option<usize>>,
+    hunks: Vec<Hunk>,
+}
+
+impl<'a> Input<'a> {
+    fn new(base: &'a str, side: &'a str, ours: bool) -> (Vec<&'a str>, Self) {
+        let diff = TextDiff::from_lines(base, side);
+        let base_lines = diff.old_slices().to_vec();
+        let lines = diff.new_slices().to_vec();
+        let mut from_base = vec![None; base_lines.len()];
+        let mut hunks: Vec<Hunk> = Vec::new();
+        for op in diff.ops() {
+            let (old, new) = (op.old_range(), op.new_range());
+            if let DiffOp::Equal { .. } = op {
+                for (b, s) in old.zip(new) {
+                    from_base[b] = Some(s);
+                }
+                continue;
+            }
+            match hunks.last_mut() {
+                Some(last) if last.base.1 == old.start && last.side.1 == new.start => {
+                    last.base.1 = old.end;
+                    last.side.1 = new.end;
+                }
+                _ => hunks.push(Hunk {
+                    ours,
+                    base: (old.start, old.end),
+                    side: (new.start, new.end),
+                }),
+            }
+        }
+        (
+            base_lines,
+            Self {
+                lines,
+                from_base,
+                hunks,
+            },
+        )
+    }
+}
+
+/// Line-based three-way merge. Regions changed on one side only are taken
+/// from that side; regions both sides changed identically are taken once;
+/// anything else is a conflict, settled by `resolutions` (keyed by conflict id)
+/// when one is given.
+pub fn merge_text(base: &str, ours: &str, theirs: &str, resolutions: &BTreeMap<usize, Resolution>) -> TextMerge {
+    let (base_lines, ours_in) = Input::new(base, ours, true);
+    let (_, theirs_in) = Input::new(base, theirs, false);
+
+    let base_origin = |b: usize| LineOrigin {
+        base: Some(b),
+        ours: ours_in.from_base[b],
+        theirs: theirs_in.from_base[b],
+    };
+    let side_lines = |input: &Input, hunks: &[Hunk], lo: usize, hi: usize| -> Vec<MergedLine> {
+        let mut out = Vec::new();
+        let mut pos = lo;
+        for hunk in hunks {
+            out.extend((pos..hunk.base.0).map(|b| MergedLine {
+                text: base_lines[b].to_string(),
+                origin: base_origin(b),
+            }));
+            out.extend((hunk.side.0..hunk.side.1).map(|s| MergedLine {
+                text: input.lines[s].to_string(),
+                origin: match hunk.ours {
+                    true => LineOrigin {
+                        ours: Some(s),
+                        ..Default::default()
+                    },
+                    false => LineOrigin {
+                        theirs: Some(s),
+                        ..Default::default()
+                    },
+                },
+            }));
+            pos = hunk.base.1;
+        }
+        out.extend((pos..hi).map(|b| MergedLine {
+            text: base_lines[b].to_string(),
+            origin: base_origin(b),
+        }));
+        out
+    };
+    let joined = |lines: &[MergedLine]| lines.iter().map(|line| line.text.as_str()).collect::<String>();
+
+    let mut hunks: Vec<Hunk> = ours_in.hunks.iter().chain(&theirs_in.hunks).copied().collect();
+    hunks.sort_by_key(|hunk| (hunk.base.0, hunk.base.1));
+
+    let mut merge = TextMerge::default();
+    // Line offset between base and each side before the current position.
+    let (mut ours_delta, mut theirs_delta) = (0isize, 0isize);
+    let mut pos = 0;
+    let mut idx = 0;
+    while idx < hunks.len() {
+        let (lo, mut hi) = hunks[idx].base;
+        let mut end = idx + 1;
+        while end < hunks.len() && hunks[end].base.0 <= hi {
+            hi = hi.max(hunks[end].base.1);
+            end += 1;
+        }
+        let region = &hunks[idx..end];
+        let ours_hunks: Vec<Hunk> = region.iter().filter(|h| h.ours).copied().collect();
+        let theirs_hunks: Vec<Hunk> = region.iter().filter(|h| !h.ours).copied().collect();
+
+        merge.lines.extend((pos..lo).map(|b| MergedLine {
+            text: base_lines[b].to_string(),
+            origin: base_origin(b),
+        }));
+
+        let ours_lines = side_lines(&ours_in, &ours_hunks, lo, hi);
+        let theirs_lines = side_lines(&theirs_in, &theirs_hunks, lo, hi);
+        if theirs_hunks.is_empty() {
+            merge.lines.extend(ours_lines);
+        } else if ours_hunks.is_empty() {
+            merge.lines.extend(theirs_lines);
+        } else if joined(&ours_lines) == joined(&theirs_lines) {
+            merge.lines.extend(ours_lines.into_iter().zip(theirs_lines).map(|(ours, theirs)| MergedLine {
+                text: ours.text,
+                origin: LineOrigin {
+                    base: ours.origin.base.or(theirs.origin.base),
+                    ours: ours.origin.ours,
+                    theirs: theirs.origin.theirs,
+                },
+            }));
+        } else {
+            let id = merge.conflicts.len();
+            merge.conflicts.push(MergeConflict::Text {
+                id,
+                base: ConflictSide {
+                    start_line: lo + 1,
+                    text: base_lines[lo..hi].concat(),
+                },
+                ours: ConflictSide {
+                    start_line: (lo as isize + ours_delta) as usize + 1,
+                    text: joined(&ours_lines),
+                },
+                theirs: ConflictSide {
+                    start_line: (lo as isize + theirs_delta) as usize + 1,
+                    text: joined(&theirs_lines),
+                },
+            });
+            match resolutions.get(&id) {
+                Some(Resolution::Ours) => merge.lines.extend(ours_lines),
+                Some(Resolution::Theirs) => merge.lines.extend(theirs_lines),
+                Some(Resolution::Both) => merge.lines.extend(ours_lines.into_iter().chain(theirs_lines)),
+                Some(Resolution::Base) => merge.lines.extend((lo..hi).map(|b| MergedLine {
+                    text: base_lines[b].to_string(),
+                    origin: base_origin(b),
+                })),
+                Some(Resolution::Custom { text }) => merge.lines.push(MergedLine {
+                    text: text.clone(),
+                    origin: LineOrigin::default(),
+                }),
+                None => {
+                    merge.unresolved = true;
+                    merge.lines.extend(ours_lines);
+                }
+            }
+        }
+
+        for hunk in region {
+            let change = (hunk.side.1 - hunk.side.0) as isize - (hunk.base.1 - hunk.base.0) as isize;
+            if hunk.ours {
+                ours_delta += change;
+            } else {
+                theirs_delta += change;
+            }
+        }
+        pos = hi;
+        idx = end;
+    }
+    merge.lines.extend((pos..base_lines.len()).map(|b| MergedLine {
+        text: base_lines[b].to_string(),
+        origin: base_origin(b),
+    }));
+    merge
+}
+
+/// Three-way merge of formatting ranges onto merged text.
+///
+/// Each side's ranges are mapped into the merged content line by line (text
+/// that did not survive the merge loses its formatting). A range is kept if
+/// both sides have it or one side added it; a range either side removed is
+/// dropped. Where both sides added different values for the same attribute
+/// on overlapping text, a formatting conflict is reported; `prefer` decides
+/// which side wins. The result is normalized.
+pub fn merge_ranges(
+    inputs: [(&str, &[MetadataRange]); 3],
+    merge: &TextMerge,
+    prefer: Option<MergeSide>,
+) -> (Vec<MetadataRange>, Vec<MergeConflict>) {
+    let merged_starts = line_starts(merge.lines.iter().map(|line| line.text.as_str()));
+    let [base, ours, theirs] = [0, 1, 2].map(|which| {
+        let (text, ranges) = inputs[which];
+        let lines: Vec<&str> = TextDiff::from_lines(text, "").old_slices().to_vec();
+        let mut to_merged = vec![None; lines.len()];
+        for (k, line) in merge.lines.iter().enumerate() {
+            let source = match which {
+                0 => line.origin.base,
+                1 => line.origin.ours,
+                _ => line.origin.theirs,
+            };
+            if let Some(j) = source.filter(|j| *j < to_merged.len()) {
+                to_merged[j] = Some(k);
+            }
+        }
+        let starts = line_starts(lines.iter().copied());
+        ranges
+            .iter()
+            .flat_map(|range| map_range(range, &starts, &to_merged, &merged_starts))
+            .collect::<Vec<_>>()
+    });
+
+    let key = |range: &MetadataRange| serde_json::to_string(range).unwrap_or_default();
+    let base_keys: BTreeSet<String> = base.iter().map(key).collect();
+    let ours_keys: BTreeSet<String> = ours.iter().map(key).collect();
+    let theirs_keys: BTreeSet<String> = theirs.iter().map(key).collect();
+
+    let kept_ours: Vec<MetadataRange> = ours
+        .iter()
+        .filter(|r| theirs_keys.contains(&key(r)) || !base_keys.contains(&key(r)))
+        .cloned()
+        .collect();
+    let kept_theirs: Vec<MetadataRange> = theirs
+        .iter()
+        .filter(|r| !ours_keys.contains(&key(r)) && !base_keys.contains(&key(r)))
+        .cloned()
+        .collect();
+
+    let mut conflicts = Vec::new();
+    for a in kept_ours.iter().filter(|r| !base_keys.contains(&key(r))) {
+        for b in &kept_theirs {
+            let (start, end) = (a.start.max(b.start), a.end.min(b.end));
+            if start >= end || a.r#type != b.r#type || a.level != b.level {
+                continue;
+            }
+            for (attr, ours_value) in &a.attrs {
+                match b.attrs.get(attr) {
+                    Some(theirs_value) if theirs_value != ours_value => conflicts.push(MergeConflict::Formatting {
+                        start,
+                        end,
+                        key: attr.clone(),
+                        ours: ours_value.clone(),
+                        theirs: theirs_value.clone(),
+                    }),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // Later ranges win during normalization, so the preferred side goes last.
+    let mut ranges = match prefer {
+        Some(MergeSide::Ours) => [kept_theirs, kept_ours].concat(),
+        _ => [kept_ours, kept_theirs].concat(),
+    };
+    normalize_ranges(&mut ranges);
+    (ranges, conflicts)
+}
+
+/// UTF-16 start offset of each line, plus the total length.
+fn line_starts<'a>(lines: impl Iterator<Item = &'a str>) -> Vec<usize> {
+    let mut starts = vec![0];
+    for line in lines {
+        starts.push(starts.last().copied().unwrap_or(0) + utf16_len(line));
+    }
+    starts
+}
+
+/// Map a range through the line correspondence into merged offsets. Parts
+/// on lines that did not survive are dropped; contiguous parts are joined.
+fn map_range(
+    range: &MetadataRange,
+    starts: &[usize],
+    to_merged: &[Option<usize>],
+    merged_starts: &[usize],
+) -> Vec<MetadataRange> {
+    let mut pieces: Vec<(usize, usize)> = Vec::new();
+    for (line, merged) in to_merged.iter().enumerate() {
+        let (line_start, line_end) = (starts[line], starts[line + 1]);
+        let (start, end) = (range.start.max(line_start), range.end.min(line_end));
+        let Some(k) = merged else { continue };
+        if start >= end {
+            continue;
+        }
+        let mapped = (
+            merged_starts[*k] + start - line_start,
+            merged_starts[*k] + end - line_start,
+        );
+        match pieces.last_mut() {
+            Some(last) if last.1 == mapped.0 => last.1 = mapped.1,
+            _ => pieces.push(mapped),
+        }
+    }
+    pieces
+        .into_iter()
+        .map(|(start, end)| MetadataRange {
+            start,
+            end,
+            ..range.clone()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn merge(base: &str, ours: &str, theirs: &str) -> TextMerge {
+        merge_text(base, ours, theirs, &BTreeMap::new())
+    }
+
+    fn range(start: usize, end: usize, attrs: Value) -> MetadataRange {
+        MetadataRange {
+            start,
+            end,
+            attrs: serde_json::from_value(attrs).unwrap(),
+            r#type: None,
+            level: None,
+        }
+    }
+
+    fn conflict_lines(merge: &TextMerge) -> Vec<(usize, usize, usize)> {
+        merge
+            .conflicts
+            .iter()
+            .map(|conflict| match conflict {
+                MergeConflict::Text { base, ours, theirs, .. } => (base.start_line, ours.start_line, theirs.start_line),
+                other => panic!("unexpected {other:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn takes_changes_from_either_side() {
+        let merged = merge("a\nb\nc\nd\n", "A\nb\nc\nd\n", "a\nb\nc\nD\n");
+        assert!(merged.conflicts.is_empty());
+        assert!(!merged.unresolved);
+        assert_eq!(merged.text(), "A\nb\nc\nD\n");
+    }
+
+    #[test]
+    fn takes_identical_changes_once() {
+        let merged = merge("a\nb\nc\n", "a\nB\nnew\nc\n", "a\nB\nnew\nc\n");
+        assert!(merged.conflicts.is_empty());
+        assert_eq!(merged.text(), "a\nB\nnew\nc\n");
+        let origin = merged.lines[1].origin;
+        assert_eq!((origin.ours, origin.theirs), (Some(1), Some(1)));
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_changes_conflict() {
+        let overlapping = merge("a\nb\nc\n", "a\nO\nc\n", "a\nT\nc\n");
+        assert_eq!(conflict_lines(&overlapping), vec![(2, 2, 2)]);
+        assert!(overlapping.unresolved);
+        // Unresolved regions keep our lines.
+        assert_eq!(overlapping.text(), "a\nO\nc\n");
+
+        let adjacent = merge("a\nb\nc\n", "A\nb\nc\n", "a\nB\nc\n");
+        assert_eq!(conflict_lines(&adjacent), vec![(1, 1, 1)]);
+        let MergeConflict::Text { base, .. } = &adjacent.conflicts[0] else {
+            unreachable!();
+        };
+        assert_eq!(base.text, "a\nb\n");
+    }
+
+    #[test]
+    fn conflict_lines_account_for_earlier_changes() {
+        let merged = merge("a\nb\nc\nd\n", "x\ny\na\nb\nO\nd\n", "a\nb\nT\nd\n");
+        assert_eq!(conflict_lines(&merged), vec![(3, 5, 3)]);
+    }
+
+    #[test]
+    fn resolutions_pick_the_merged_lines() {
+        let cases = [
+            (Resolution::Ours, "a\nO\nc\n"),
+            (Resolution::Theirs, "a\nT\nc\n"),
+            (Resolution::Base, "a\nb\nc\n"),
+            (Resolution::Both, "a\nO\nT\nc\n"),
+            (
+                Resolution::Custom {
+                    text: "X\n".to_string(),
+                },
+                "a\nX\nc\n",
+            ),
+        ];
+        for (resolution, expected) in cases {
+            let resolutions = BTreeMap::from([(0, resolution.clone())]);
+            let merged = merge_text("a\nb\nc\n", "a\nO\nc\n", "a\nT\nc\n", &resolutions);
+            assert!(!merged.unresolved, "{resolution:?}");
+            assert_eq!(merged.conflicts.len(), 1);
+            assert_eq!(merged.text(), expected, "{resolution:?}");
+        }
+    }
+
+    #[test]
+    fn ranges_follow_lines_across_changes() {
+        let (base, ours, theirs) = ("aa\nbb\ncc\n", "aa\nBB\ncc\n", "aa\nbb\ncc\ndd\n");
+        let merged = merge(base, ours, theirs);
+        assert_eq!(merged.text(), "aa\nBB\ncc\ndd\n");
+
+        let bold = vec![range(0, 8, json!({"b": true}))];
+        let (ranges, conflicts) = merge_ranges(
+            [(base, bold.as_slice()), (ours, bold.as_slice()), (theirs, bold.as_slice())],
+            &merged,
+            None,
+        );
+        assert!(conflicts.is_empty());
+        assert_eq!(ranges.len(), 1);
+        assert_eq!((ranges[0].start, ranges[0].end), (0, 8));
+    }
+
+    #[test]
+    fn ranges_on_dropped_lines_are_removed() {
+        let (base, ours, theirs) = ("aa\nbb\n", "aa\n", "aa\nbb\n");
+        let merged = merge(base, ours, theirs);
+        let italic = vec![range(3, 5, json!({"i": true}))];
+        let (ranges, _) = merge_ranges([(base, &italic), (ours, &[]), (theirs, &italic)], &merged, None);
+        assert!(ranges.is_empty());
+    }
+
+    #[test]
+    fn conflicting_formatting_is_reported_and_settled_by_preference() {
+        let text = "aa\n";
+        let merged = merge(text, text, text);
+        let ours = vec![range(0, 2, json!({"color": "red"}))];
+        let theirs = vec![range(0, 2, json!({"color": "blue"}))];
+        let inputs = [(text, &[][..]), (text, ours.as_slice()), (text, theirs.as_slice())];
+
+        let (_, conflicts) = merge_ranges(inputs, &merged, None);
+        assert!(matches!(
+            conflicts.as_slice(),
+            [MergeConflict::Formatting { start: 0, end: 2, key, .. }] if key == "color"
+        ));
+        for (prefer, color) in [(MergeSide::Ours, "red"), (MergeSide::Theirs, "blue")] {
+            let (ranges, _) = merge_ranges(inputs, &merged, Some(prefer));
+            let colors: Vec<&Value> = ranges.iter().filter_map(|range| range.attrs.get("color")).collect();
+            assert_eq!(colors, vec![&json!(color)], "{prefer:?}");
+        }
+    }
+}
@@ -1,2 +1,13 @@
+pub mod blame;
+pub mod consistency;
+pub mod document_tree;
+pub mod formatting;
+pub mod merge;
+pub mod operation;
+pub mod partial_restore;
 pub mod piece_table;
+pub mod rebase;
+pub mod retention;
+pub mod stats;
+pub mod tree_diff;
 pub mod version;
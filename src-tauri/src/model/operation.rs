@@ -0,0 +1,971 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+use super::document_tree::{
+    BlockNode, DocumentTree, InlineMark, MarkAttrs, NodeId, SectionNode, TextBlock, TextRange, OBJECT_REPLACEMENT_CHAR,
+};
+use super::piece_table::{byte_offset, ChunkType, PieceChunk, PieceTableContent};
+use crate::storage::checksum::sha256_hex;
+
+#[derive(Debug, Error)]
+pub enum OperationError {
+    #[error("node not found: {0}")]
+    NodeNotFound(String),
+    #[error("node {0} does not hold text")]
+    NotTextNode(String),
+    #[error("offset {offset} out of range for node {node_id} of length {len}")]
+    OffsetOutOfRange { node_id: String, offset: usize, len: usize },
+    #[error("invalid format attributes: {0}")]
+    InvalidAttrs(#[from] serde_json::Error),
+    #[error("invalid operation log: {0}")]
+    InvalidLog(String),
+}
+
+/// An edit to the document model.
+/// Mirrors the `Operation` union in `app/lib/doc/schema.ts`; offsets are
+/// relative to the target node and measured in UTF-16 code units.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum Operation {
+    Insert {
+        node_id: NodeId,
+        offset: usize,
+        text: String,
+    },
+    Delete {
+        node_id: NodeId,
+        offset: usize,
+        len: usize,
+    },
+    Format {
+        node_id: NodeId,
+        start: usize,
+        end: usize,
+        attrs: BTreeMap<String, Value>,
+    },
+    /// Insert a top-level block after `after_node_id` (or first, if `None`).
+    /// The block is placed at its own declared buffer position: images and
+    /// tables get a U+FFFC sentinel there, text blocks start out empty.
+    InsertBlock {
+        after_node_id: Option<NodeId>,
+        block: BlockNode,
+    },
+    /// Remove a top-level block together with its buffer span.
+    DeleteBlock {
+        node_id: NodeId,
+    },
+    /// Replace a node's inline marks wholesale.
+    /// Backend-only: emitted by [`DocumentState::inverse`] to undo formatting.
+    SetMarks {
+        node_id: NodeId,
+        marks: Vec<InlineMark>,
+    },
+    /// Put a deleted top-level block back with `text`, the buffer text of
+    /// its whole span (separators between container children included).
+    /// Backend-only: emitted by [`DocumentState::inverse`] to undo `DeleteBlock`.
+    RestoreBlock {
+        after_node_id: Option<NodeId>,
+        block: BlockNode,
+        text: String,
+    },
+}
+
+/// Tree and buffer that operations are applied to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DocumentState {
+    pub base_text: String,
+    pub chunks: Vec<PieceChunk>,
+    pub document_tree: DocumentTree,
+}
+
+impl DocumentState {
+    pub fn text(&self) -> String {
+        PieceTableContent {
+            base_text: self.base_text.clone(),
+            chunks: self.chunks.clone(),
+        }
+        .to_text()
+    }
+
+    /// SHA-256 of the serialized state, used to tell whether an operation
+    /// log still ends in this state.
+    pub fn hash(&self) -> String {
+        serde_json::to_vec(self).map(|bytes| sha256_hex(&bytes)).unwrap_or_default()
+    }
+
+    /// Apply a single operation, appending piece-table chunks and keeping
+    /// node ranges in sync with the buffer.
+    pub fn apply(&mut self, op: &Operation) -> Result<(), OperationError> {
+        match op {
+            Operation::Insert { node_id, offset, text } => {
+                let range = self.text_range(node_id)?;
+                check_offset(node_id, *offset, range.len())?;
+                let len = utf16_len(text);
+                if len == 0 {
+                    return Ok(());
+                }
+                let pos = range.start + offset;
+                self.push_insert(pos, text.clone());
+                shift_tree(&mut self.document_tree, pos, len as isize, Some(node_id));
+                let (range, marks) = find_text_node(&mut self.document_tree, node_id)?;
+                range.end += len;
+                if let Some(marks) = marks {
+                    for mark in marks.iter_mut() {
+                        if mark.start >= *offset {
+                            mark.start += len;
+                            mark.end += len;
+                        } else if mark.end >= *offset {
+                            mark.end += len;
+                        }
+                    }
+                }
+            }
+            Operation::Delete { node_id, offset, len } => {
+                let range = self.text_range(node_id)?;
+                check_offset(node_id, *offset, range.len())?;
+                let len = (*len).min(range.len() - offset);
+                if len == 0 {
+                    return Ok(());
+                }
+                let pos = range.start + offset;
+                self.push_delete(pos, len);
+                shift_tree(&mut self.document_tree, pos, -(len as isize), Some(node_id));
+                let (range, marks) = find_text_node(&mut self.document_tree, node_id)?;
+                range.end -= len;
+                if let Some(marks) = marks {
+                    let map = |p: usize| if p <= *offset { p } else { p.saturating_sub(len).max(*offset) };
+                    for mark in marks.iter_mut() {
+                        mark.start = map(mark.start);
+                        mark.end = map(mark.end);
+                    }
+                    marks.retain(|mark| mark.start < mark.end);
+                }
+            }
+            Operation::Format {
+                node_id,
+                start,
+                end,
+                attrs,
+            } => {
+                let len = self.text_range(node_id)?.len();
+                let end = (*end).min(len);
+                if *start >= end {
+                    return Ok(());
+                }
+                let marks = self.marks_mut(node_id)?;
+                *marks = format_marks(marks, *start, end, attrs)?;
+            }
+            Operation::InsertBlock { after_node_id, block } => {
+                let mut block = block.clone();
+                let buffer_len = utf16_len(&self.text());
+                let pos = block_position(&block).min(buffer_len);
+                let width = match &block {
+                    BlockNode::Image(_) | BlockNode::Table(_) => 1,
+                    _ => 0,
+                };
+                if width > 0 {
+                    self.push_insert(pos, OBJECT_REPLACEMENT_CHAR.to_string());
+                    shift_tree(&mut self.document_tree, pos, width as isize, None);
+                }
+                place_block(&mut block, pos, width);
+                self.insert_block(after_node_id.as_deref(), block)?;
+            }
+            Operation::DeleteBlock { node_id } => {
+                let (section, index) = self.locate_block(node_id)?;
+                let block = self.document_tree.root.children[section].children.remove(index);
+                if let Some(span) = block.span().filter(|span| !span.is_empty()) {
+                    self.push_delete(span.start, span.len());
+                    shift_tree(&mut self.document_tree, span.start, -(span.len() as isize), None);
+                }
+            }
+            Operation::SetMarks { node_id, marks } => {
+                *self.marks_mut(node_id)? = marks.clone();
+            }
+            Operation::RestoreBlock {
+                after_node_id,
+                block,
+                text,
+            } => {
+                let mut block = block.clone();
+                let start = block.span().map_or(0, |span| span.start);
+                let pos = start.min(utf16_len(&self.text()));
+                let len = utf16_len(text);
+                if len > 0 {
+                    self.push_insert(pos, text.clone());
+                    shift_tree(&mut self.document_tree, pos, len as isize, None);
+                }
+                move_block(&mut block, start, pos);
+                self.insert_block(after_node_id.as_deref(), block)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Operations that undo `op` when applied, in order, to the state that
+    /// results from applying `op` to `self`.
+    pub fn inverse(&self, op: &Operation) -> Result<Vec<Operation>, OperationError> {
+        Ok(match op {
+            Operation::Insert { node_id, offset, text } => vec![Operation::Delete {
+                node_id: node_id.clone(),
+                offset: *offset,
+                len: utf16_len(text),
+            }],
+            Operation::Delete { node_id, offset, len } => {
+                let range = self.text_range(node_id)?;
+                check_offset(node_id, *offset, range.len())?;
+                let start = range.start + offset;
+                let end = start + (*len).min(range.len() - offset);
+                vec![
+                    Operation::Insert {
+                        node_id: node_id.clone(),
+                        offset: *offset,
+                        text: slice_utf16(&self.text(), start, end),
+                    },
+                    Operation::SetMarks {
+                        node_id: node_id.clone(),
+                        marks: self.marks(node_id)?.to_vec(),
+                    },
+                ]
+            }
+            Operation::Format { node_id, .. } | Operation::SetMarks { node_id, .. } => vec![Operation::SetMarks {
+                node_id: node_id.clone(),
+                marks: self.marks(node_id)?.to_vec(),
+            }],
+            Operation::InsertBlock { block, .. } | Operation::RestoreBlock { block, .. } => {
+                vec![Operation::DeleteBlock {
+                    node_id: block.id().to_string(),
+                }]
+            }
+            Operation::DeleteBlock { node_id } => {
+                let (section, index) = self.locate_block(node_id)?;
+                let children = &self.document_tree.root.children[section].children;
+                let block = &children[index];
+                let after_node_id = match index {
+                    0 if section == 0 => None,
+                    0 => self.document_tree.root.children[section - 1]
+                        .children
+                        .last()
+                        .map(|b| b.id().to_string()),
+                    _ => Some(children[index - 1].id().to_string()),
+                };
+                let text = block
+                    .span()
+                    .map(|span| slice_utf16(&self.text(), span.start, span.end))
+                    .unwrap_or_default();
+                vec![Operation::RestoreBlock {
+                    after_node_id,
+                    block: block.clone(),
+                    text,
+                }]
+            }
+        })
+    }
+
+    fn push_insert(&mut self, pos: usize, data: String) {
+        self.chunks.push(PieceChunk {
+            kind: ChunkType::Insert,
+            offset: None,
+            len: None,
+            source: None,
+            pos: Some(pos),
+            data: Some(data),
+        });
+    }
+
+    fn push_delete(&mut self, pos: usize, len: usize) {
+        self.chunks.push(PieceChunk {
+            kind: ChunkType::Delete,
+            offset: None,
+            len: Some(len),
+            source: None,
+            pos: Some(pos),
+            data: None,
+        });
+    }
+
+    /// Insert a top-level block after `after_node_id` (or first, if `None`).
+    fn insert_block(&mut self, after_node_id: Option<&str>, block: BlockNode) -> Result<(), OperationError> {
+        let (section, index) = match after_node_id {
+            Some(id) => {
+                let (section, index) = self.locate_block(id)?;
+                (section, index + 1)
+            }
+            None => (0, 0),
+        };
+        if self.document_tree.root.children.is_empty() {
+            self.document_tree.root.children.push(section_for(&block));
+        }
+        self.document_tree.root.children[section].children.insert(index, block);
+        Ok(())
+    }
+
+    /// Section and child index of a top-level block.
+    fn locate_block(&self, id: &str) -> Result<(usize, usize), OperationError> {
+        for (si, section) in self.document_tree.root.children.iter().enumerate() {
+            if let Some(bi) = section.children.iter().position(|b| b.id() == id) {
+                return Ok((si, bi));
+            }
+        }
+        Err(OperationError::NodeNotFound(id.to_string()))
+    }
+
+    fn text_range(&self, id: &str) -> Result<TextRange, OperationError> {
+        Ok(find_text_node_ref(&self.document_tree, id)?.0)
+    }
+
+    fn marks(&self, id: &str) -> Result<&[InlineMark], OperationError> {
+        find_text_node_ref(&self.document_tree, id)?
+            .1
+            .ok_or_else(|| OperationError::NotTextNode(id.to_string()))
+    }
+
+    fn marks_mut(&mut self, id: &str) -> Result<&mut Vec<InlineMark>, OperationError> {
+        find_text_node(&mut self.document_tree, id)?
+            .1
+            .ok_or_else(|| OperationError::NotTextNode(id.to_string()))
+    }
+}
+
+type TextNodeMut<'a> = (&'a mut TextRange, Option<&'a mut Vec<InlineMark>>);
+
+/// Range and marks of a text-bearing node anywhere in the tree.
+fn find_text_node_ref<'a>(
+    tree: &'a DocumentTree,
+    id: &str,
+) -> Result<(TextRange, Option<&'a [InlineMark]>), OperationError> {
+    for block in tree.blocks() {
+        let found = match block {
+            BlockNode::Paragraph(p) if p.id == id => Some((p.text_range, Some(p.marks.as_slice()))),
+            BlockNode::Heading(h) if h.id == id => Some((h.text_range, Some(h.marks.as_slice()))),
+            BlockNode::Table(t) if t.id == id => Some((t.text_range, None)),
+            BlockNode::Image(i) if i.id == id => return Err(OperationError::NotTextNode(id.to_string())),
+            BlockNode::Blockquote(q) => q
+                .children
+                .iter()
+                .find(|c| c.id() == id)
+                .map(|c| (c.text_range(), Some(text_block_marks(c).as_slice()))),
+            BlockNode::List(l) => l
+                .items
+                .iter()
+                .find(|item| item.content.id() == id)
+                .map(|item| (item.content.text_range(), Some(text_block_marks(&item.content).as_slice()))),
+            _ => None,
+        };
+        if let Some(found) = found {
+            return Ok(found);
+        }
+    }
+    Err(OperationError::NodeNotFound(id.to_string()))
+}
+
+/// Range (and marks, for paragraphs and headings) of a text-bearing node anywhere in the tree.
+fn find_text_node<'a>(tree: &'a mut DocumentTree, id: &str) -> Result<TextNodeMut<'a>, OperationError> {
+    for section in &mut tree.root.children {
+        for block in &mut section.children {
+            let found = match block {
+                BlockNode::Paragraph(p) if p.id == id => Some((&mut p.text_range, Some(&mut p.marks))),
+                BlockNode::Heading(h) if h.id == id => Some((&mut h.text_range, Some(&mut h.marks))),
+                BlockNode::Table(t) if t.id == id => Some((&mut t.text_range, None)),
+                BlockNode::Image(i) if i.id == id => return Err(OperationError::NotTextNode(id.to_string())),
+                BlockNode::Blockquote(q) => q.children.iter_mut().find(|c| c.id() == id).map(text_block_mut),
+                BlockNode::List(l) => l
+                    .items
+                    .iter_mut()
+                    .find(|item| item.content.id() == id)
+                    .map(|item| text_block_mut(&mut item.content)),
+                _ => None,
+            };
+            if let Some(found) = found {
+                return Ok(found);
+            }
+        }
+    }
+    Err(OperationError::NodeNotFound(id.to_string()))
+}
+
+fn text_block_mut(block: &mut TextBlock) -> TextNodeMut<'_> {
+    match block {
+        TextBlock::Paragraph(p) => (&mut p.text_range, Some(&mut p.marks)),
+        TextBlock::Heading(h) => (&mut h.text_range, Some(&mut h.marks)),
+    }
+}
+
+fn text_block_marks(block: &TextBlock) -> &Vec<InlineMark> {
+    match block {
+        TextBlock::Paragraph(p) => &p.marks,
+        TextBlock::Heading(h) => &h.marks,
+    }
+}
+
+/// Declared buffer position of a block about to be inserted.
+fn block_position(block: &BlockNode) -> usize {
+    match block {
+        BlockNode::Image(i) => i.buffer_position,
+        _ => block.span().map(|span| span.start).unwrap_or(0),
+    }
+}
+
+/// Collapse an inserted block onto `pos`: text starts empty, objects own `width` sentinel characters.
+fn place_block(block: &mut BlockNode, pos: usize, width: usize) {
+    let collapse = |block: &mut TextBlock| match block {
+        TextBlock::Paragraph(p) => {
+            p.text_range = TextRange::new(pos, pos);
+            p.marks.clear();
+        }
+        TextBlock::Heading(h) => {
+            h.text_range = TextRange::new(pos, pos);
+            h.marks.clear();
+        }
+    };
+    match block {
+        BlockNode::Paragraph(p) => {
+            p.text_range = TextRange::new(pos, pos);
+            p.marks.clear();
+        }
+        BlockNode::Heading(h) => {
+            h.text_range = TextRange::new(pos, pos);
+            h.marks.clear();
+        }
+        BlockNode::Image(i) => {
+            i.buffer_position = pos;
+            i.asset_ref.target_pos = pos;
+        }
+        BlockNode::Table(t) => t.text_range = TextRange::new(pos, pos + width),
+        BlockNode::Blockquote(q) => q.children.iter_mut().for_each(collapse),
+        BlockNode::List(l) => l.items.iter_mut().for_each(|item| collapse(&mut item.content)),
+    }
+}
+
+/// Move a block whose span starts at `from` to start at `to`.
+fn move_block(block: &mut BlockNode, from: usize, to: usize) {
+    let delta = to as isize - from as isize;
+    if delta == 0 {
+        return;
+    }
+    let shift = |value: &mut usize| *value = value.saturating_add_signed(delta);
+    let shift_range = |range: &mut TextRange| {
+        shift(&mut range.start);
+        shift(&mut range.end);
+    };
+    let shift_text = |block: &mut TextBlock| match block {
+        TextBlock::Paragraph(p) => shift_range(&mut p.text_range),
+        TextBlock::Heading(h) => shift_range(&mut h.text_range),
+    };
+    match block {
+        BlockNode::Paragraph(p) => shift_range(&mut p.text_range),
+        BlockNode::Heading(h) => shift_range(&mut h.text_range),
+        BlockNode::Table(t) => shift_range(&mut t.text_range),
+        BlockNode::Image(i) => {
+            shift(&mut i.buffer_position);
+            i.asset_ref.target_pos = i.buffer_position;
+        }
+        BlockNode::Blockquote(q) => q.children.iter_mut().for_each(shift_text),
+        BlockNode::List(l) => l.items.iter_mut().for_each(|item| shift_text(&mut item.content)),
+    }
+}
+
+/// Section created to hold `block` in an empty tree. Its ID is derived
+/// from the block so that replaying the log rebuilds the same tree.
+pub(crate) fn section_for(block: &BlockNode) -> SectionNode {
+    SectionNode {
+        id: format!("{}-section", block.id()),
+        children: Vec::new(),
+        margins: None,
+        orientation: None,
+        headers: None,
+        footers: None,
+    }
+}
+
+/// Shift every node range at or after `pos` by `delta`, as `_shiftRangesAfter`
+/// does in `editorEngine.ts`. The node being edited (`skip`) is adjusted by the caller.
+pub(crate) fn shift_tree(tree: &mut DocumentTree, pos: usize, delta: isize, skip: Option<&str>) {
+    let shift = |value: &mut usize| *value = value.saturating_add_signed(delta).max(pos.min(*value));
+    let shift_range = |range: &mut TextRange| {
+        if range.start >= pos {
+            shift(&mut range.start);
+            shift(&mut range.end);
+        } else if range.end > pos {
+            shift(&mut range.end);
+        }
+    };
+    let shift_text = |block: &mut TextBlock| {
+        if Some(block.id()) != skip {
+            match block {
+                TextBlock::Paragraph(p) => shift_range(&mut p.text_range),
+                TextBlock::Heading(h) => shift_range(&mut h.text_range),
+            }
+        }
+    };
+    for section in &mut tree.root.children {
+        for block in &mut section.children {
+            if Some(block.id()) == skip {
+                continue;
+            }
+            match block {
+                BlockNode::Paragraph(p) => shift_range(&mut p.text_range),
+                BlockNode::Heading(h) => shift_range(&mut h.text_range),
+                BlockNode::Table(t) => shift_range(&mut t.text_range),
+                BlockNode::Image(i) => {
+                    if i.buffer_position >= pos {
+                        shift(&mut i.buffer_position);
+                        i.asset_ref.target_pos = i.buffer_position;
+                    }
+                }
+                BlockNode::Blockquote(q) => q.children.iter_mut().for_each(&shift_text),
+                BlockNode::List(l) => l.items.iter_mut().for_each(|item| shift_text(&mut item.content)),
+            }
+        }
+    }
+}
+
+fn check_offset(node_id: &str, offset: usize, len: usize) -> Result<(), OperationError> {
+    if offset > len {
+        return Err(OperationError::OffsetOutOfRange {
+            node_id: node_id.to_string(),
+            offset,
+            len,
+        });
+    }
+    Ok(())
+}
+
+/// Apply `attrs` to `[start, end)`: existing marks are split at the
+/// boundaries, attributes merged (a `null` value clears a key), and
+/// adjacent marks with identical attributes coalesced.
+fn format_marks(
+    marks: &[InlineMark],
+    start: usize,
+    end: usize,
+    attrs: &BTreeMap<String, Value>,
+) -> Result<Vec<InlineMark>, OperationError> {
+    let mut bounds: Vec<usize> = vec![start, end];
+    for mark in marks {
+        bounds.push(mark.start);
+        bounds.push(mark.end);
+    }
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    let mut out: Vec<InlineMark> = Vec::new();
+    for window in bounds.windows(2) {
+        let (seg_start, seg_end) = (window[0], window[1]);
+        let existing = marks
+            .iter()
+            .rev()
+            .find(|m| m.start <= seg_start && m.end >= seg_end)
+            .and_then(|m| m.attrs.clone());
+        let attrs = if seg_start >= start && seg_end <= end {
+            Some(merge_attrs(existing.unwrap_or_default(), attrs)?)
+        } else {
+            existing
+        };
+        let Some(attrs) = attrs.filter(|a| *a != MarkAttrs::default()) else {
+            continue;
+        };
+        match out.last_mut() {
+            Some(last) if last.end == seg_start && last.attrs.as_ref() == Some(&attrs) => last.end = seg_end,
+            _ => out.push(InlineMark {
+                start: seg_start,
+                end: seg_end,
+                attrs: Some(attrs),
+            }),
+        }
+    }
+    Ok(out)
+}
+
+fn merge_attrs(base: MarkAttrs, attrs: &BTreeMap<String, Value>) -> Result<MarkAttrs, OperationError> {
+    let mut merged = match serde_json::to_value(base)? {
+        Value::Object(map) => map,
+        _ => serde_json::Map::new(),
+    };
+    for (key, value) in attrs {
+        if value.is_null() {
+            merged.remove(key);
+        } else {
+            merged.insert(key.clone(), value.clone());
+        }
+    }
+    Ok(serde_json::from_value(Value::Object(merged))?)
+}
+
+fn utf16_len(text: &str) -> usize {
+    super::piece_table::utf16_len(text)
+}
+
+fn slice_utf16(text: &str, start: usize, end: usize) -> String {
+    text[byte_offset(text, start)..byte_offset(text, end)].to_string()
+}
+
+// ============================================================================
+// Operation Log
+// ============================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EntryKind {
+    /// A user edit
+    Edit,
+    /// Reverts the entry named by `target`
+    Undo,
+    /// Re-applies the entry named by `target`
+    Redo,
+}
+
+/// One user action in the log: a group of operations applied together.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogEntry {
+    /// Sequence number (1-indexed, strictly increasing)
+    pub seq: u64,
+    pub kind: EntryKind,
+    /// For undo/redo entries, the edit entry they act on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<u64>,
+    pub timestamp: DateTime<Utc>,
+    /// Operations applied by this entry
+    pub ops: Vec<Operation>,
+    /// Operations that revert this entry
+    pub inverse: Vec<Operation>,
+}
+
+/// Append-only operation history, persisted as `versions/operations.json`.
+/// Undo and redo are recorded as entries too, so the undo stack can be
+/// rebuilt from the log after a restart and any past state replayed from `base`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OperationLog {
+    /// Document state before the first entry
+    pub base: DocumentState,
+    pub entries: Vec<LogEntry>,
+    /// Hash of the state after the latest entry (see [`DocumentState::hash`])
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub head_hash: Option<String>,
+}
+
+impl OperationLog {
+    pub fn new(base: DocumentState) -> Self {
+        Self {
+            head_hash: Some(base.hash()),
+            base,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Whether the log ends in `state`. Undoing entries of a log that does
+    /// not would apply their inverses to text they were not recorded on.
+    /// Logs saved without a head hash are checked by replaying them.
+    pub fn matches(&self, state: &DocumentState) -> bool {
+        match &self.head_hash {
+            Some(hash) => *hash == state.hash(),
+            None => self.replay(self.head()).is_ok_and(|head| head == *state),
+        }
+    }
+
+    /// Check that sequence numbers increase and that every undo and redo
+    /// entry targets an earlier edit, as a log read from an archive may not.
+    pub fn validate(&self) -> Result<(), OperationError> {
+        let mut edits = std::collections::HashSet::new();
+        let mut last = 0;
+        for entry in &self.entries {
+            if entry.seq <= last {
+                return Err(OperationError::InvalidLog(format!("entry {} is out of sequence", entry.seq)));
+            }
+            last = entry.seq;
+            match (entry.kind, entry.target) {
+                (EntryKind::Edit, _) => {
+                    edits.insert(entry.seq);
+                }
+                (_, Some(target)) if edits.contains(&target) => {}
+                (_, target) => {
+                    return Err(OperationError::InvalidLog(format!(
+                        "entry {} targets unknown edit {:?}",
+                        entry.seq, target
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sequence number of the latest entry (0 if empty).
+    pub fn head(&self) -> u64 {
+        self.entries.last().map(|e| e.seq).unwrap_or(0)
+    }
+
+    /// Apply `ops` to `state` as one undoable action and append it to the log.
+    /// `state` is left untouched if any operation fails.
+    pub fn record(&mut self, state: &mut DocumentState, ops: Vec<Operation>) -> Result<&LogEntry, OperationError> {
+        let (next, inverse) = apply_all(state, &ops)?;
+        *state = next;
+        Ok(self.push(EntryKind::Edit, None, ops, inverse, state))
+    }
+
+    /// Revert the most recent undoable edit. Returns `None` if there is nothing to undo.
+    pub fn undo(&mut self, state: &mut DocumentState) -> Result<Option<&LogEntry>, OperationError> {
+        let Some(target) = self.stacks().0.pop() else {
+            return Ok(None);
+        };
+        let ops = self.entry(target)?.inverse.clone();
+        let (next, inverse) = apply_all(state, &ops)?;
+        *state = next;
+        Ok(Some(self.push(EntryKind::Undo, Some(target), ops, inverse, state)))
+    }
+
+    /// Re-apply the most recently undone edit. Returns `None` if there is nothing to redo.
+    pub fn redo(&mut self, state: &mut DocumentState) -> Result<Option<&LogEntry>, OperationError> {
+        let Some(target) = self.stacks().1.pop() else {
+            return Ok(None);
+        };
+        let ops = self.entry(target)?.ops.clone();
+        let (next, inverse) = apply_all(state, &ops)?;
+        *state = next;
+        Ok(Some(self.push(EntryKind::Redo, Some(target), ops, inverse, state)))
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.stacks().0.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.stacks().1.is_empty()
+    }
+
+    /// Rebuild the document as it was right after entry `seq` (or the base state for 0).
+    pub fn replay(&self, seq: u64) -> Result<DocumentState, OperationError> {
+        let mut state = self.base.clone();
+        for entry in self.entries.iter().take_while(|e| e.seq <= seq) {
+            for op in &entry.ops {
+                state.apply(op)?;
+            }
+        }
+        Ok(state)
+    }
+
+    fn entry(&self, seq: u64) -> Result<&LogEntry, OperationError> {
+        self.entries
+            .iter()
+            .find(|e| e.seq == seq)
+            .ok_or_else(|| OperationError::InvalidLog(format!("no entry {seq}")))
+    }
+
+    /// Undo and redo stacks of edit sequence numbers, derived from the log.
+    fn stacks(&self) -> (Vec<u64>, Vec<u64>) {
+        let mut undo = Vec::new();
+        let mut redo = Vec::new();
+        for entry in &self.entries {
+            match entry.kind {
+                EntryKind::Edit => {
+                    undo.push(entry.seq);
+                    redo.clear();
+                }
+                EntryKind::Undo => {
+                    undo.pop();
+                    redo.extend(entry.target);
+                }
+                EntryKind::Redo => {
+                    redo.pop();
+                    undo.extend(entry.target);
+                }
+            }
+        }
+        (undo, redo)
+    }
+
+    fn push(
+        &mut self,
+        kind: EntryKind,
+        target: Option<u64>,
+        ops: Vec<Operation>,
+        inverse: Vec<Operation>,
+        head: &DocumentState,
+    ) -> &LogEntry {
+        let seq = self.head() + 1;
+        self.head_hash = Some(head.hash());
+        self.entries.push(LogEntry {
+            seq,
+            kind,
+            target,
+            timestamp: Utc::now(),
+            ops,
+            inverse,
+        });
+        self.entries.last().expect("entry was just pushed")
+    }
+}
+
+/// Apply `ops` to a copy of `state`, returning the new state and the
+/// operations that revert the whole group.
+fn apply_all(state: &DocumentState, ops: &[Operation]) -> Result<(DocumentState, Vec<Operation>), OperationError> {
+    let mut next = state.clone();
+    let mut inverse = Vec::new();
+    for op in ops {
+        let mut undo = next.inverse(op)?;
+        next.apply(op)?;
+        undo.extend(inverse);
+        inverse = undo;
+    }
+    Ok((next, inverse))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "Title\nHello 😀 world\n\u{FFFC}\none\ntwo\nq1\nq2\n\u{FFFC}ab";
+
+    fn state() -> DocumentState {
+        let document_tree = serde_json::from_value(serde_json::json!({
+            "version": 2,
+            "root": {"children": [{
+                "id": "s1",
+                "type": "section",
+                "children": [
+                    {"id": "h1", "type": "heading", "level": 1, "textRange": {"start": 0, "end": 5}, "marks": []},
+                    {"id": "p1", "type": "paragraph", "textRange": {"start": 6, "end": 20},
+                        "marks": [{"start": 0, "end": 5, "attrs": {"b": true}}]},
+                    {"id": "i1", "type": "image", "bufferPosition": 21,
+                        "assetRef": {"name": "a.png", "targetPos": 21, "alt": "x", "size": [1, 1], "bytes": []},
+                        "alt": "x", "size": [1, 1]},
+                    {"id": "l1", "type": "list", "listType": "unordered", "items": [
+                        {"id": "li1", "type": "listItem", "content": {"id": "p2", "type": "paragraph", "textRange": {"start": 23, "end": 26}, "marks": []}},
+                        {"id": "li2", "type": "listItem", "content": {"id": "p3", "type": "paragraph", "textRange": {"start": 27, "end": 30},
+                            "marks": [{"start": 0, "end": 3, "attrs": {"i": true}}]}}
+                    ]},
+                    {"id": "q1", "type": "blockquote", "children": [
+                        {"id": "p4", "type": "paragraph", "textRange": {"start": 31, "end": 33}, "marks": []},
+                        {"id": "p5", "type": "paragraph", "textRange": {"start": 34, "end": 36}, "marks": []}
+                    ]},
+                    {"id": "t1", "type": "table", "textRange": {"start": 37, "end": 40}, "rows": 1, "cols": 2}
+                ]
+            }]}
+        }))
+        .unwrap();
+        DocumentState {
+            base_text: TEXT.to_string(),
+            chunks: Vec::new(),
+            document_tree,
+        }
+    }
+
+    fn paragraph(id: &str, start: usize) -> BlockNode {
+        serde_json::from_value(serde_json::json!({
+            "id": id, "type": "paragraph", "textRange": {"start": start, "end": start}, "marks": []
+        }))
+        .unwrap()
+    }
+
+    /// Apply `op`, then its inverse, and expect the original text and tree.
+    fn assert_round_trip(op: Operation) {
+        let original = state();
+        let inverse = original.inverse(&op).unwrap();
+        let mut state = original.clone();
+        state.apply(&op).unwrap();
+        assert_ne!((state.text(), &state.document_tree), (original.text(), &original.document_tree), "{op:?}");
+        for undo in &inverse {
+            state.apply(undo).unwrap();
+        }
+        assert_eq!(state.text(), original.text(), "{op:?}");
+        assert_eq!(state.document_tree, original.document_tree, "{op:?}");
+    }
+
+    #[test]
+    fn every_operation_is_undone_by_its_inverse() {
+        let bold = BTreeMap::from([("b".to_string(), Value::Null), ("u".to_string(), Value::Bool(true))]);
+        let image: BlockNode = serde_json::from_value(serde_json::json!({
+            "id": "i2", "type": "image", "bufferPosition": 6,
+            "assetRef": {"name": "b.png", "targetPos": 6, "alt": "", "size": [1, 1], "bytes": []},
+            "alt": "", "size": [1, 1]
+        }))
+        .unwrap();
+        let ops = [
+            Operation::Insert { node_id: "p1".into(), offset: 6, text: "big 🎉 ".into() },
+            Operation::Insert { node_id: "p3".into(), offset: 3, text: "!".into() },
+            Operation::Delete { node_id: "p1".into(), offset: 2, len: 6 },
+            Operation::Delete { node_id: "p5".into(), offset: 0, len: 2 },
+            Operation::Format { node_id: "p1".into(), start: 3, end: 10, attrs: bold },
+            Operation::SetMarks { node_id: "p3".into(), marks: Vec::new() },
+            Operation::InsertBlock { after_node_id: Some("h1".into()), block: paragraph("p9", 6) },
+            Operation::InsertBlock { after_node_id: Some("h1".into()), block: image },
+        ];
+        for op in ops {
+            assert_round_trip(op);
+        }
+        for node_id in ["h1", "p1", "i1", "l1", "q1", "t1"] {
+            assert_round_trip(Operation::DeleteBlock { node_id: node_id.into() });
+        }
+    }
+
+    #[test]
+    fn deleting_a_container_removes_its_separators_and_undo_puts_them_back() {
+        let mut state = state();
+        let op = Operation::DeleteBlock { node_id: "l1".into() };
+        let inverse = state.inverse(&op).unwrap();
+        assert!(matches!(&inverse[..], [Operation::RestoreBlock { text, .. }] if text == "one\ntwo"));
+        state.apply(&op).unwrap();
+        assert_eq!(state.text(), TEXT.replace("one\ntwo", ""));
+        // undoing the undo deletes the block again
+        let redo = state.inverse(&inverse[0]).unwrap();
+        state.apply(&inverse[0]).unwrap();
+        assert_eq!(state.text(), TEXT);
+        state.apply(&redo[0]).unwrap();
+        assert_eq!(state.text(), TEXT.replace("one\ntwo", ""));
+    }
+
+    #[test]
+    fn inserting_into_an_empty_tree_replays_identically() {
+        let empty = DocumentState {
+            base_text: String::new(),
+            chunks: Vec::new(),
+            document_tree: serde_json::from_value(serde_json::json!({"version": 2, "root": {"children": []}})).unwrap(),
+        };
+        let mut log = OperationLog::new(empty.clone());
+        let mut head = empty;
+        log.record(&mut head, vec![Operation::InsertBlock { after_node_id: None, block: paragraph("p1", 0) }])
+            .unwrap();
+        assert_eq!(log.replay(log.head()).unwrap(), head);
+        assert_eq!(log.replay(log.head()).unwrap(), log.replay(log.head()).unwrap());
+    }
+
+    #[test]
+    fn undo_and_redo_survive_a_reload_and_replay_any_state() {
+        let original = state();
+        let mut log = OperationLog::new(original.clone());
+        let mut head = original.clone();
+        log.record(&mut head, vec![Operation::Insert { node_id: "p1".into(), offset: 0, text: "Oh ".into() }])
+            .unwrap();
+        let first = head.clone();
+        log.record(&mut head, vec![Operation::DeleteBlock { node_id: "q1".into() }]).unwrap();
+        let second = head.clone();
+
+        // a log read back from the archive keeps its stacks
+        let mut log: OperationLog = serde_json::from_slice(&serde_json::to_vec(&log).unwrap()).unwrap();
+        log.validate().unwrap();
+        assert!(log.matches(&head));
+        log.undo(&mut head).unwrap().unwrap();
+        assert_eq!((head.text(), &head.document_tree), (first.text(), &first.document_tree));
+        log.undo(&mut head).unwrap().unwrap();
+        assert_eq!((head.text(), &head.document_tree), (original.text(), &original.document_tree));
+        assert!(log.undo(&mut head).unwrap().is_none());
+        log.redo(&mut head).unwrap().unwrap();
+        assert_eq!((head.text(), &head.document_tree), (first.text(), &first.document_tree));
+        assert!(log.can_redo());
+
+        // replay rebuilds every historical state, undo entries included
+        assert_eq!(log.replay(0).unwrap(), original);
+        assert_eq!(log.replay(1).unwrap().text(), first.text());
+        assert_eq!(log.replay(2).unwrap().text(), second.text());
+        assert_eq!(log.replay(log.head()).unwrap().text(), head.text());
+        assert!(log.matches(&head));
+
+        // a new edit clears the redo stack
+        log.record(&mut head, vec![Operation::Delete { node_id: "h1".into(), offset: 0, len: 1 }]).unwrap();
+        assert!(!log.can_redo());
+    }
+}
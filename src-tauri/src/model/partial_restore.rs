@@ -0,0 +1,683 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+use similar::{DiffOp, TextDiff};
+use thiserror::Error;
+
+use super::document_tree::{BlockNode, DocumentTree, ImageNode, NodeId, TextBlock, TextRange};
+use super::formatting::normalize_ranges;
+use super::operation::{section_for, shift_tree};
+use super::piece_table::{byte_offset, utf16_len, ChunkType, PieceChunk};
+use super::rebase::{edits_from_chunks, rebase_through_chunks, Edit, RebaseOptions, RebaseReport};
+use super::version::{DiffHunk, DiffLineKind, DocumentVersion};
+use crate::storage::checksum::sha256_hex;
+use crate::storage::zip_container::{AssetRef, DocumentPayload};
+
+#[derive(Debug, Error)]
+pub enum PartialRestoreError {
+    #[error("hunk {0} matches neither side of the version")]
+    HunkMismatch(String),
+    #[error("hunk {0} no longer applies to the working content")]
+    HunkNotApplicable(String),
+    #[error("version has no document tree")]
+    NoDocumentTree,
+    #[error("node not found in the version: {0}")]
+    NodeNotFound(String),
+    #[error("node {0} covers no text")]
+    EmptySelection(String),
+}
+
+/// Part of a version to bring back into the working content.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum RestoreSelection {
+    /// Hunks from `diff_versions` between the version and any other
+    /// version; whichever side matches the version is restored
+    Hunks { hunks: Vec<DiffHunk> },
+    /// A section, a heading with the blocks up to the next heading of the
+    /// same or a higher level, or any other top-level block
+    Section { node_id: NodeId },
+}
+
+/// One splice of the working text. Offsets are UTF-16 code units into the
+/// text before any splice is applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replacement {
+    pub pos: usize,
+    pub len: usize,
+    pub text: String,
+    /// Range of the version's text that `text` brings back
+    pub source: TextRange,
+    /// Units at the start of `text` that do not come from the version
+    pub lead: usize,
+}
+
+/// Work out how to splice the selected part of `version` into `working`.
+/// Only lines that differ are replaced, so nodes on unchanged lines keep
+/// their identity. The result is sorted and non-overlapping.
+pub fn plan(
+    selection: &RestoreSelection,
+    version: &DocumentVersion,
+    working: &str,
+    working_tree: Option<&DocumentTree>,
+) -> Result<Vec<Replacement>, PartialRestoreError> {
+    match selection {
+        RestoreSelection::Hunks { hunks } => plan_hunks(hunks, &version.content, working),
+        RestoreSelection::Section { node_id } => {
+            let tree = version
+                .document_tree
+                .as_ref()
+                .ok_or(PartialRestoreError::NoDocumentTree)?;
+            plan_section(node_id, &version.content, tree, working, working_tree)
+        }
+    }
+}
+
+/// Apply planned replacements to the payload. The text is spliced with
+/// piece-table chunks that metadata ranges, asset anchors and the tree are
+/// rebased through; the version's blocks, formatting and images for the
+/// restored text then take the place of whatever they replace.
+pub fn apply(
+    payload: &mut DocumentPayload,
+    version: &DocumentVersion,
+    replacements: &[Replacement],
+    options: &RebaseOptions,
+) -> RebaseReport {
+    // Splice back to front so every position still refers to untouched text.
+    let mut chunks = Vec::new();
+    for replacement in replacements.iter().rev() {
+        if replacement.len > 0 {
+            chunks.push(PieceChunk {
+                kind: ChunkType::Delete,
+                offset: None,
+                len: Some(replacement.len),
+                source: None,
+                pos: Some(replacement.pos),
+                data: None,
+            });
+        }
+        if !replacement.text.is_empty() {
+            chunks.push(PieceChunk {
+                kind: ChunkType::Insert,
+                offset: None,
+                len: None,
+                source: None,
+                pos: Some(replacement.pos),
+                data: Some(replacement.text.clone()),
+            });
+        }
+    }
+
+    let mut report = rebase_through_chunks(&mut payload.metadata.ranges, &mut payload.assets, &chunks, options);
+    if let Some(tree) = payload.document_tree.as_mut() {
+        for replacement in replacements {
+            remove_blocks_within(tree, TextRange::new(replacement.pos, replacement.pos + replacement.len));
+        }
+        for edit in edits_from_chunks(&chunks) {
+            match edit {
+                Edit::Insert { pos, len } => shift_tree(tree, pos, len as isize, None),
+                Edit::Delete { pos, len } => shift_tree(tree, pos, -(len as isize), None),
+            }
+        }
+    }
+    payload.chunks.extend(chunks);
+
+    let mut delta: isize = 0;
+    for replacement in replacements {
+        let start = replacement.pos.saturating_add_signed(delta) + replacement.lead;
+        let offset = start as isize - replacement.source.start as isize;
+        for range in &version.ranges {
+            let point = range.start == range.end && contains(replacement.source, TextRange::new(range.start, range.start + 1));
+            let (from, to) = (range.start.max(replacement.source.start), range.end.min(replacement.source.end));
+            if from < to || point {
+                let mut range = range.clone();
+                range.start = from.saturating_add_signed(offset);
+                range.end = to.max(from).saturating_add_signed(offset);
+                payload.metadata.ranges.push(range);
+            }
+        }
+        if let (Some(from), Some(tree)) = (version.document_tree.as_ref(), payload.document_tree.as_mut()) {
+            for block in from.blocks() {
+                if !block.span().is_some_and(|span| contains(replacement.source, span)) {
+                    continue;
+                }
+                let mut block = block.clone();
+                offset_block(&mut block, offset);
+                if tree.blocks().any(|other| other.id() == block.id()) {
+                    set_block_id(&mut block, uuid::Uuid::new_v4().to_string());
+                }
+                if let BlockNode::Image(image) = &block {
+                    restore_asset(image, version, &mut payload.assets, &payload.asset_history, &mut report);
+                }
+                insert_block(tree, block);
+            }
+        }
+        delta += utf16_len(&replacement.text) as isize - replacement.len as isize;
+    }
+    normalize_ranges(&mut payload.metadata.ranges);
+    report
+}
+
+// ============================================================================
+// Helper Functions
+// ============================================================================
+
+/// Lines of a text without their terminators, with the UTF-16 offset each
+/// one starts at; `starts` has one more entry for the end of the text.
+struct Lines<'a> {
+    lines: Vec<&'a str>,
+    starts: Vec<usize>,
+}
+
+impl<'a> Lines<'a> {
+    fn new(text: &'a str) -> Self {
+        let mut lines = Vec::new();
+        let mut starts = vec![0];
+        for line in text.split_inclusive('\n') {
+            starts.push(starts[starts.len() - 1] + utf16_len(line));
+            let content = line.strip_suffix('\n').unwrap_or(line);
+            lines.push(content.strip_suffix('\r').unwrap_or(content));
+        }
+        Self { lines, starts }
+    }
+
+    /// Whether `lines` appear at 1-indexed line `start`.
+    fn matches(&self, start: usize, lines: &[&str]) -> bool {
+        let start = start.saturating_sub(1);
+        self.lines.get(start..start + lines.len()) == Some(lines)
+    }
+
+    /// Occurrence of `lines` closest to 0-indexed line `expected`.
+    fn find(&self, lines: &[&str], expected: usize) -> Option<usize> {
+        if lines.is_empty() {
+            return Some(expected.min(self.lines.len()));
+        }
+        (0..=self.lines.len().checked_sub(lines.len())?)
+            .filter(|&idx| self.lines[idx..idx + lines.len()] == *lines)
+            .min_by_key(|idx| idx.abs_diff(expected))
+    }
+
+    fn span(&self, lines: Range<usize>) -> TextRange {
+        TextRange::new(self.starts[lines.start], self.starts[lines.end])
+    }
+}
+
+fn plan_hunks(hunks: &[DiffHunk], version: &str, working: &str) -> Result<Vec<Replacement>, PartialRestoreError> {
+    let version_lines = Lines::new(version);
+    let working_lines = Lines::new(working);
+    let mut claimed: Vec<Range<usize>> = Vec::new();
+    let mut replacements = Vec::new();
+    for hunk in hunks {
+        let side = |kind: DiffLineKind| -> Vec<&str> {
+            hunk.lines
+                .iter()
+                .filter(|line| line.kind == DiffLineKind::Context || line.kind == kind)
+                .map(|line| line.content.as_str())
+                .collect()
+        };
+        let (old, new) = (side(DiffLineKind::Deletion), side(DiffLineKind::Addition));
+        let (restored, restored_start, replaced, replaced_start) = if version_lines.matches(hunk.old_start, &old) {
+            (old, hunk.old_start, new, hunk.new_start)
+        } else if version_lines.matches(hunk.new_start, &new) {
+            (new, hunk.new_start, old, hunk.old_start)
+        } else {
+            return Err(PartialRestoreError::HunkMismatch(hunk.header.clone()));
+        };
+
+        let target = working_lines
+            .find(&replaced, replaced_start.saturating_sub(1))
+            .map(|at| at..at + replaced.len())
+            .filter(|target| {
+                !claimed
+                    .iter()
+                    .any(|other| other.start < target.end && target.start < other.end)
+            })
+            .ok_or_else(|| PartialRestoreError::HunkNotApplicable(hunk.header.clone()))?;
+        let source = restored_start.saturating_sub(1);
+        replacements.extend(region_replacements(
+            working,
+            working_lines.span(target.clone()),
+            version,
+            version_lines.span(source..source + restored.len()),
+        ));
+        claimed.push(target);
+    }
+    replacements.sort_by_key(|replacement| replacement.pos);
+    Ok(replacements)
+}
+
+fn plan_section(
+    node_id: &str,
+    version: &str,
+    tree: &DocumentTree,
+    working: &str,
+    working_tree: Option<&DocumentTree>,
+) -> Result<Vec<Replacement>, PartialRestoreError> {
+    let blocks = selected_blocks(tree, node_id).ok_or_else(|| PartialRestoreError::NodeNotFound(node_id.to_string()))?;
+    let source = blocks_span(&blocks, version).ok_or_else(|| PartialRestoreError::EmptySelection(node_id.to_string()))?;
+    // Replace the selection as it is now, or failing that, the blocks of
+    // it that survive.
+    let existing = working_tree.and_then(|working_tree| {
+        let current = selected_blocks(working_tree, node_id).unwrap_or_else(|| {
+            working_tree
+                .blocks()
+                .filter(|block| blocks.iter().any(|selected| selected.id() == block.id()))
+                .collect()
+        });
+        blocks_span(&current, working)
+    });
+    if let Some(target) = existing {
+        return Ok(region_replacements(working, target, version, source));
+    }
+
+    // Nothing of it is left: insert it after the nearest preceding block
+    // that survives. Without a tree, fall back to its offset in the version.
+    let first = blocks[0].id();
+    let preceding: Vec<&BlockNode> = tree.blocks().take_while(|block| block.id() != first).collect();
+    let working_len = utf16_len(working);
+    let next_line = |at: usize| {
+        let rest = &working[byte_offset(working, at)..];
+        rest.find('\n').map_or(working_len, |idx| at + utf16_len(&rest[..idx]) + 1)
+    };
+    let pos = match working_tree {
+        Some(working_tree) => preceding
+            .iter()
+            .rev()
+            .find_map(|block| working_tree.blocks().find(|other| other.id() == block.id())?.span())
+            .map_or(0, |span| next_line(span.end)),
+        None if source.start == 0 || slice(working, TextRange::new(source.start - 1, source.start)) == "\n" => {
+            source.start.min(working_len)
+        }
+        None => next_line(source.start.min(working_len)),
+    };
+
+    let body = slice(version, source);
+    let mut replacement = Replacement {
+        pos,
+        len: 0,
+        text: body.to_string(),
+        source,
+        lead: 0,
+    };
+    if pos == working_len && pos > 0 && !working.ends_with('\n') {
+        let body = body.strip_suffix('\n').unwrap_or(body);
+        replacement.text = format!("\n{}", body);
+        replacement.source.end = source.start + utf16_len(body);
+        replacement.lead = 1;
+    } else if pos < working_len && !body.ends_with('\n') {
+        replacement.text.push('\n');
+    }
+    Ok(vec![replacement])
+}
+
+/// Replacements turning `working[target]` into `version[source]`, one per
+/// run of changed lines.
+fn region_replacements(working: &str, target: TextRange, version: &str, source: TextRange) -> Vec<Replacement> {
+    let (old, new) = (slice(working, target), slice(version, source));
+    let diff = TextDiff::from_lines(old, new);
+    let starts = |lines: &[&str]| {
+        let mut starts = vec![0];
+        for line in lines {
+            starts.push(starts[starts.len() - 1] + utf16_len(line));
+        }
+        starts
+    };
+    let (old_starts, new_starts) = (starts(diff.old_slices()), starts(diff.new_slices()));
+    diff.ops()
+        .iter()
+        .filter(|op| !matches!(op, DiffOp::Equal { .. }))
+        .map(|op| {
+            let (removed, restored) = (op.old_range(), op.new_range());
+            let restored = TextRange::new(new_starts[restored.start], new_starts[restored.end]);
+            Replacement {
+                pos: target.start + old_starts[removed.start],
+                len: old_starts[removed.end] - old_starts[removed.start],
+                text: slice(new, restored).to_string(),
+                source: TextRange::new(source.start + restored.start, source.start + restored.end),
+                lead: 0,
+            }
+        })
+        .collect()
+}
+
+fn slice(text: &str, range: TextRange) -> &str {
+    &text[byte_offset(text, range.start)..byte_offset(text, range.end)]
+}
+
+/// Whether `span` lies inside `outer`. Empty spans count when their
+/// position is inside, so an empty paragraph goes with its line.
+fn contains(outer: TextRange, span: TextRange) -> bool {
+    span.start >= outer.start && span.end <= outer.end && span.start < outer.end
+}
+
+/// Top-level blocks a section node ID selects, in document order.
+fn selected_blocks<'a>(tree: &'a DocumentTree, node_id: &str) -> Option<Vec<&'a BlockNode>> {
+    if let Some(section) = tree.root.children.iter().find(|section| section.id == node_id) {
+        return Some(section.children.iter().collect());
+    }
+    let blocks: Vec<&BlockNode> = tree.blocks().collect();
+    let idx = blocks.iter().position(|block| block.id() == node_id)?;
+    let end = match blocks[idx] {
+        BlockNode::Heading(heading) => blocks[idx + 1..]
+            .iter()
+            .position(|block| matches!(block, BlockNode::Heading(next) if next.level <= heading.level))
+            .map_or(blocks.len(), |len| idx + 1 + len),
+        _ => idx + 1,
+    };
+    Some(blocks[idx..end].to_vec())
+}
+
+/// Buffer span of `blocks`, including the newline after the last one.
+fn blocks_span(blocks: &[&BlockNode], text: &str) -> Option<TextRange> {
+    let mut spans = blocks.iter().filter_map(|block| block.span());
+    let first = spans.next()?;
+    let mut span = TextRange::new(first.start, spans.next_back().unwrap_or(first).end);
+    if slice(text, TextRange::new(span.end, span.end + 1)) == "\n" {
+        span.end += 1;
+    }
+    Some(span)
+}
+
+/// Drop blocks, and children of containers, whose text lies inside `range`.
+fn remove_blocks_within(tree: &mut DocumentTree, range: TextRange) {
+    if range.is_empty() {
+        return;
+    }
+    for section in &mut tree.root.children {
+        section.children.retain_mut(|block| {
+            let Some(span) = block.span() else {
+                return true;
+            };
+            if contains(range, span) {
+                return false;
+            }
+            match block {
+                BlockNode::Blockquote(q) => q.children.retain(|child| !contains(range, child.text_range())),
+                BlockNode::List(l) => l.items.retain(|item| !contains(range, item.content.text_range())),
+                _ => {}
+            }
+            block.span().is_some()
+        });
+    }
+}
+
+fn offset_block(block: &mut BlockNode, offset: isize) {
+    let shift = |range: &mut TextRange| {
+        range.start = range.start.saturating_add_signed(offset);
+        range.end = range.end.saturating_add_signed(offset);
+    };
+    let shift_text = |block: &mut TextBlock| match block {
+        TextBlock::Paragraph(p) => shift(&mut p.text_range),
+        TextBlock::Heading(h) => shift(&mut h.text_range),
+    };
+    match block {
+        BlockNode::Paragraph(p) => shift(&mut p.text_range),
+        BlockNode::Heading(h) => shift(&mut h.text_range),
+        BlockNode::Table(t) => shift(&mut t.text_range),
+        BlockNode::Image(i) => {
+            i.buffer_position = i.buffer_position.saturating_add_signed(offset);
+            i.asset_ref.target_pos = i.buffer_position;
+        }
+        BlockNode::Blockquote(q) => q.children.iter_mut().for_each(&shift_text),
+        BlockNode::List(l) => l.items.iter_mut().for_each(|item| shift_text(&mut item.content)),
+    }
+}
+
+fn set_block_id(block: &mut BlockNode, id: NodeId) {
+    match block {
+        BlockNode::Paragraph(p) => p.id = id,
+        BlockNode::Heading(h) => h.id = id,
+        BlockNode::Image(i) => i.id = id,
+        BlockNode::Blockquote(q) => q.id = id,
+        BlockNode::List(l) => l.id = id,
+        BlockNode::Table(t) => t.id = id,
+    }
+}
+
+/// Insert `block` before the first block that starts after it.
+fn insert_block(tree: &mut DocumentTree, block: BlockNode) {
+    let start = block.span().map_or(0, |span| span.start);
+    let at = tree.root.children.iter().enumerate().find_map(|(s, section)| {
+        section
+            .children
+            .iter()
+            .position(|other| other.span().is_some_and(|span| span.start > start))
+            .map(|b| (s, b))
+    });
+    match at {
+        Some((s, b)) => tree.root.children[s].children.insert(b, block),
+        None => {
+            if tree.root.children.is_empty() {
+                tree.root.children.push(section_for(&block));
+            }
+            if let Some(section) = tree.root.children.last_mut() {
+                section.children.push(block);
+            }
+        }
+    }
+}
+
+/// Anchor a restored image's asset: re-anchor the working copy's asset if
+/// the splice orphaned it, otherwise bring the version's bytes back from
+/// the asset history.
+fn restore_asset(
+    image: &ImageNode,
+    version: &DocumentVersion,
+    assets: &mut Vec<AssetRef>,
+    history: &BTreeMap<String, Vec<u8>>,
+    report: &mut RebaseReport,
+) {
+    let name = &image.asset_ref.name;
+    let orphaned = report.orphaned_assets.iter().any(|other| other == name);
+    if let Some(asset) = assets.iter_mut().find(|asset| asset.name == *name) {
+        if orphaned {
+            asset.target_pos = image.buffer_position;
+            report.orphaned_assets.retain(|other| other != name);
+        }
+        return;
+    }
+    let bytes = version
+        .assets
+        .iter()
+        .flatten()
+        .find(|asset| asset.name == *name)
+        .and_then(|captured| {
+            history.get(&captured.hash).cloned().or_else(|| {
+                assets
+                    .iter()
+                    .find(|asset| sha256_hex(&asset.bytes) == captured.hash)
+                    .map(|asset| asset.bytes.clone())
+            })
+        })
+        .or_else(|| Some(image.asset_ref.bytes.clone()).filter(|bytes| !bytes.is_empty()));
+    match bytes {
+        Some(bytes) => assets.push(AssetRef {
+            name: name.clone(),
+            target_pos: image.buffer_position,
+            alt: image.alt.clone(),
+            size: image.size,
+            bytes,
+        }),
+        None => log::warn!("bytes of asset {} are missing", name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::model::piece_table::PieceTableContent;
+    use crate::model::version::DiffLine;
+
+    fn hunk(old_start: usize, new_start: usize, lines: &[(DiffLineKind, &str)]) -> DiffHunk {
+        DiffHunk {
+            header: format!("@@ -{} +{} @@", old_start, new_start),
+            old_start,
+            old_lines: lines.iter().filter(|(kind, _)| *kind != DiffLineKind::Addition).count(),
+            new_start,
+            new_lines: lines.iter().filter(|(kind, _)| *kind != DiffLineKind::Deletion).count(),
+            lines: lines
+                .iter()
+                .map(|(kind, content)| DiffLine {
+                    kind: *kind,
+                    content: content.to_string(),
+                    old_line: None,
+                    new_line: None,
+                    spans: vec![],
+                })
+                .collect(),
+        }
+    }
+
+    fn tree(blocks: &[(&str, &str, usize, usize)]) -> DocumentTree {
+        let children: Vec<_> = blocks
+            .iter()
+            .map(|(id, kind, start, end)| {
+                let mut block = json!({"id": id, "type": kind, "textRange": {"start": start, "end": end}, "marks": []});
+                if *kind == "heading" {
+                    block["level"] = json!(1);
+                }
+                block
+            })
+            .collect();
+        serde_json::from_value(json!({"version": 2, "root": {"children": [{"id": "s1", "type": "section", "children": children}]}}))
+            .unwrap()
+    }
+
+    fn splice(text: &str, replacements: &[Replacement]) -> String {
+        let mut text = text.to_string();
+        for replacement in replacements.iter().rev() {
+            let range = byte_offset(&text, replacement.pos)..byte_offset(&text, replacement.pos + replacement.len);
+            text.replace_range(range, &replacement.text);
+        }
+        text
+    }
+
+    fn payload(text: &str, tree: DocumentTree) -> DocumentPayload {
+        DocumentPayload {
+            base_text: text.to_string(),
+            chunks: vec![PieceChunk::original(text)],
+            metadata: Default::default(),
+            versions: vec![],
+            assets: vec![],
+            document_tree: Some(tree),
+            operations: None,
+            branches: Default::default(),
+            asset_history: BTreeMap::new(),
+        }
+    }
+
+    fn spans(tree: &DocumentTree) -> Vec<(&str, Option<TextRange>)> {
+        tree.blocks().map(|block| (block.id(), block.span())).collect()
+    }
+
+    const VERSION: &str = "Intro\nbody\nGone\nmore\n";
+
+    fn version() -> DocumentVersion {
+        let mut version = DocumentVersion::new(1, VERSION.to_string(), None);
+        version.document_tree = Some(tree(&[
+            ("h1", "heading", 0, 5),
+            ("p1", "paragraph", 6, 10),
+            ("h2", "heading", 11, 15),
+            ("p2", "paragraph", 16, 20),
+        ]));
+        version
+    }
+
+    #[test]
+    fn hunks_restore_whichever_side_matches_the_version() {
+        use DiffLineKind::*;
+        let (version, working) = ("a\nb\nc\n", "a\nB\nc\n");
+        let from_version = hunk(1, 1, &[(Context, "a"), (Deletion, "b"), (Addition, "B"), (Context, "c")]);
+        let to_version = hunk(1, 1, &[(Context, "a"), (Deletion, "B"), (Addition, "b"), (Context, "c")]);
+        for hunk in [from_version, to_version] {
+            let replacements = plan_hunks(&[hunk], version, working).unwrap();
+            assert_eq!(replacements.len(), 1);
+            assert_eq!((replacements[0].pos, replacements[0].len, replacements[0].text.as_str()), (2, 2, "b\n"));
+            assert_eq!(splice(working, &replacements), version);
+        }
+
+        let unrelated = hunk(1, 1, &[(Deletion, "x"), (Addition, "y")]);
+        assert!(matches!(plan_hunks(&[unrelated], version, working), Err(PartialRestoreError::HunkMismatch(_))));
+    }
+
+    #[test]
+    fn hunks_restore_a_last_line_without_newline() {
+        use DiffLineKind::*;
+        let replacements = plan_hunks(&[hunk(1, 1, &[(Context, "a"), (Deletion, "b"), (Addition, "c")])], "a\nb", "a\nc").unwrap();
+        assert_eq!(splice("a\nc", &replacements), "a\nb");
+        assert_eq!(replacements[0].source, TextRange::new(2, 3));
+    }
+
+    #[test]
+    fn region_replacements_leave_unchanged_lines_alone() {
+        let working = "one\ntwo\nthree\nfour\n";
+        let version = "one\n2\nthree\n4\n";
+        let replacements = region_replacements(working, TextRange::new(0, 19), version, TextRange::new(0, 14));
+        let parts: Vec<_> = replacements.iter().map(|r| (r.pos, r.len, r.text.as_str(), r.source)).collect();
+        assert_eq!(parts, vec![(4, 4, "2\n", TextRange::new(4, 6)), (14, 5, "4\n", TextRange::new(12, 14))]);
+        assert_eq!(splice(working, &replacements), version);
+    }
+
+    #[test]
+    fn deleted_section_comes_back_after_its_predecessor() {
+        let version = version();
+        let working = "Intro\nbody\nOutro\n";
+        let working_tree = tree(&[("h1", "heading", 0, 5), ("p1", "paragraph", 6, 10), ("h3", "heading", 11, 16)]);
+        let selection = RestoreSelection::Section { node_id: "h2".to_string() };
+        let replacements = plan(&selection, &version, working, Some(&working_tree)).unwrap();
+        assert_eq!(splice(working, &replacements), "Intro\nbody\nGone\nmore\nOutro\n");
+
+        let mut payload = payload(working, working_tree);
+        apply(&mut payload, &version, &replacements, &RebaseOptions::default());
+        let content = PieceTableContent { base_text: payload.base_text.clone(), chunks: payload.chunks.clone() };
+        assert_eq!(content.to_text(), "Intro\nbody\nGone\nmore\nOutro\n");
+        assert_eq!(
+            spans(payload.document_tree.as_ref().unwrap()),
+            vec![
+                ("h1", Some(TextRange::new(0, 5))),
+                ("p1", Some(TextRange::new(6, 10))),
+                ("h2", Some(TextRange::new(11, 15))),
+                ("p2", Some(TextRange::new(16, 20))),
+                ("h3", Some(TextRange::new(21, 26))),
+            ]
+        );
+    }
+
+    #[test]
+    fn section_restored_at_the_end_gets_a_leading_newline() {
+        let version = version();
+        let working = "Intro\nbody";
+        let working_tree = tree(&[("h1", "heading", 0, 5), ("p1", "paragraph", 6, 10)]);
+        let selection = RestoreSelection::Section { node_id: "h2".to_string() };
+        let replacements = plan(&selection, &version, working, Some(&working_tree)).unwrap();
+        assert_eq!(replacements[0].text, "\nGone\nmore");
+        assert_eq!(replacements[0].lead, 1);
+
+        let mut payload = payload(working, working_tree);
+        apply(&mut payload, &version, &replacements, &RebaseOptions::default());
+        let content = PieceTableContent { base_text: payload.base_text.clone(), chunks: payload.chunks.clone() };
+        assert_eq!(content.to_text(), "Intro\nbody\nGone\nmore");
+        let restored = spans(payload.document_tree.as_ref().unwrap());
+        assert_eq!(restored[2..], [("h2", Some(TextRange::new(11, 15))), ("p2", Some(TextRange::new(16, 20)))]);
+    }
+
+    #[test]
+    fn section_in_place_is_replaced_line_by_line() {
+        let version = version();
+        let working = "Intro\nbody\nGone\nless\n";
+        let working_tree = tree(&[
+            ("h1", "heading", 0, 5),
+            ("p1", "paragraph", 6, 10),
+            ("h2", "heading", 11, 15),
+            ("p2", "paragraph", 16, 20),
+        ]);
+        let selection = RestoreSelection::Section { node_id: "h2".to_string() };
+        let replacements = plan(&selection, &version, working, Some(&working_tree)).unwrap();
+        assert_eq!(replacements.len(), 1);
+        assert_eq!((replacements[0].pos, replacements[0].len), (16, 5));
+        assert_eq!(splice(working, &replacements), VERSION);
+    }
+}
@@ -1,6 +1,9 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// One piece-table entry. `offset`, `len` and `pos` are UTF-16 code unit
+/// offsets, as in the frontend `TextBuffer`, so that chunks written by
+/// either side describe the same text.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PieceChunk {
     #[serde(rename = "type")]
@@ -12,7 +15,21 @@ pub struct PieceChunk {
     pub data: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl PieceChunk {
+    /// The chunk covering all of `base_text`.
+    pub fn original(base_text: &str) -> Self {
+        Self {
+            kind: ChunkType::Original,
+            offset: Some(0),
+            len: Some(utf16_len(base_text)),
+            source: Some("baseText".to_string()),
+            pos: None,
+            data: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ChunkType {
     Original,
@@ -20,7 +37,7 @@ pub enum ChunkType {
     Delete,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PieceTableContent {
     pub base_text: String,
@@ -28,22 +45,25 @@ pub struct PieceTableContent {
 }
 
 impl PieceTableContent {
+    /// Rebuild the full text by applying all chunks in order.
+    /// Chunk positions are UTF-16 code unit offsets, matching the frontend `TextBuffer`.
     pub fn to_text(&self) -> String {
         let mut text = self.base_text.clone();
         for chunk in &self.chunks {
             match chunk.kind {
                 ChunkType::Insert => {
                     if let (Some(pos), Some(data)) = (chunk.pos, chunk.data.as_ref()) {
-                        if pos <= text.len() {
-                            text.insert_str(pos, data);
+                        if pos <= utf16_len(&text) {
+                            text.insert_str(byte_offset(&text, pos), data);
                         }
                     }
                 }
                 ChunkType::Delete => {
                     if let (Some(pos), Some(len)) = (chunk.pos, chunk.len) {
-                        let end = (pos + len).min(text.len());
-                        if pos < end {
-                            text.replace_range(pos..end, "");
+                        let start = byte_offset(&text, pos);
+                        let end = byte_offset(&text, pos.saturating_add(len));
+                        if start < end {
+                            text.replace_range(start..end, "");
                         }
                     }
                 }
@@ -53,3 +73,21 @@ impl PieceTableContent {
         text
     }
 }
+
+/// Length of `text` in UTF-16 code units (the unit used by buffer offsets).
+pub fn utf16_len(text: &str) -> usize {
+    text.chars().map(char::len_utf16).sum()
+}
+
+/// Convert a UTF-16 offset into a byte offset, clamped to the end of `text`.
+/// Offsets that fall inside a surrogate pair round down to the char boundary.
+pub fn byte_offset(text: &str, pos: usize) -> usize {
+    let mut units = 0;
+    for (idx, ch) in text.char_indices() {
+        if units + ch.len_utf16() > pos {
+            return idx;
+        }
+        units += ch.len_utf16();
+    }
+    text.len()
+}
@@ -0,0 +1,314 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::piece_table::{utf16_len, ChunkType, PieceChunk};
+use crate::storage::zip_container::{AssetRef, MetadataRange};
+
+/// A single buffer edit, in UTF-16 code units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Edit {
+    Insert { pos: usize, len: usize },
+    Delete { pos: usize, len: usize },
+}
+
+/// How a range reacts to edits at or inside its boundaries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Stickiness {
+    /// Text inserted at either boundary becomes part of the range
+    Expand,
+    /// Text inserted at a boundary stays outside the range
+    #[default]
+    DontExpand,
+    /// Any edit inside the range collapses it to an empty range at the edit
+    Collapse,
+}
+
+/// What to do with ranges that end up empty after rebasing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EmptyRangePolicy {
+    /// Remove them from the metadata
+    #[default]
+    Drop,
+    /// Keep them and report their indices
+    Flag,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RebaseOptions {
+    /// Stickiness for ranges without a more specific rule
+    #[serde(default)]
+    pub stickiness: Stickiness,
+    /// Per-`MetadataRange.type` overrides
+    #[serde(default)]
+    pub by_type: BTreeMap<String, Stickiness>,
+    #[serde(default)]
+    pub empty: EmptyRangePolicy,
+}
+
+impl RebaseOptions {
+    fn stickiness_for(&self, range: &MetadataRange) -> Stickiness {
+        range
+            .r#type
+            .as_ref()
+            .and_then(|kind| self.by_type.get(kind))
+            .copied()
+            .unwrap_or(self.stickiness)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RebaseReport {
+    /// Ranges removed because they became empty
+    pub dropped: Vec<MetadataRange>,
+    /// Indices (into the rebased list) of ranges that are empty or were collapsed
+    pub flagged: Vec<usize>,
+    /// Names of assets whose anchor was deleted
+    pub orphaned_assets: Vec<String>,
+}
+
+/// Translate piece-table chunks into buffer edits, in application order.
+pub fn edits_from_chunks(chunks: &[PieceChunk]) -> Vec<Edit> {
+    chunks
+        .iter()
+        .filter_map(|chunk| match chunk.kind {
+            ChunkType::Insert => Some(Edit::Insert {
+                pos: chunk.pos?,
+                len: utf16_len(chunk.data.as_deref()?),
+            }),
+            ChunkType::Delete => Some(Edit::Delete {
+                pos: chunk.pos?,
+                len: chunk.len?,
+            }),
+            ChunkType::Original => None,
+        })
+        .filter(|edit| match edit {
+            Edit::Insert { len, .. } | Edit::Delete { len, .. } => *len > 0,
+        })
+        .collect()
+}
+
+/// Map every range through `edits`, applying stickiness and the empty-range policy.
+pub fn rebase_ranges(ranges: &mut Vec<MetadataRange>, edits: &[Edit], options: &RebaseOptions) -> RebaseReport {
+    let mut report = RebaseReport::default();
+    let mut collapsed = vec![false; ranges.len()];
+    for (range, collapsed) in ranges.iter_mut().zip(collapsed.iter_mut()) {
+        let stickiness = options.stickiness_for(range);
+        let (mut start, mut end) = (range.start.min(range.end), range.end.max(range.start));
+        for edit in edits {
+            if map_range(&mut start, &mut end, *edit, stickiness) {
+                *collapsed = true;
+            }
+        }
+        range.start = start;
+        range.end = end;
+    }
+
+    let mut kept = Vec::with_capacity(ranges.len());
+    for (range, collapsed) in ranges.drain(..).zip(collapsed) {
+        let empty = range.start >= range.end;
+        if empty && options.empty == EmptyRangePolicy::Drop {
+            report.dropped.push(range);
+            continue;
+        }
+        if empty || collapsed {
+            report.flagged.push(kept.len());
+        }
+        kept.push(range);
+    }
+    *ranges = kept;
+    report
+}
+
+/// Map asset anchors through `edits`. Anchors behave like the U+FFFC sentinel
+/// they point at: insertions at the anchor push it forward, and deleting the
+/// anchor collapses it onto the deletion point and reports the asset.
+pub fn rebase_assets(assets: &mut [AssetRef], edits: &[Edit]) -> Vec<String> {
+    let mut orphaned = Vec::new();
+    for asset in assets.iter_mut() {
+        let mut pos = asset.target_pos;
+        let mut deleted = false;
+        for edit in edits {
+            match *edit {
+                Edit::Insert { pos: at, len } if at <= pos => pos += len,
+                Edit::Delete { pos: at, len } if pos >= at + len => pos -= len,
+                Edit::Delete { pos: at, .. } if pos >= at => {
+                    pos = at;
+                    deleted = true;
+                }
+                _ => {}
+            }
+        }
+        asset.target_pos = pos;
+        if deleted {
+            orphaned.push(asset.name.clone());
+        }
+    }
+    orphaned
+}
+
+/// Rebase metadata ranges and asset anchors through piece-table chunks.
+pub fn rebase_through_chunks(
+    ranges: &mut Vec<MetadataRange>,
+    assets: &mut [AssetRef],
+    chunks: &[PieceChunk],
+    options: &RebaseOptions,
+) -> RebaseReport {
+    let edits = edits_from_chunks(chunks);
+    let mut report = rebase_ranges(ranges, &edits, options);
+    report.orphaned_assets = rebase_assets(assets, &edits);
+    report
+}
+
+/// Map `[start, end)` through one edit. Returns true if a `Collapse` range was collapsed.
+fn map_range(start: &mut usize, end: &mut usize, edit: Edit, stickiness: Stickiness) -> bool {
+    let expand = stickiness == Stickiness::Expand;
+    match edit {
+        Edit::Insert { pos, len } => {
+            if stickiness == Stickiness::Collapse && pos > *start && pos < *end {
+                *start = pos;
+                *end = pos;
+                return true;
+            }
+            if pos < *start || (pos == *start && !expand && *start < *end) {
+                *start += len;
+                *end += len;
+            } else if pos < *end || (pos == *end && expand) {
+                *end += len;
+            }
+            false
+        }
+        Edit::Delete { pos, len } => {
+            let del_end = pos + len;
+            if stickiness == Stickiness::Collapse && pos < *end && del_end > *start {
+                *start = pos;
+                *end = pos;
+                return true;
+            }
+            let map = |p: usize| {
+                if p <= pos {
+                    p
+                } else if p >= del_end {
+                    p - len
+                } else {
+                    pos
+                }
+            };
+            *start = map(*start);
+            *end = map(*end);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapped(range: (usize, usize), edit: Edit, stickiness: Stickiness) -> (usize, usize, bool) {
+        let (mut start, mut end) = range;
+        let collapsed = map_range(&mut start, &mut end, edit, stickiness);
+        (start, end, collapsed)
+    }
+
+    fn range(start: usize, end: usize, kind: Option<&str>) -> MetadataRange {
+        MetadataRange {
+            start,
+            end,
+            attrs: BTreeMap::new(),
+            r#type: kind.map(str::to_string),
+            level: None,
+        }
+    }
+
+    fn asset(name: &str, target_pos: usize) -> AssetRef {
+        AssetRef {
+            name: name.to_string(),
+            target_pos,
+            alt: String::new(),
+            size: (1, 1),
+            bytes: vec![],
+        }
+    }
+
+    #[test]
+    fn inserts_at_the_boundaries_follow_stickiness() {
+        use Stickiness::*;
+        let at_start = Edit::Insert { pos: 2, len: 3 };
+        assert_eq!(mapped((2, 5), at_start, Expand), (2, 8, false));
+        assert_eq!(mapped((2, 5), at_start, DontExpand), (5, 8, false));
+        assert_eq!(mapped((2, 5), at_start, Collapse), (5, 8, false));
+
+        let at_end = Edit::Insert { pos: 5, len: 3 };
+        assert_eq!(mapped((2, 5), at_end, Expand), (2, 8, false));
+        assert_eq!(mapped((2, 5), at_end, DontExpand), (2, 5, false));
+        assert_eq!(mapped((2, 5), at_end, Collapse), (2, 5, false));
+
+        let inside = Edit::Insert { pos: 3, len: 3 };
+        assert_eq!(mapped((2, 5), inside, Expand), (2, 8, false));
+        assert_eq!(mapped((2, 5), inside, DontExpand), (2, 8, false));
+        assert_eq!(mapped((2, 5), inside, Collapse), (3, 3, true));
+    }
+
+    #[test]
+    fn empty_anchors_only_grow_when_expanding() {
+        use Stickiness::*;
+        let at_anchor = Edit::Insert { pos: 4, len: 2 };
+        assert_eq!(mapped((4, 4), at_anchor, Expand), (4, 6, false));
+        assert_eq!(mapped((4, 4), at_anchor, DontExpand), (4, 4, false));
+        assert_eq!(mapped((4, 4), at_anchor, Collapse), (4, 4, false));
+        for stickiness in [Expand, DontExpand, Collapse] {
+            assert_eq!(mapped((4, 4), Edit::Insert { pos: 3, len: 2 }, stickiness), (6, 6, false));
+        }
+    }
+
+    #[test]
+    fn deletes_shrink_or_collapse_ranges() {
+        use Stickiness::*;
+        let inside = Edit::Delete { pos: 3, len: 1 };
+        assert_eq!(mapped((2, 5), inside, Expand), (2, 4, false));
+        assert_eq!(mapped((2, 5), inside, DontExpand), (2, 4, false));
+        assert_eq!(mapped((2, 5), inside, Collapse), (3, 3, true));
+
+        // deletions that only touch a boundary leave a collapsing range alone
+        assert_eq!(mapped((2, 5), Edit::Delete { pos: 0, len: 2 }, Collapse), (0, 3, false));
+        assert_eq!(mapped((2, 5), Edit::Delete { pos: 5, len: 2 }, Collapse), (2, 5, false));
+        assert_eq!(mapped((2, 5), Edit::Delete { pos: 1, len: 5 }, DontExpand), (1, 1, false));
+    }
+
+    #[test]
+    fn deleting_an_anchor_orphans_its_asset() {
+        let mut assets = vec![asset("a", 4), asset("b", 10), asset("c", 13)];
+        let edits = [Edit::Insert { pos: 4, len: 2 }, Edit::Delete { pos: 10, len: 3 }];
+        assert_eq!(rebase_assets(&mut assets, &edits), vec!["b".to_string()]);
+        let positions: Vec<_> = assets.iter().map(|asset| asset.target_pos).collect();
+        assert_eq!(positions, vec![6, 10, 12]);
+    }
+
+    #[test]
+    fn empty_ranges_are_dropped_or_flagged() {
+        let ranges = vec![range(0, 2, None), range(4, 6, None), range(8, 10, Some("comment"))];
+        let edits = [Edit::Insert { pos: 9, len: 1 }, Edit::Delete { pos: 3, len: 4 }];
+        let mut options = RebaseOptions::default();
+        options.by_type.insert("comment".to_string(), Stickiness::Collapse);
+
+        let mut dropped = ranges.clone();
+        let report = rebase_ranges(&mut dropped, &edits, &options);
+        let bounds = |ranges: &[MetadataRange]| ranges.iter().map(|r| (r.start, r.end)).collect::<Vec<_>>();
+        assert_eq!(bounds(&dropped), vec![(0, 2)]);
+        assert_eq!(bounds(&report.dropped), vec![(3, 3), (5, 5)]);
+        assert!(report.flagged.is_empty());
+
+        options.empty = EmptyRangePolicy::Flag;
+        let mut flagged = ranges;
+        let report = rebase_ranges(&mut flagged, &edits, &options);
+        assert_eq!(bounds(&flagged), vec![(0, 2), (3, 3), (5, 5)]);
+        assert!(report.dropped.is_empty());
+        assert_eq!(report.flagged, vec![1, 2]);
+    }
+}
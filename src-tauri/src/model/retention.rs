@@ -0,0 +1,178 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Datelike, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::version::VersionSummary;
+
+/// Which versions to keep as history grows. Configured per document under
+/// `metadata.custom.retention`; omitted fields take their defaults.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RetentionPolicy {
+    /// Keep every version younger than this
+    pub keep_all_hours: u32,
+    /// After that, keep the newest version of each day for this many days
+    pub daily_days: u32,
+    /// After that, keep the newest version of each week for this many
+    /// weeks; `None` keeps one per week indefinitely
+    pub weekly_weeks: Option<u32>,
+    pub keep_pinned: bool,
+    pub keep_labeled: bool,
+    /// Automatic snapshots older than this are pruned even where the day
+    /// and week tiers would keep them
+    pub auto_max_age_hours: Option<u32>,
+    /// Cap on the stored size of all versions. The oldest unprotected
+    /// versions are pruned until the history fits.
+    pub max_storage_mb: Option<f64>,
+    /// Trashed versions are purged this many days after they were deleted;
+    /// `None` keeps them until the trash is emptied
+    pub trash_days: Option<u32>,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            keep_all_hours: 24,
+            daily_days: 30,
+            weekly_weeks: None,
+            keep_pinned: true,
+            keep_labeled: true,
+            auto_max_age_hours: None,
+            max_storage_mb: None,
+            trash_days: Some(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PruneReason {
+    /// Superseded by a newer version in the same day or week, or older
+    /// than the weekly or automatic snapshot window
+    Age,
+    /// Dropped to bring the history under `maxStorageMb`
+    StorageCap,
+    /// In the trash for longer than `trashDays`
+    TrashExpired,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PrunedVersion {
+    #[serde(flatten)]
+    pub version: VersionSummary,
+    pub reason: PruneReason,
+    /// Stored size of the version's entry in bytes
+    pub size: u64,
+}
+
+/// Decide which versions `policy` prunes. `protected` versions (such as
+/// branch heads) and the newest version are always kept; `sizes` maps
+/// version IDs to their stored size. The result is oldest first.
+pub fn plan_pruning(
+    policy: &RetentionPolicy,
+    versions: &[VersionSummary],
+    sizes: &HashMap<String, u64>,
+    protected: &HashSet<String>,
+    now: DateTime<Utc>,
+) -> Vec<PrunedVersion> {
+    let mut newest_first: Vec<&VersionSummary> = versions.iter().collect();
+    newest_first.sort_by_key(|v| std::cmp::Reverse(v.created_at));
+
+    let keeps = |version: &VersionSummary| {
+        protected.contains(&version.id)
+            || (policy.keep_pinned && version.pinned)
+            || (policy.keep_labeled && version.label.is_some())
+    };
+    let keep_all = Duration::hours(i64::from(policy.keep_all_hours));
+    let daily_until = keep_all + Duration::days(i64::from(policy.daily_days));
+    let weekly_until = policy
+        .weekly_weeks
+        .map(|weeks| daily_until + Duration::weeks(i64::from(weeks)));
+    let auto_until = policy
+        .auto_max_age_hours
+        .map(|hours| Duration::hours(i64::from(hours)));
+
+    let mut buckets = HashSet::new();
+    let mut kept: Vec<&VersionSummary> = Vec::new();
+    let mut pruned: Vec<PrunedVersion> = Vec::new();
+    for (idx, version) in newest_first.iter().copied().enumerate() {
+        let age = now - version.created_at;
+        let keep = if idx == 0 || keeps(version) {
+            true
+        } else if version.auto && auto_until.is_some_and(|until| age >= until) {
+            false
+        } else if age < keep_all {
+            true
+        } else if age < daily_until {
+            buckets.insert(("day", version.created_at.date_naive().num_days_from_ce()))
+        } else if weekly_until.is_some_and(|until| age >= until) {
+            false
+        } else {
+            let week = version.created_at.iso_week();
+            buckets.insert(("week", week.year() * 100 + week.week() as i32))
+        };
+        if keep {
+            kept.push(version);
+        } else {
+            pruned.push(pruned_version(version, PruneReason::Age, sizes));
+        }
+    }
+
+    if let Some(cap_mb) = policy.max_storage_mb {
+        let cap = (cap_mb.max(0.0) * 1024.0 * 1024.0) as u64;
+        let mut total: u64 = kept.iter().map(|v| sizes.get(&v.id).copied().unwrap_or(0)).sum();
+        // `kept` is newest first; the newest version itself is never dropped.
+        for version in kept.iter().skip(1).rev() {
+            if total <= cap {
+                break;
+            }
+            if keeps(version) {
+                continue;
+            }
+            let entry = pruned_version(version, PruneReason::StorageCap, sizes);
+            total = total.saturating_sub(entry.size);
+            pruned.push(entry);
+        }
+    }
+
+    pruned.sort_by_key(|v| v.version.created_at);
+    pruned
+}
+
+/// When a version trashed at `deleted_at` is due to be purged, if ever.
+pub fn trash_expiry(policy: &RetentionPolicy, deleted_at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    policy
+        .trash_days
+        .map(|days| deleted_at + Duration::days(i64::from(days)))
+}
+
+/// Trashed versions whose time in the trash has run out, oldest first.
+pub fn expired_trash(
+    policy: &RetentionPolicy,
+    trashed: &[VersionSummary],
+    sizes: &HashMap<String, u64>,
+    now: DateTime<Utc>,
+) -> Vec<PrunedVersion> {
+    let mut expired: Vec<PrunedVersion> = trashed
+        .iter()
+        .filter(|version| {
+            version
+                .deleted_at
+                .and_then(|deleted_at| trash_expiry(policy, deleted_at))
+                .is_some_and(|expiry| expiry <= now)
+        })
+        .map(|version| pruned_version(version, PruneReason::TrashExpired, sizes))
+        .collect();
+    expired.sort_by_key(|v| v.version.created_at);
+    expired
+}
+
+fn pruned_version(version: &VersionSummary, reason: PruneReason, sizes: &HashMap<String, u64>) -> PrunedVersion {
+    PrunedVersion {
+        version: version.clone(),
+        reason,
+        size: sizes.get(&version.id).copied().unwrap_or(0),
+    }
+}
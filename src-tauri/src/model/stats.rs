@@ -0,0 +1,210 @@
+use chrono::{DateTime, Datelike, Duration, TimeZone, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use similar::{ChangeTag, TextDiff};
+
+use super::document_tree::{BlockNode, DocumentTree, TextBlock};
+
+/// Writing velocity is measured over this many days before the newest version.
+const VELOCITY_WINDOW_DAYS: i64 = 14;
+
+/// Size of a text.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TextStats {
+    pub words: usize,
+    /// Unicode scalar values, not bytes
+    pub characters: usize,
+    pub characters_no_spaces: usize,
+    /// Paragraph blocks of the tree, or non-blank lines without one
+    pub paragraphs: usize,
+    /// Heading blocks of the tree; 0 without one
+    pub headings: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsInterval {
+    Hour,
+    #[default]
+    Day,
+    Week,
+}
+
+/// Statistics of one version.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionStats {
+    pub version_id: String,
+    pub version_number: u32,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub author: Option<String>,
+    #[serde(flatten)]
+    pub stats: TextStats,
+    /// Words added since the previous version (its first parent)
+    pub words_added: usize,
+    /// Words removed since the previous version
+    pub words_removed: usize,
+}
+
+/// Writing activity within one interval.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimelineBucket {
+    pub start: DateTime<Utc>,
+    pub versions: usize,
+    pub words_added: usize,
+    pub words_removed: usize,
+    /// Net words written in the interval: the writing velocity
+    pub net_words: i64,
+    /// Words in the newest version of the interval
+    pub words: usize,
+}
+
+/// Progress toward a target length.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TargetProgress {
+    pub target_words: usize,
+    pub current_words: usize,
+    pub remaining_words: usize,
+    /// 0.0 to 1.0, capped once the target is reached
+    pub progress: f64,
+    /// When the target is reached at the current velocity; absent if it is
+    /// already reached or the document is not growing
+    pub projected_completion: Option<DateTime<Utc>>,
+}
+
+pub fn text_stats(content: &str, tree: Option<&DocumentTree>) -> TextStats {
+    let (mut paragraphs, mut headings) = (0, 0);
+    match tree {
+        Some(tree) => {
+            let is_heading = |block: &TextBlock| matches!(block, TextBlock::Heading(_));
+            for block in tree.blocks() {
+                let kinds: Vec<bool> = match block {
+                    BlockNode::Paragraph(_) => vec![false],
+                    BlockNode::Heading(_) => vec![true],
+                    BlockNode::Blockquote(q) => q.children.iter().map(is_heading).collect(),
+                    BlockNode::List(l) => l.items.iter().map(|item| is_heading(&item.content)).collect(),
+                    BlockNode::Image(_) | BlockNode::Table(_) => Vec::new(),
+                };
+                for heading in kinds {
+                    if heading {
+                        headings += 1;
+                    } else {
+                        paragraphs += 1;
+                    }
+                }
+            }
+        }
+        None => paragraphs = content.lines().filter(|line| !line.trim().is_empty()).count(),
+    }
+    TextStats {
+        words: count_words(content),
+        characters: content.chars().count(),
+        characters_no_spaces: content.chars().filter(|c| !c.is_whitespace()).count(),
+        paragraphs,
+        headings,
+    }
+}
+
+/// Words added and removed going from `old` to `new`.
+pub fn word_changes(old: &str, new: &str) -> (usize, usize) {
+    let diff = TextDiff::from_words(old, new);
+    let mut added = 0;
+    let mut removed = 0;
+    for change in diff.iter_all_changes() {
+        match change.tag() {
+            ChangeTag::Insert => added += count_words(change.value()),
+            ChangeTag::Delete => removed += count_words(change.value()),
+            ChangeTag::Equal => {}
+        }
+    }
+    (added, removed)
+}
+
+/// Group versions, oldest first, into intervals. Intervals without
+/// versions are left out.
+pub fn timeline(versions: &[VersionStats], interval: StatsInterval) -> Vec<TimelineBucket> {
+    let mut buckets: Vec<TimelineBucket> = Vec::new();
+    for version in versions {
+        let start = bucket_start(version.created_at, interval);
+        if buckets.last().is_none_or(|bucket| bucket.start != start) {
+            buckets.push(TimelineBucket {
+                start,
+                versions: 0,
+                words_added: 0,
+                words_removed: 0,
+                net_words: 0,
+                words: 0,
+            });
+        }
+        if let Some(bucket) = buckets.last_mut() {
+            bucket.versions += 1;
+            bucket.words_added += version.words_added;
+            bucket.words_removed += version.words_removed;
+            bucket.net_words += version.words_added as i64 - version.words_removed as i64;
+            bucket.words = version.stats.words;
+        }
+    }
+    buckets
+}
+
+/// Net words per day over the last two weeks of a history sorted oldest
+/// first.
+pub fn velocity(versions: &[VersionStats]) -> f64 {
+    let Some(last) = versions.last() else {
+        return 0.0;
+    };
+    let since = last.created_at - Duration::days(VELOCITY_WINDOW_DAYS);
+    let Some(first) = versions.iter().find(|v| v.created_at >= since) else {
+        return 0.0;
+    };
+    let days = (last.created_at - first.created_at).num_seconds() as f64 / 86_400.0;
+    if days <= 0.0 {
+        return 0.0;
+    }
+    // Count the first version's own edits too, since they fall in the window.
+    let net = last.stats.words as f64 - first.stats.words as f64 + first.words_added as f64
+        - first.words_removed as f64;
+    net / days
+}
+
+pub fn target_progress(target_words: usize, current_words: usize, velocity: f64, now: DateTime<Utc>) -> TargetProgress {
+    let remaining_words = target_words.saturating_sub(current_words);
+    let projected_completion = (remaining_words > 0 && velocity > 0.0)
+        .then(|| now + Duration::seconds((remaining_words as f64 / velocity * 86_400.0) as i64));
+    TargetProgress {
+        target_words,
+        current_words,
+        remaining_words,
+        progress: if target_words == 0 {
+            1.0
+        } else {
+            (current_words as f64 / target_words as f64).min(1.0)
+        },
+        projected_completion,
+    }
+}
+
+// ============================================================================
+// Helper Functions
+// ============================================================================
+
+/// Whitespace-separated tokens containing a letter or digit.
+fn count_words(text: &str) -> usize {
+    text.split_whitespace()
+        .filter(|word| word.chars().any(char::is_alphanumeric))
+        .count()
+}
+
+fn bucket_start(at: DateTime<Utc>, interval: StatsInterval) -> DateTime<Utc> {
+    let date = at.date_naive();
+    let naive = match interval {
+        StatsInterval::Hour => date.and_hms_opt(at.hour(), 0, 0),
+        StatsInterval::Day => date.and_hms_opt(0, 0, 0),
+        StatsInterval::Week => (date - Duration::days(i64::from(date.weekday().num_days_from_monday())))
+            .and_hms_opt(0, 0, 0),
+    };
+    naive.map_or(at, |naive| Utc.from_utc_datetime(&naive))
+}
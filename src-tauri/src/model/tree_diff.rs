@@ -0,0 +1,478 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use similar::{DiffOp, TextDiff};
+
+use super::document_tree::{BlockNode, DocumentTree, InlineMark, MarkAttrs, NodeId, SectionNode, TextBlock, TextRange};
+use super::piece_table::{byte_offset, utf16_len};
+use super::rebase::{rebase_ranges, Edit, RebaseOptions};
+use super::version::DocumentVersion;
+use crate::storage::zip_container::MetadataRange;
+
+/// Blocks without a common ID are paired when their text is at least this similar.
+const MIN_SIMILARITY: f32 = 0.6;
+
+/// Position of a top-level block in one of the compared trees.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockRef {
+    pub id: NodeId,
+    #[serde(rename = "type")]
+    pub block_type: String,
+    pub section_id: NodeId,
+    /// Index among all top-level blocks of the tree
+    pub index: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TextChange {
+    pub old: String,
+    pub new: String,
+    pub similarity: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttributeChange {
+    pub name: String,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+/// Inline mark present on only one side. Offsets are relative to the block
+/// in the tree the mark belongs to; container blocks count their children
+/// as joined by newlines.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "change", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum MarkChange {
+    Added { start: usize, end: usize, attrs: MarkAttrs },
+    Removed { start: usize, end: usize, attrs: MarkAttrs },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum TreeChange {
+    Inserted {
+        block: BlockRef,
+        text: String,
+    },
+    Deleted {
+        block: BlockRef,
+        text: String,
+    },
+    /// Reordered relative to the other blocks, or moved to another section
+    Moved {
+        from: BlockRef,
+        to: BlockRef,
+    },
+    Modified {
+        from: BlockRef,
+        to: BlockRef,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        text: Option<TextChange>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        attributes: Vec<AttributeChange>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        marks: Vec<MarkChange>,
+    },
+    /// Layout of a section present on both sides changed
+    SectionModified {
+        id: NodeId,
+        attributes: Vec<AttributeChange>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TreeDiff {
+    pub from_version_id: String,
+    pub to_version_id: String,
+    /// Changes in document order
+    pub changes: Vec<TreeChange>,
+    /// Blocks matched on both sides without any change
+    pub unchanged: usize,
+}
+
+/// Compare the document trees of two versions block by block. Blocks are
+/// matched by ID, then by identical text, then by text similarity; matched
+/// blocks are checked for moves and for text, attribute and mark changes.
+pub fn diff_trees(from: &DocumentVersion, to: &DocumentVersion) -> TreeDiff {
+    let empty = DocumentTree::default();
+    let old_tree = from.document_tree.as_ref().unwrap_or(&empty);
+    let new_tree = to.document_tree.as_ref().unwrap_or(&empty);
+    let old = entries(old_tree, &from.content, &asset_hashes(from));
+    let new = entries(new_tree, &to.content, &asset_hashes(to));
+    let pairs = match_entries(&old, &new);
+
+    let mut changes: Vec<(usize, TreeChange)> = Vec::new();
+    for section in &old_tree.root.children {
+        if let Some(other) = new_tree.root.children.iter().find(|other| other.id == section.id) {
+            let attributes = attribute_changes(&section_attrs(section), &section_attrs(other));
+            if !attributes.is_empty() {
+                let index = new.iter().position(|e| e.block.section_id == other.id).unwrap_or(0);
+                changes.push((index, TreeChange::SectionModified { id: other.id.clone(), attributes }));
+            }
+        }
+    }
+
+    let mut old_matched = vec![false; old.len()];
+    let mut new_matched = vec![false; new.len()];
+    for &(o, n) in &pairs {
+        old_matched[o] = true;
+        new_matched[n] = true;
+    }
+    for (o, entry) in old.iter().enumerate().filter(|(o, _)| !old_matched[*o]) {
+        changes.push((
+            o,
+            TreeChange::Deleted {
+                block: entry.block.clone(),
+                text: entry.text.clone(),
+            },
+        ));
+    }
+    for (n, entry) in new.iter().enumerate().filter(|(n, _)| !new_matched[*n]) {
+        changes.push((
+            n,
+            TreeChange::Inserted {
+                block: entry.block.clone(),
+                text: entry.text.clone(),
+            },
+        ));
+    }
+
+    let in_order = increasing_subsequence(&pairs);
+    let mut unchanged = 0;
+    for (idx, &(o, n)) in pairs.iter().enumerate() {
+        let (a, b) = (&old[o], &new[n]);
+        let moved = !in_order[idx] || a.block.section_id != b.block.section_id;
+        if moved {
+            changes.push((
+                n,
+                TreeChange::Moved {
+                    from: a.block.clone(),
+                    to: b.block.clone(),
+                },
+            ));
+        }
+        let text = (a.text != b.text).then(|| TextChange {
+            old: a.text.clone(),
+            new: b.text.clone(),
+            similarity: f64::from(TextDiff::from_chars(a.text.as_str(), b.text.as_str()).ratio()),
+        });
+        let attributes = attribute_changes(&a.attrs, &b.attrs);
+        let marks = mark_changes(a, b);
+        if text.is_some() || !attributes.is_empty() || !marks.is_empty() {
+            changes.push((
+                n,
+                TreeChange::Modified {
+                    from: a.block.clone(),
+                    to: b.block.clone(),
+                    text,
+                    attributes,
+                    marks,
+                },
+            ));
+        } else if !moved {
+            unchanged += 1;
+        }
+    }
+
+    changes.sort_by_key(|(index, _)| *index);
+    TreeDiff {
+        from_version_id: from.id.clone(),
+        to_version_id: to.id.clone(),
+        changes: changes.into_iter().map(|(_, change)| change).collect(),
+        unchanged,
+    }
+}
+
+// ============================================================================
+// Helper Functions
+// ============================================================================
+
+/// A top-level block flattened for comparison.
+struct Entry {
+    block: BlockRef,
+    /// `text` for paragraphs and headings, otherwise the block type; only
+    /// blocks of the same family are paired by content
+    family: &'static str,
+    text: String,
+    attrs: BTreeMap<String, Value>,
+    marks: Vec<MetadataRange>,
+}
+
+fn entries(tree: &DocumentTree, text: &str, hashes: &HashMap<String, String>) -> Vec<Entry> {
+    // A malformed range (start after end) reads as empty rather than panicking.
+    let slice = |range: TextRange| {
+        text.get(byte_offset(text, range.start)..byte_offset(text, range.end))
+            .unwrap_or_default()
+            .to_string()
+    };
+    let mark_ranges = |marks: &[InlineMark], offset: usize| -> Vec<MetadataRange> {
+        marks
+            .iter()
+            .map(|mark| MetadataRange {
+                start: offset + mark.start,
+                end: offset + mark.end,
+                attrs: serde_json::to_value(mark.attrs.clone().unwrap_or_default())
+                    .ok()
+                    .and_then(|value| serde_json::from_value(value).ok())
+                    .unwrap_or_default(),
+                r#type: None,
+                level: None,
+            })
+            .collect()
+    };
+    // Containers compare as their children joined by newlines.
+    let joined = |children: Vec<&TextBlock>| {
+        let mut text = String::new();
+        let mut marks = Vec::new();
+        for (idx, child) in children.into_iter().enumerate() {
+            if idx > 0 {
+                text.push('\n');
+            }
+            let child_marks = match child {
+                TextBlock::Paragraph(p) => &p.marks,
+                TextBlock::Heading(h) => &h.marks,
+            };
+            marks.extend(mark_ranges(child_marks, utf16_len(&text)));
+            text.push_str(&slice(child.text_range()));
+        }
+        (text, marks)
+    };
+
+    let mut entries = Vec::new();
+    for section in &tree.root.children {
+        for block in &section.children {
+            let mut attrs = BTreeMap::new();
+            let (block_type, family, text, marks) = match block {
+                BlockNode::Paragraph(p) => ("paragraph", "text", slice(p.text_range), mark_ranges(&p.marks, 0)),
+                BlockNode::Heading(h) => {
+                    attrs.insert("level".to_string(), json!(h.level));
+                    ("heading", "text", slice(h.text_range), mark_ranges(&h.marks, 0))
+                }
+                BlockNode::Image(i) => {
+                    attrs.insert("asset".to_string(), json!(i.asset_ref.name));
+                    if let Some(hash) = hashes.get(&i.asset_ref.name) {
+                        attrs.insert("assetHash".to_string(), json!(hash));
+                    }
+                    attrs.insert("alt".to_string(), json!(i.alt));
+                    attrs.insert("size".to_string(), json!(i.size));
+                    ("image", "image", i.alt.clone(), Vec::new())
+                }
+                BlockNode::Table(t) => {
+                    attrs.insert("rows".to_string(), json!(t.rows));
+                    attrs.insert("cols".to_string(), json!(t.cols));
+                    ("table", "table", slice(t.text_range), Vec::new())
+                }
+                BlockNode::List(l) => {
+                    attrs.insert("listType".to_string(), json!(l.list_type));
+                    attrs.insert("items".to_string(), json!(l.items.len()));
+                    let (text, marks) = joined(l.items.iter().map(|item| &item.content).collect());
+                    ("list", "list", text, marks)
+                }
+                BlockNode::Blockquote(q) => {
+                    attrs.insert("children".to_string(), json!(q.children.len()));
+                    let (text, marks) = joined(q.children.iter().collect());
+                    ("blockquote", "blockquote", text, marks)
+                }
+            };
+            attrs.insert("type".to_string(), json!(block_type));
+            entries.push(Entry {
+                block: BlockRef {
+                    id: block.id().to_string(),
+                    block_type: block_type.to_string(),
+                    section_id: section.id.clone(),
+                    index: entries.len(),
+                },
+                family,
+                text,
+                attrs,
+                marks,
+            });
+        }
+    }
+    entries
+}
+
+fn asset_hashes(version: &DocumentVersion) -> HashMap<String, String> {
+    version
+        .assets
+        .iter()
+        .flatten()
+        .map(|asset| (asset.name.clone(), asset.hash.clone()))
+        .collect()
+}
+
+fn section_attrs(section: &SectionNode) -> BTreeMap<String, Value> {
+    let mut attrs = BTreeMap::new();
+    let mut insert = |name: &str, value: Option<Value>| {
+        if let Some(value) = value.filter(|value| !value.is_null()) {
+            attrs.insert(name.to_string(), value);
+        }
+    };
+    insert("margins", serde_json::to_value(section.margins).ok());
+    insert("orientation", serde_json::to_value(section.orientation).ok());
+    insert("headers", serde_json::to_value(&section.headers).ok());
+    insert("footers", serde_json::to_value(&section.footers).ok());
+    attrs
+}
+
+/// Pair old and new entries, returned in new-tree order.
+fn match_entries(old: &[Entry], new: &[Entry]) -> Vec<(usize, usize)> {
+    let mut old_taken = vec![false; old.len()];
+    let mut new_taken = vec![false; new.len()];
+    let mut pairs = Vec::new();
+    let by_id: HashMap<&str, usize> = old.iter().enumerate().map(|(o, e)| (e.block.id.as_str(), o)).collect();
+    for (n, entry) in new.iter().enumerate() {
+        if let Some(&o) = by_id.get(entry.block.id.as_str()) {
+            old_taken[o] = true;
+            new_taken[n] = true;
+            pairs.push((o, n));
+        }
+    }
+
+    let mut by_text: HashMap<(&str, &str), Vec<usize>> = HashMap::new();
+    for (o, entry) in old.iter().enumerate().rev().filter(|(o, _)| !old_taken[*o]) {
+        by_text.entry((entry.family, entry.text.as_str())).or_default().push(o);
+    }
+    for (n, entry) in new.iter().enumerate() {
+        if new_taken[n] {
+            continue;
+        }
+        if let Some(o) = by_text.get_mut(&(entry.family, entry.text.as_str())).and_then(Vec::pop) {
+            old_taken[o] = true;
+            new_taken[n] = true;
+            pairs.push((o, n));
+        }
+    }
+
+    let mut candidates = Vec::new();
+    for (o, a) in old.iter().enumerate().filter(|(o, _)| !old_taken[*o]) {
+        for (n, b) in new.iter().enumerate().filter(|(n, _)| !new_taken[*n]) {
+            let (la, lb) = (a.text.chars().count(), b.text.chars().count());
+            // The ratio can't reach the threshold when lengths differ this much.
+            let bound = 2.0 * la.min(lb) as f32 / (la + lb).max(1) as f32;
+            if a.family != b.family || bound < MIN_SIMILARITY {
+                continue;
+            }
+            let ratio = TextDiff::from_chars(a.text.as_str(), b.text.as_str()).ratio();
+            if ratio >= MIN_SIMILARITY {
+                candidates.push((ratio, o, n));
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+    for (_, o, n) in candidates {
+        if !old_taken[o] && !new_taken[n] {
+            old_taken[o] = true;
+            new_taken[n] = true;
+            pairs.push((o, n));
+        }
+    }
+
+    pairs.sort_by_key(|&(_, n)| n);
+    pairs
+}
+
+/// Which pairs (in new order) belong to the longest run that keeps its old
+/// order; the rest were moved.
+fn increasing_subsequence(pairs: &[(usize, usize)]) -> Vec<bool> {
+    let mut tails: Vec<usize> = Vec::new();
+    let mut prev = vec![None; pairs.len()];
+    for (idx, &(o, _)) in pairs.iter().enumerate() {
+        let at = tails.partition_point(|&t| pairs[t].0 < o);
+        prev[idx] = at.checked_sub(1).map(|p| tails[p]);
+        if at == tails.len() {
+            tails.push(idx);
+        } else {
+            tails[at] = idx;
+        }
+    }
+    let mut keep = vec![false; pairs.len()];
+    let mut cursor = tails.last().copied();
+    while let Some(idx) = cursor {
+        keep[idx] = true;
+        cursor = prev[idx];
+    }
+    keep
+}
+
+fn attribute_changes(old: &BTreeMap<String, Value>, new: &BTreeMap<String, Value>) -> Vec<AttributeChange> {
+    let mut names: Vec<&String> = old.keys().chain(new.keys()).collect();
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .filter(|name| old.get(*name) != new.get(*name))
+        .map(|name| AttributeChange {
+            name: name.clone(),
+            old: old.get(name).cloned(),
+            new: new.get(name).cloned(),
+        })
+        .collect()
+}
+
+/// Marks of `a` mapped through the text edit from `a` to `b`, compared with
+/// the marks of `b`.
+fn mark_changes(a: &Entry, b: &Entry) -> Vec<MarkChange> {
+    let diff = TextDiff::from_chars(a.text.as_str(), b.text.as_str());
+    let mut edits = Vec::new();
+    let mut pos = 0;
+    for op in diff.ops() {
+        let old_len: usize = diff.old_slices()[op.old_range()].iter().map(|s| utf16_len(s)).sum();
+        let new_len: usize = diff.new_slices()[op.new_range()].iter().map(|s| utf16_len(s)).sum();
+        match op {
+            DiffOp::Equal { .. } => pos += old_len,
+            _ => {
+                if old_len > 0 {
+                    edits.push(Edit::Delete { pos, len: old_len });
+                }
+                if new_len > 0 {
+                    edits.push(Edit::Insert { pos, len: new_len });
+                    pos += new_len;
+                }
+            }
+        }
+    }
+    // Marks whose text was deleted map to nothing.
+    let mapped: Vec<(Option<MetadataRange>, &MetadataRange)> = a
+        .marks
+        .iter()
+        .map(|mark| {
+            let mut ranges = vec![mark.clone()];
+            rebase_ranges(&mut ranges, &edits, &RebaseOptions::default());
+            (ranges.pop(), mark)
+        })
+        .collect();
+
+    let same = |x: &MetadataRange, y: &MetadataRange| x.start == y.start && x.end == y.end && x.attrs == y.attrs;
+    let attrs = |range: &MetadataRange| {
+        serde_json::to_value(&range.attrs)
+            .ok()
+            .and_then(|value| serde_json::from_value::<MarkAttrs>(value).ok())
+            .unwrap_or_default()
+    };
+    let mut changes = Vec::new();
+    for (range, original) in &mapped {
+        if !range.as_ref().is_some_and(|range| b.marks.iter().any(|mark| same(mark, range))) {
+            changes.push(MarkChange::Removed {
+                start: original.start,
+                end: original.end,
+                attrs: attrs(original),
+            });
+        }
+    }
+    for mark in &b.marks {
+        if !mapped.iter().any(|(range, _)| range.as_ref().is_some_and(|range| same(range, mark))) {
+            changes.push(MarkChange::Added {
+                start: mark.start,
+                end: mark.end,
+                attrs: attrs(mark),
+            });
+        }
+    }
+    changes
+}
@@ -1,6 +1,15 @@
+use std::collections::BTreeMap;
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+use super::document_tree::DocumentTree;
+use crate::storage::checksum::sha256_hex;
+use crate::storage::zip_container::{AssetRef, DocumentPayload, MetadataRange};
+
+/// Branch that new versions go on when none has been chosen.
+pub const DEFAULT_BRANCH: &str = "main";
+
 /// A snapshot of a document at a specific point in time.
 /// Versions are immutable once created.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,6 +24,23 @@ pub struct DocumentVersion {
     /// User-provided label for this version (optional)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub label: Option<String>,
+    /// Who created the version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    /// Longer free-form notes about the version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Marked as important by the user
+    #[serde(default)]
+    pub pinned: bool,
+    /// Created by an automatic snapshot on save rather than by the user
+    #[serde(default)]
+    pub auto: bool,
+    /// When the label, author, description, tags or pin were last edited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<DateTime<Utc>>,
     /// SHA-256 hash of the content for integrity verification
     pub content_hash: String,
     /// The text content at this version
@@ -22,6 +48,55 @@ pub struct DocumentVersion {
     /// Formatting metadata at this version
     #[serde(default)]
     pub metadata: super::piece_table::PieceTableContent,
+    /// IDs of the versions this one was created on top of. Empty for the
+    /// first version; absent in archives written before the version graph.
+    #[serde(default)]
+    pub parent_ids: Vec<String>,
+    /// Version whose content this one restored, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restored_from: Option<String>,
+    /// Formatting ranges over `content` (UTF-16 offsets)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ranges: Vec<MetadataRange>,
+    /// Document tree over `content`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub document_tree: Option<DocumentTree>,
+    /// Assets at this version. `None` for versions that captured only
+    /// their text.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub assets: Option<Vec<VersionAsset>>,
+    /// When the version was moved to the trash. Trashed versions stay in
+    /// the archive but are left out of listings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Branches that pointed at the version when it was trashed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trashed_heads: Vec<String>,
+}
+
+/// An asset as referenced by a version. The bytes live in the archive,
+/// addressed by `hash`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionAsset {
+    pub name: String,
+    pub target_pos: usize,
+    pub alt: String,
+    pub size: (u32, u32),
+    /// SHA-256 of the asset bytes
+    pub hash: String,
+}
+
+impl From<&AssetRef> for VersionAsset {
+    fn from(asset: &AssetRef) -> Self {
+        Self {
+            name: asset.name.clone(),
+            target_pos: asset.target_pos,
+            alt: asset.alt.clone(),
+            size: asset.size,
+            hash: sha256_hex(&asset.bytes),
+        }
+    }
 }
 
 /// Summary of a version for list display (without full content).
@@ -32,11 +107,71 @@ pub struct VersionSummary {
     pub version_number: u32,
     pub created_at: DateTime<Utc>,
     pub label: Option<String>,
+    #[serde(default)]
+    pub author: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default)]
+    pub auto: bool,
+    #[serde(default)]
+    pub updated_at: Option<DateTime<Utc>>,
     pub content_hash: String,
     /// Character count at this version
     pub char_count: usize,
     /// Line count at this version
     pub line_count: usize,
+    #[serde(default)]
+    pub parent_ids: Vec<String>,
+    #[serde(default)]
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+/// When `save_grokedoc` takes an automatic snapshot. Configured per
+/// document under `metadata.custom.autoVersions`; off unless enabled.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AutoVersionPolicy {
+    pub enabled: bool,
+    /// Snapshot once this long has passed since the current branch head
+    pub interval_minutes: Option<u32>,
+    /// Snapshot once the similarity to the branch head drops to this
+    /// ratio (0.0 to 1.0) or below
+    pub min_similarity: Option<f64>,
+    /// Snapshot when the save happens because the app is closing
+    pub on_close: bool,
+}
+
+impl Default for AutoVersionPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_minutes: Some(30),
+            min_similarity: Some(0.8),
+            on_close: true,
+        }
+    }
+}
+
+/// Named branches of the version graph. Each branch is a movable pointer to
+/// its head version; `create_version` extends the current branch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionBranches {
+    pub current: String,
+    /// Branch name -> head version ID
+    #[serde(default)]
+    pub heads: BTreeMap<String, String>,
+}
+
+impl Default for VersionBranches {
+    fn default() -> Self {
+        Self {
+            current: DEFAULT_BRANCH.to_string(),
+            heads: BTreeMap::new(),
+        }
+    }
 }
 
 /// Result of comparing two versions.
@@ -91,6 +226,32 @@ pub struct DiffLine {
     pub old_line: Option<usize>,
     /// Line number in new file (None for deletions)
     pub new_line: Option<usize>,
+    /// Changed parts of a line that replaces or is replaced by other lines,
+    /// in words or characters depending on the diff's granularity. Empty for
+    /// lines added or removed without a counterpart.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub spans: Vec<DiffSpan>,
+}
+
+/// Changed part of a diff line, in UTF-16 code units of its `content`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiffSpan {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// How finely changed lines are compared. Hunks are always made of lines;
+/// `Line` marks the changed words within them and `Char` the changed
+/// characters. There is no separate word mode since line mode already marks
+/// words; `"word"` is still accepted and means `Line`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffGranularity {
+    #[default]
+    #[serde(alias = "word")]
+    Line,
+    Char,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -114,14 +275,7 @@ impl DocumentVersion {
 
         let metadata = super::piece_table::PieceTableContent {
             base_text: content.clone(),
-            chunks: vec![super::piece_table::PieceChunk {
-                kind: super::piece_table::ChunkType::Original,
-                offset: Some(0),
-                len: Some(content.len()),
-                source: Some("baseText".to_string()),
-                pos: None,
-                data: None,
-            }],
+            chunks: vec![super::piece_table::PieceChunk::original(&content)],
         };
 
         Self {
@@ -129,12 +283,33 @@ impl DocumentVersion {
             version_number,
             created_at: Utc::now(),
             label,
+            author: None,
+            description: None,
+            tags: Vec::new(),
+            pinned: false,
+            auto: false,
+            updated_at: None,
             content_hash,
             content,
             metadata,
+            parent_ids: Vec::new(),
+            restored_from: None,
+            ranges: Vec::new(),
+            document_tree: None,
+            assets: None,
+            deleted_at: None,
+            trashed_heads: Vec::new(),
         }
     }
 
+    /// Capture the formatting, tree and assets of a payload whose text is
+    /// this version's content.
+    pub fn capture_state(&mut self, payload: &DocumentPayload) {
+        self.ranges = payload.metadata.ranges.clone();
+        self.document_tree = payload.document_tree.clone();
+        self.assets = Some(payload.assets.iter().map(VersionAsset::from).collect());
+    }
+
     /// Convert to a summary for list display.
     pub fn to_summary(&self) -> VersionSummary {
         VersionSummary {
@@ -142,9 +317,16 @@ impl DocumentVersion {
             version_number: self.version_number,
             created_at: self.created_at,
             label: self.label.clone(),
+            author: self.author.clone(),
+            tags: self.tags.clone(),
+            pinned: self.pinned,
+            auto: self.auto,
+            updated_at: self.updated_at,
             content_hash: self.content_hash.clone(),
-            char_count: self.content.len(),
+            char_count: self.content.chars().count(),
             line_count: self.content.lines().count(),
+            parent_ids: self.parent_ids.clone(),
+            deleted_at: self.deleted_at,
         }
     }
 }
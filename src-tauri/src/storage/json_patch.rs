@@ -0,0 +1,299 @@
+//! RFC 6902 JSON Patch generation and application.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PatchError {
+    #[error("invalid JSON pointer: {0}")]
+    InvalidPointer(String),
+    #[error("path not found: {0}")]
+    PathNotFound(String),
+    #[error("invalid array index in {0}")]
+    InvalidIndex(String),
+    #[error("cannot move {from} into its own child {path}")]
+    MoveIntoChild { from: String, path: String },
+    #[error("test failed at {0}")]
+    TestFailed(String),
+}
+
+/// A single JSON Patch operation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOperation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+/// Compute a patch that turns `from` into `to`.
+///
+/// Objects are diffed key by key and arrays index by index (trailing
+/// elements added or removed); any other change becomes a `replace`.
+pub fn diff(from: &Value, to: &Value) -> Vec<PatchOperation> {
+    let mut patch = Vec::new();
+    diff_into(from, to, String::new(), &mut patch);
+    patch
+}
+
+fn diff_into(from: &Value, to: &Value, path: String, patch: &mut Vec<PatchOperation>) {
+    if from == to {
+        return;
+    }
+    match (from, to) {
+        (Value::Object(a), Value::Object(b)) => {
+            for key in a.keys().filter(|key| !b.contains_key(*key)) {
+                patch.push(PatchOperation::Remove {
+                    path: child_path(&path, key),
+                });
+            }
+            for (key, value) in b {
+                match a.get(key) {
+                    Some(old) => diff_into(old, value, child_path(&path, key), patch),
+                    None => patch.push(PatchOperation::Add {
+                        path: child_path(&path, key),
+                        value: value.clone(),
+                    }),
+                }
+            }
+        }
+        (Value::Array(a), Value::Array(b)) => {
+            let common = a.len().min(b.len());
+            for idx in 0..common {
+                diff_into(&a[idx], &b[idx], format!("{path}/{idx}"), patch);
+            }
+            // Remove from the end so earlier indices stay valid.
+            for idx in (common..a.len()).rev() {
+                patch.push(PatchOperation::Remove {
+                    path: format!("{path}/{idx}"),
+                });
+            }
+            for (idx, value) in b.iter().enumerate().skip(common) {
+                patch.push(PatchOperation::Add {
+                    path: format!("{path}/{idx}"),
+                    value: value.clone(),
+                });
+            }
+        }
+        _ => patch.push(PatchOperation::Replace {
+            path,
+            value: to.clone(),
+        }),
+    }
+}
+
+fn child_path(parent: &str, key: &str) -> String {
+    format!("{parent}/{}", key.replace('~', "~0").replace('/', "~1"))
+}
+
+/// Apply `patch` to `doc`. The patch is atomic: on error `doc` is left unchanged.
+pub fn apply(doc: &mut Value, patch: &[PatchOperation]) -> Result<(), PatchError> {
+    let mut working = doc.clone();
+    for op in patch {
+        apply_one(&mut working, op)?;
+    }
+    *doc = working;
+    Ok(())
+}
+
+fn apply_one(doc: &mut Value, op: &PatchOperation) -> Result<(), PatchError> {
+    match op {
+        PatchOperation::Add { path, value } => add(doc, path, value.clone()),
+        PatchOperation::Remove { path } => remove(doc, path).map(|_| ()),
+        PatchOperation::Replace { path, value } => {
+            let target = doc
+                .pointer_mut(&pointer(path)?)
+                .ok_or_else(|| PatchError::PathNotFound(path.clone()))?;
+            *target = value.clone();
+            Ok(())
+        }
+        PatchOperation::Move { from, path } => {
+            if path.starts_with(&format!("{from}/")) {
+                return Err(PatchError::MoveIntoChild {
+                    from: from.clone(),
+                    path: path.clone(),
+                });
+            }
+            let value = remove(doc, from)?;
+            add(doc, path, value)
+        }
+        PatchOperation::Copy { from, path } => {
+            let value = doc
+                .pointer(&pointer(from)?)
+                .cloned()
+                .ok_or_else(|| PatchError::PathNotFound(from.clone()))?;
+            add(doc, path, value)
+        }
+        PatchOperation::Test { path, value } => match doc.pointer(&pointer(path)?) {
+            Some(actual) if actual == value => Ok(()),
+            _ => Err(PatchError::TestFailed(path.clone())),
+        },
+    }
+}
+
+/// Validate a JSON pointer (`""` or `/`-prefixed) and return it unchanged.
+fn pointer(path: &str) -> Result<String, PatchError> {
+    if path.is_empty() || path.starts_with('/') {
+        Ok(path.to_string())
+    } else {
+        Err(PatchError::InvalidPointer(path.to_string()))
+    }
+}
+
+/// Split a pointer into its parent pointer and unescaped last token.
+fn split_parent(path: &str) -> Result<(&str, String), PatchError> {
+    pointer(path)?;
+    let idx = path
+        .rfind('/')
+        .ok_or_else(|| PatchError::InvalidPointer(path.to_string()))?;
+    let token = path[idx + 1..].replace("~1", "/").replace("~0", "~");
+    Ok((&path[..idx], token))
+}
+
+fn add(doc: &mut Value, path: &str, value: Value) -> Result<(), PatchError> {
+    if path.is_empty() {
+        *doc = value;
+        return Ok(());
+    }
+    let (parent, token) = split_parent(path)?;
+    match doc.pointer_mut(parent) {
+        Some(Value::Object(map)) => {
+            map.insert(token, value);
+            Ok(())
+        }
+        Some(Value::Array(items)) => {
+            let idx = if token == "-" {
+                items.len()
+            } else {
+                parse_index(&token, path)?
+            };
+            if idx > items.len() {
+                return Err(PatchError::InvalidIndex(path.to_string()));
+            }
+            items.insert(idx, value);
+            Ok(())
+        }
+        _ => Err(PatchError::PathNotFound(path.to_string())),
+    }
+}
+
+fn remove(doc: &mut Value, path: &str) -> Result<Value, PatchError> {
+    let (parent, token) = split_parent(path)?;
+    match doc.pointer_mut(parent) {
+        Some(Value::Object(map)) => map
+            .remove(&token)
+            .ok_or_else(|| PatchError::PathNotFound(path.to_string())),
+        Some(Value::Array(items)) => {
+            let idx = parse_index(&token, path)?;
+            if idx >= items.len() {
+                return Err(PatchError::InvalidIndex(path.to_string()));
+            }
+            Ok(items.remove(idx))
+        }
+        _ => Err(PatchError::PathNotFound(path.to_string())),
+    }
+}
+
+fn parse_index(token: &str, path: &str) -> Result<usize, PatchError> {
+    // RFC 6901: no leading zeros, digits only.
+    if token.is_empty() || (token.len() > 1 && token.starts_with('0')) || !token.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(PatchError::InvalidIndex(path.to_string()));
+    }
+    token.parse().map_err(|_| PatchError::InvalidIndex(path.to_string()))
+}
+
+/// Whether a stored value is a JSON Patch document rather than a full snapshot.
+pub fn is_patch(value: &Value) -> bool {
+    value
+        .as_array()
+        .is_some_and(|ops| ops.iter().all(|op| op.get("op").is_some_and(Value::is_string)))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn diff_then_apply_round_trips() {
+        let from = json!({
+            "id": "v1",
+            "label": "Draft",
+            "tags": ["a", "b", "c"],
+            "metadata": {"ranges": [{"start": 0, "end": 4}], "a/b": 1, "c~d": 2},
+        });
+        let to = json!({
+            "id": "v1",
+            "tags": ["a", "x"],
+            "metadata": {"ranges": [{"start": 0, "end": 6}, {"start": 8, "end": 9}], "a/b": 3},
+            "branch": "main",
+        });
+        for (from, to) in [(&from, &to), (&to, &from), (&from, &json!(null))] {
+            let mut doc = from.clone();
+            apply(&mut doc, &diff(from, to)).unwrap();
+            assert_eq!(&doc, to);
+        }
+        assert!(diff(&from, &from).is_empty());
+    }
+
+    #[test]
+    fn escapes_keys_in_paths() {
+        let patch = diff(&json!({"a/b": 1, "c~d": 1}), &json!({"a/b": 2, "c~d": 2}));
+        let paths: Vec<_> = patch
+            .iter()
+            .map(|op| match op {
+                PatchOperation::Replace { path, .. } => path.as_str(),
+                other => panic!("unexpected {other:?}"),
+            })
+            .collect();
+        assert_eq!(paths, vec!["/a~1b", "/c~0d"]);
+    }
+
+    #[test]
+    fn applies_every_operation() {
+        let mut doc = json!({"list": [1, 2], "obj": {"k": "v"}});
+        let patch: Vec<PatchOperation> = serde_json::from_value(json!([
+            {"op": "test", "path": "/obj/k", "value": "v"},
+            {"op": "add", "path": "/list/-", "value": 3},
+            {"op": "add", "path": "/list/0", "value": 0},
+            {"op": "copy", "from": "/obj", "path": "/copied"},
+            {"op": "move", "from": "/obj/k", "path": "/moved"},
+            {"op": "replace", "path": "/list/1", "value": "one"},
+            {"op": "remove", "path": "/list/3"},
+        ]))
+        .unwrap();
+        apply(&mut doc, &patch).unwrap();
+        assert_eq!(doc, json!({"list": [0, "one", 2], "obj": {}, "copied": {"k": "v"}, "moved": "v"}));
+    }
+
+    #[test]
+    fn failed_patches_leave_the_document_unchanged() {
+        let original = json!({"id": "v1", "list": [1]});
+        let failing = [
+            json!([{"op": "add", "path": "/x", "value": 1}, {"op": "test", "path": "/id", "value": "v2"}]),
+            json!([{"op": "remove", "path": "/list/1"}]),
+            json!([{"op": "add", "path": "/list/01", "value": 2}]),
+            json!([{"op": "replace", "path": "/missing", "value": 2}]),
+            json!([{"op": "move", "from": "/list", "path": "/list/0"}]),
+            json!([{"op": "add", "path": "id", "value": 2}]),
+        ];
+        for patch in failing {
+            let patch: Vec<PatchOperation> = serde_json::from_value(patch).unwrap();
+            let mut doc = original.clone();
+            assert!(apply(&mut doc, &patch).is_err(), "{patch:?}");
+            assert_eq!(doc, original);
+        }
+    }
+
+    #[test]
+    fn tells_patches_from_snapshots() {
+        assert!(is_patch(&json!([{"op": "remove", "path": "/a"}])));
+        assert!(!is_patch(&json!({"id": "v1"})));
+        assert!(!is_patch(&json!([{"id": "v1"}])));
+    }
+}
@@ -1,2 +1,5 @@
 pub mod checksum;
+pub mod json_patch;
+pub mod version_delta;
+pub mod version_store;
 pub mod zip_container;
@@ -0,0 +1,362 @@
+//! Compact on-disk encoding of the version history.
+//!
+//! Each version is split into its text content and the remaining fields. The
+//! newest version and every `KEYFRAME_INTERVAL`-th version are stored in full;
+//! all others are stored as reverse deltas against their successor: a line
+//! based copy/insert script for the content and a JSON Patch for the fields.
+//! A version's `metadata.baseText` is dropped when it duplicates the content.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use similar::{DiffOp, TextDiff};
+
+use crate::storage::checksum::sha256_hex;
+use crate::storage::json_patch::{self, PatchOperation};
+use crate::storage::zip_container::StorageError;
+
+/// Versions at indices divisible by this are keyframes, which bounds the
+/// number of deltas applied to rebuild any version.
+pub const KEYFRAME_INTERVAL: usize = 32;
+
+/// One step of a content delta. Serialized as `[start, len]` (copy bytes
+/// from the successor's content) or as a string (literal text).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum TextOp {
+    Copy(usize, usize),
+    Insert(String),
+}
+
+/// A stored version entry.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "encoding", rename_all = "camelCase")]
+pub enum VersionRecord {
+    Keyframe {
+        version: Value,
+        content: String,
+    },
+    ReverseDelta {
+        /// Turns the successor's fields into this version's fields
+        patch: Vec<PatchOperation>,
+        /// Rebuilds this version's content from the successor's content
+        content: Vec<TextOp>,
+    },
+}
+
+/// Encode full version values, oldest first, into stored records.
+pub fn encode(versions: &[Value]) -> Vec<VersionRecord> {
+    versions
+        .iter()
+        .enumerate()
+        .map(|(idx, version)| match versions.get(idx + 1) {
+            Some(successor) if !is_keyframe(idx, versions.len()) => reverse_delta(version, successor),
+            _ => keyframe(version),
+        })
+        .collect()
+}
+
+/// Whether [`encode`] stores the version at `idx` of `len` in full.
+pub fn is_keyframe(idx: usize, len: usize) -> bool {
+    idx % KEYFRAME_INTERVAL == 0 || idx + 1 == len
+}
+
+/// Store a version in full.
+pub fn keyframe(version: &Value) -> VersionRecord {
+    let (version, content) = split(version);
+    VersionRecord::Keyframe { version, content }
+}
+
+/// Store a version as a delta against the version that follows it.
+pub fn reverse_delta(version: &Value, successor: &Value) -> VersionRecord {
+    let (fields, content) = split(version);
+    let (next_fields, next_content) = split(successor);
+    let mut patch = Vec::new();
+    if let Some(id) = next_fields.get("id") {
+        patch.push(PatchOperation::Test {
+            path: "/id".to_string(),
+            value: id.clone(),
+        });
+    }
+    patch.extend(json_patch::diff(&next_fields, &fields));
+    VersionRecord::ReverseDelta {
+        patch,
+        content: text_delta(&next_content, &content),
+    }
+}
+
+/// Rebuild full version values from stored entries and verify each
+/// version's `contentHash`.
+///
+/// Archives written before records were introduced hold either full
+/// snapshots or forward JSON Patches against the predecessor; both are
+/// still accepted.
+pub fn decode(entries: Vec<Value>) -> Result<Vec<Value>, StorageError> {
+    if entries.first().is_some_and(|entry| entry.get("encoding").is_some()) {
+        let records = entries
+            .into_iter()
+            .map(serde_json::from_value)
+            .collect::<Result<Vec<VersionRecord>, _>>()?;
+        return decode_run(records);
+    }
+    let versions = decode_legacy(entries)?;
+    for version in &versions {
+        verify_content_hash(version)?;
+    }
+    Ok(versions)
+}
+
+/// Rebuild consecutive versions from their records. The last record must
+/// be a keyframe; a run read from the archive can stop at the first one.
+pub fn decode_run(records: Vec<VersionRecord>) -> Result<Vec<Value>, StorageError> {
+    let versions = decode_records(records)?;
+    for version in &versions {
+        verify_content_hash(version)?;
+    }
+    Ok(versions)
+}
+
+fn decode_records(records: Vec<VersionRecord>) -> Result<Vec<Value>, StorageError> {
+    let mut versions = Vec::with_capacity(records.len());
+    let mut successor: Option<(Value, String)> = None;
+    for record in records.into_iter().rev() {
+        let (fields, content) = match record {
+            VersionRecord::Keyframe { version, content } => (version, content),
+            VersionRecord::ReverseDelta { patch, content } => {
+                let (next_fields, next_content) = successor.as_ref().ok_or_else(|| {
+                    StorageError::Integrity("version delta has no successor".to_string())
+                })?;
+                let mut fields = next_fields.clone();
+                json_patch::apply(&mut fields, &patch)?;
+                (fields, apply_text_delta(next_content, &content)?)
+            }
+        };
+        versions.push(join(&fields, &content));
+        successor = Some((fields, content));
+    }
+    versions.reverse();
+    Ok(versions)
+}
+
+fn decode_legacy(entries: Vec<Value>) -> Result<Vec<Value>, StorageError> {
+    let mut previous = Value::Null;
+    let mut versions = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let version = if json_patch::is_patch(&entry) {
+            let patch: Vec<PatchOperation> = serde_json::from_value(entry)?;
+            let mut next = previous.clone();
+            json_patch::apply(&mut next, &patch)?;
+            next
+        } else {
+            entry
+        };
+        previous = version.clone();
+        versions.push(version);
+    }
+    Ok(versions)
+}
+
+fn verify_content_hash(version: &Value) -> Result<(), StorageError> {
+    let (Some(content), Some(expected)) = (
+        version.get("content").and_then(Value::as_str),
+        version.get("contentHash").and_then(Value::as_str),
+    ) else {
+        return Ok(());
+    };
+    let actual = sha256_hex(content.as_bytes());
+    if actual != expected {
+        let id = version.get("id").and_then(Value::as_str).unwrap_or("?");
+        return Err(StorageError::Integrity(format!(
+            "content hash mismatch for version {id}: expected {expected}, got {actual}"
+        )));
+    }
+    Ok(())
+}
+
+/// Separate a version into its fields and its content.
+fn split(version: &Value) -> (Value, String) {
+    let mut fields = version.clone();
+    let content = fields
+        .as_object_mut()
+        .and_then(|map| map.remove("content"))
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default();
+    if let Some(metadata) = fields.get_mut("metadata").and_then(Value::as_object_mut) {
+        if metadata.get("baseText").and_then(Value::as_str) == Some(content.as_str()) {
+            metadata.remove("baseText");
+        }
+    }
+    (fields, content)
+}
+
+/// Inverse of [`split`].
+fn join(fields: &Value, content: &str) -> Value {
+    let mut version = fields.clone();
+    if let Some(map) = version.as_object_mut() {
+        if let Some(metadata) = map.get_mut("metadata").and_then(Value::as_object_mut) {
+            if !metadata.contains_key("baseText") {
+                metadata.insert("baseText".to_string(), Value::from(content));
+            }
+        }
+        map.insert("content".to_string(), Value::from(content));
+    }
+    version
+}
+
+/// Script that rebuilds `target` from `base` by copying unchanged lines.
+fn text_delta(base: &str, target: &str) -> Vec<TextOp> {
+    let diff = TextDiff::from_lines(base, target);
+    let base_offsets = line_offsets(diff.old_slices());
+    let target_offsets = line_offsets(diff.new_slices());
+
+    let mut ops: Vec<TextOp> = Vec::new();
+    for op in diff.ops() {
+        match *op {
+            DiffOp::Equal { old_index, len, .. } => {
+                let start = base_offsets[old_index];
+                let len = base_offsets[old_index + len] - start;
+                match ops.last_mut() {
+                    Some(TextOp::Copy(prev_start, prev_len)) if *prev_start + *prev_len == start => *prev_len += len,
+                    _ => ops.push(TextOp::Copy(start, len)),
+                }
+            }
+            DiffOp::Delete { .. } => {}
+            DiffOp::Insert { new_index, new_len, .. } | DiffOp::Replace { new_index, new_len, .. } => {
+                let text = &target[target_offsets[new_index]..target_offsets[new_index + new_len]];
+                match ops.last_mut() {
+                    Some(TextOp::Insert(prev)) => prev.push_str(text),
+                    _ => ops.push(TextOp::Insert(text.to_string())),
+                }
+            }
+        }
+    }
+    ops
+}
+
+fn apply_text_delta(base: &str, ops: &[TextOp]) -> Result<String, StorageError> {
+    let mut out = String::new();
+    for op in ops {
+        match op {
+            TextOp::Copy(start, len) => {
+                let piece = start.checked_add(*len).and_then(|end| base.get(*start..end)).ok_or_else(|| {
+                    StorageError::Integrity(format!("version delta copies invalid range {start}+{len}"))
+                })?;
+                out.push_str(piece);
+            }
+            TextOp::Insert(text) => out.push_str(text),
+        }
+    }
+    Ok(out)
+}
+
+/// Byte offset of every line start, plus the text length.
+fn line_offsets(lines: &[&str]) -> Vec<usize> {
+    let mut offsets = vec![0];
+    offsets.extend(lines.iter().scan(0, |end, line| {
+        *end += line.len();
+        Some(*end)
+    }));
+    offsets
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn version(idx: usize, content: &str) -> Value {
+        json!({
+            "id": format!("v{idx}"),
+            "versionNumber": idx + 1,
+            "content": content,
+            "contentHash": sha256_hex(content.as_bytes()),
+            "label": if idx % 5 == 0 { Value::from(format!("Draft {idx}")) } else { Value::Null },
+            "metadata": {"baseText": content, "ranges": [{"start": 0, "end": idx % 7, "attrs": {"b": true}}]},
+        })
+    }
+
+    fn history(count: usize) -> Vec<Value> {
+        let mut lines: Vec<String> = (0..20).map(|line| format!("line {line} ✓\n")).collect();
+        (0..count)
+            .map(|idx| {
+                lines[idx % 20] = format!("line {} edited in {idx}\n", idx % 20);
+                if idx % 3 == 0 {
+                    lines.push(format!("appended {idx}\n"));
+                }
+                version(idx, &lines.concat())
+            })
+            .collect()
+    }
+
+    fn stored(records: &[VersionRecord]) -> Vec<Value> {
+        records.iter().map(|record| serde_json::to_value(record).unwrap()).collect()
+    }
+
+    #[test]
+    fn round_trips_across_keyframe_boundaries() {
+        let versions = history(2 * KEYFRAME_INTERVAL + 6);
+        let records = encode(&versions);
+
+        let keyframes: Vec<usize> = records
+            .iter()
+            .enumerate()
+            .filter(|(_, record)| matches!(record, VersionRecord::Keyframe { .. }))
+            .map(|(idx, _)| idx)
+            .collect();
+        assert_eq!(keyframes, vec![0, KEYFRAME_INTERVAL, 2 * KEYFRAME_INTERVAL, versions.len() - 1]);
+        assert_eq!(decode(stored(&records)).unwrap(), versions);
+    }
+
+    #[test]
+    fn decodes_a_run_up_to_the_next_keyframe() {
+        let versions = history(KEYFRAME_INTERVAL + 3);
+        let records = encode(&versions);
+        let run = records[1..=KEYFRAME_INTERVAL].to_vec();
+        assert_eq!(decode_run(run).unwrap(), versions[1..=KEYFRAME_INTERVAL]);
+    }
+
+    #[test]
+    fn deltas_drop_duplicated_base_text() {
+        let versions = history(3);
+        let VersionRecord::ReverseDelta { patch, .. } = &encode(&versions)[1] else {
+            panic!("expected a delta");
+        };
+        assert!(patch.iter().all(|op| !serde_json::to_string(op).unwrap().contains("baseText")));
+    }
+
+    #[test]
+    fn rejects_tampered_content() {
+        let versions = history(4);
+        let mut records = encode(&versions);
+        let VersionRecord::ReverseDelta { content, .. } = &mut records[2] else {
+            panic!("expected a delta");
+        };
+        content.push(TextOp::Insert("tampered".to_string()));
+        assert!(matches!(decode(stored(&records)), Err(StorageError::Integrity(_))));
+    }
+
+    #[test]
+    fn rejects_invalid_copies_and_orphan_deltas() {
+        let versions = history(3);
+        let mut records = encode(&versions);
+        let VersionRecord::ReverseDelta { content, .. } = &mut records[1] else {
+            panic!("expected a delta");
+        };
+        *content = vec![TextOp::Copy(usize::MAX, 2)];
+        assert!(matches!(decode(stored(&records)), Err(StorageError::Integrity(_))));
+
+        let records = encode(&versions)[1..2].to_vec();
+        assert!(matches!(decode_run(records), Err(StorageError::Integrity(_))));
+    }
+
+    #[test]
+    fn reads_legacy_snapshots_and_patches() {
+        let versions = history(3);
+        let entries = vec![
+            versions[0].clone(),
+            serde_json::to_value(json_patch::diff(&versions[0], &versions[1])).unwrap(),
+            versions[2].clone(),
+        ];
+        assert_eq!(decode(entries).unwrap(), versions);
+    }
+}
@@ -0,0 +1,667 @@
+//! Incremental access to the version history of a saved document.
+//!
+//! Archives carry a `versions/index.json` entry with a summary of every
+//! version and the branch heads, so listing reads only the manifest and the
+//! index. Edits rewrite the index and the version entries whose stored
+//! record changes, and copy everything else as is. Archives without an
+//! index are read with a full load and migrated on their first edit.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use zip::read::ZipArchive;
+
+use crate::model::version::{DocumentVersion, VersionBranches, VersionSummary};
+use crate::storage::version_delta::{self, VersionRecord, KEYFRAME_INTERVAL};
+use crate::storage::zip_container::{
+    load_document, read_entry, read_manifest, rewrite_entries, save_document, Manifest, StorageError,
+};
+
+pub const VERSION_INDEX_PATH: &str = "versions/index.json";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionIndex {
+    pub versions: Vec<IndexEntry>,
+    #[serde(default)]
+    pub branches: VersionBranches,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexEntry {
+    #[serde(flatten)]
+    pub summary: VersionSummary,
+    /// Archive entry holding the version record
+    pub entry: String,
+    /// Whether the entry is stored in full rather than as a delta
+    #[serde(default)]
+    pub keyframe: bool,
+}
+
+impl VersionIndex {
+    /// Summaries of the versions outside the trash, oldest first.
+    pub fn summaries(&self) -> Vec<VersionSummary> {
+        self.versions
+            .iter()
+            .filter(|entry| entry.summary.deleted_at.is_none())
+            .map(|entry| entry.summary.clone())
+            .collect()
+    }
+
+    /// Summaries of the trashed versions, oldest first.
+    pub fn trash(&self) -> Vec<VersionSummary> {
+        self.versions
+            .iter()
+            .filter(|entry| entry.summary.deleted_at.is_some())
+            .map(|entry| entry.summary.clone())
+            .collect()
+    }
+
+    fn find(&self, version_id: &str) -> Option<&IndexEntry> {
+        self.versions.iter().find(|entry| entry.summary.id == version_id)
+    }
+
+    fn find_entry(&self, entry: &str) -> Option<&IndexEntry> {
+        self.versions.iter().find(|indexed| indexed.entry == entry)
+    }
+}
+
+/// Build the index for full version values stored at `paths`.
+pub fn build_index(versions: &[Value], paths: &[String], branches: &VersionBranches) -> VersionIndex {
+    let summaries = summaries(versions);
+    VersionIndex {
+        versions: versions
+            .iter()
+            .zip(paths)
+            .enumerate()
+            .filter_map(|(idx, (value, path))| {
+                let id = version_id_of(value)?;
+                Some(IndexEntry {
+                    summary: summaries.iter().find(|summary| summary.id == id)?.clone(),
+                    entry: path.clone(),
+                    keyframe: version_delta::is_keyframe(idx, versions.len()),
+                })
+            })
+            .collect(),
+        branches: branches.clone(),
+    }
+}
+
+/// Version summaries, oldest first. Versions written before the version
+/// graph have no parent links; each is linked to the version before it.
+pub fn summaries(versions: &[Value]) -> Vec<VersionSummary> {
+    let mut previous: Option<String> = None;
+    versions
+        .iter()
+        .filter_map(|value| {
+            let mut summary = serde_json::from_value::<DocumentVersion>(value.clone())
+                .ok()?
+                .to_summary();
+            if value.get("parentIds").is_none() {
+                summary.parent_ids = previous.iter().cloned().collect();
+            }
+            previous = Some(summary.id.clone());
+            Some(summary)
+        })
+        .collect()
+}
+
+/// Like [`summaries`], without the trashed versions.
+pub fn live_summaries(versions: &[Value]) -> Vec<VersionSummary> {
+    let mut summaries = summaries(versions);
+    summaries.retain(|summary| summary.deleted_at.is_none());
+    summaries
+}
+
+/// The version index, or one built from a full load for archives without it.
+pub fn read_index(path: &Path) -> Result<VersionIndex, StorageError> {
+    match VersionEditor::open(path)? {
+        Some(editor) => Ok(editor.index),
+        None => {
+            let payload = load_document(path)?;
+            let paths = vec![String::new(); payload.versions.len()];
+            Ok(build_index(&payload.versions, &paths, &payload.branches))
+        }
+    }
+}
+
+/// Rebuild every version without loading the rest of the document, along
+/// with the branch heads. Returns an empty history if the file does not exist.
+pub fn load_versions(path: &Path) -> Result<(Vec<Value>, VersionBranches), StorageError> {
+    if !path.exists() {
+        return Ok(Default::default());
+    }
+    let Some(mut editor) = VersionEditor::open(path)? else {
+        let payload = load_document(path)?;
+        return Ok((payload.versions, payload.branches));
+    };
+    let mut entries = Vec::with_capacity(editor.manifest.files.versions.len());
+    for entry in &editor.manifest.files.versions {
+        entries.push(serde_json::from_slice(&read_entry(&mut editor.archive, entry)?)?);
+    }
+    Ok((version_delta::decode(entries)?, editor.index.branches))
+}
+
+/// Rebuild one version. Only the version's entry and the deltas up to the
+/// next keyframe are read.
+pub fn read_version(path: &Path, version_id: &str) -> Result<Option<Value>, StorageError> {
+    let Some(mut editor) = VersionEditor::open(path)? else {
+        return Ok(load_document(path)?
+            .versions
+            .into_iter()
+            .find(|version| version_id_of(version) == Some(version_id)));
+    };
+    let Some(entry) = editor.index.find(version_id).map(|entry| entry.entry.clone()) else {
+        return Ok(None);
+    };
+    editor.value(&entry).map(Some)
+}
+
+/// Append a version as the newest one and return the updated index.
+/// `update_branches` runs before the index is written.
+pub fn append_version(
+    path: &Path,
+    version: &DocumentVersion,
+    update_branches: impl FnOnce(&mut VersionBranches),
+) -> Result<VersionIndex, StorageError> {
+    let mut editor = VersionEditor::open_for_edit(path)?;
+    let entry = format!("versions/{}.json", version.id);
+    let mut order = editor.manifest.files.versions.clone();
+    order.push(entry.clone());
+    update_branches(&mut editor.index.branches);
+    editor.commit(path, order, BTreeMap::from([(entry, serde_json::to_value(version)?)]))
+}
+
+/// Edit a stored version in place and return it with the updated index, or
+/// `None` if no version has that ID. Entries that store a delta against the
+/// version are re-encoded as needed.
+pub fn update_version(
+    path: &Path,
+    version_id: &str,
+    update: impl FnOnce(&mut DocumentVersion),
+) -> Result<Option<(DocumentVersion, VersionIndex)>, StorageError> {
+    let mut editor = VersionEditor::open_for_edit(path)?;
+    let Some(indexed) = editor.index.find(version_id).cloned() else {
+        return Ok(None);
+    };
+    let entry = indexed.entry;
+    let mut version: DocumentVersion = serde_json::from_value(editor.value(&entry)?)?;
+    // Older entries leave their parent implicit; keep the one the index inferred.
+    version.parent_ids = indexed.summary.parent_ids;
+    update(&mut version);
+
+    let order = editor.manifest.files.versions.clone();
+    let changed = BTreeMap::from([(entry, serde_json::to_value(&version)?)]);
+    let index = editor.commit(path, order, changed)?;
+    Ok(Some((version, index)))
+}
+
+/// Remove a version and return the updated index, or `None` if no version
+/// has that ID. Children of the removed version are re-attached to its
+/// parents, and branches pointing at it move to its first parent.
+pub fn remove_version(path: &Path, version_id: &str) -> Result<Option<VersionIndex>, StorageError> {
+    let mut editor = VersionEditor::open_for_edit(path)?;
+    if editor.index.find(version_id).is_none() {
+        return Ok(None);
+    }
+    let (order, changed) = plan_removal(&mut editor, &HashSet::from([version_id.to_string()]))?;
+    editor.commit(path, order, changed).map(Some)
+}
+
+/// Remove several versions in one rewrite. Children are re-attached to their
+/// nearest surviving ancestors, and branch heads move the same way. Unknown
+/// IDs are ignored.
+pub fn remove_versions(path: &Path, version_ids: &HashSet<String>) -> Result<VersionIndex, StorageError> {
+    let mut editor = VersionEditor::open_for_edit(path)?;
+    let (order, changed) = plan_removal(&mut editor, version_ids)?;
+    editor.commit(path, order, changed)
+}
+
+/// Fold the `removed` versions into `into` and return it with the updated
+/// index, or `None` if `into` is not a version. Children of the removed
+/// versions are re-attached as by [`remove_versions`], and branch heads on
+/// them move to `into`. `update` edits `into` after its parents are fixed up.
+pub fn squash_versions(
+    path: &Path,
+    removed: &HashSet<String>,
+    into: &str,
+    update: impl FnOnce(&mut DocumentVersion),
+) -> Result<Option<(DocumentVersion, VersionIndex)>, StorageError> {
+    let mut editor = VersionEditor::open_for_edit(path)?;
+    let Some(indexed) = editor.index.find(into).cloned() else {
+        return Ok(None);
+    };
+    for head in editor.index.branches.heads.values_mut() {
+        if removed.contains(head) {
+            *head = into.to_string();
+        }
+    }
+    let (order, mut changed) = plan_removal(&mut editor, removed)?;
+    let mut version: DocumentVersion = match changed.get(&indexed.entry) {
+        Some(value) => serde_json::from_value(value.clone())?,
+        None => {
+            let mut version: DocumentVersion = serde_json::from_value(editor.value(&indexed.entry)?)?;
+            version.parent_ids = indexed.summary.parent_ids;
+            version
+        }
+    };
+    update(&mut version);
+    changed.insert(indexed.entry, serde_json::to_value(&version)?);
+    let index = editor.commit(path, order, changed)?;
+    Ok(Some((version, index)))
+}
+
+/// Move a version to the trash and return the updated index, or `None` if
+/// no version has that ID. Branches pointing at it move to its nearest
+/// ancestor outside the trash; the version remembers them so that taking
+/// it out of the trash can point them back.
+pub fn trash_version(path: &Path, version_id: &str, now: DateTime<Utc>) -> Result<Option<VersionIndex>, StorageError> {
+    let mut editor = VersionEditor::open_for_edit(path)?;
+    let Some(indexed) = editor.index.find(version_id).cloned() else {
+        return Ok(None);
+    };
+    let mut version: DocumentVersion = serde_json::from_value(editor.value(&indexed.entry)?)?;
+    version.parent_ids = indexed.summary.parent_ids;
+    move_to_trash(&mut editor.index, &mut version, now);
+    let order = editor.manifest.files.versions.clone();
+    let changed = BTreeMap::from([(indexed.entry, serde_json::to_value(&version)?)]);
+    editor.commit(path, order, changed).map(Some)
+}
+
+/// Index of versions loaded in memory, which have no archive entries.
+pub fn loaded_index(versions: &[Value], branches: &VersionBranches) -> VersionIndex {
+    build_index(versions, &vec![String::new(); versions.len()], branches)
+}
+
+/// [`trash_version`] for versions loaded in memory. Returns whether
+/// `version_id` is one of them.
+pub fn trash_loaded(
+    versions: &mut [Value],
+    branches: &mut VersionBranches,
+    version_id: &str,
+    now: DateTime<Utc>,
+) -> Result<bool, StorageError> {
+    let mut index = loaded_index(versions, branches);
+    let (Some(indexed), Some(position)) = (
+        index.find(version_id).cloned(),
+        versions.iter().position(|value| version_id_of(value) == Some(version_id)),
+    ) else {
+        return Ok(false);
+    };
+    let mut version: DocumentVersion = serde_json::from_value(versions[position].clone())?;
+    version.parent_ids = indexed.summary.parent_ids;
+    move_to_trash(&mut index, &mut version, now);
+    versions[position] = serde_json::to_value(&version)?;
+    *branches = index.branches;
+    Ok(true)
+}
+
+/// [`remove_version`] for versions loaded in memory. Returns whether
+/// `version_id` was one of them.
+pub fn remove_loaded(
+    versions: &mut Vec<Value>,
+    branches: &mut VersionBranches,
+    version_id: &str,
+) -> Result<bool, StorageError> {
+    let mut index = loaded_index(versions, branches);
+    if index.find(version_id).is_none() {
+        return Ok(false);
+    }
+    let removed = HashSet::from([version_id.to_string()]);
+    for (child, reattached) in relink_removed(&mut index, &removed) {
+        if let Some(value) = versions.iter_mut().find(|value| version_id_of(value) == Some(child.as_str())) {
+            value["parentIds"] = serde_json::to_value(reattached)?;
+        }
+    }
+    versions.retain(|value| version_id_of(value) != Some(version_id));
+    *branches = index.branches;
+    Ok(true)
+}
+
+/// Take a version out of the trash and return the updated index, or `None`
+/// if no version has that ID. Branches it was the head of point back at it
+/// unless they have moved on to versions that do not descend from it.
+pub fn untrash_version(path: &Path, version_id: &str) -> Result<Option<VersionIndex>, StorageError> {
+    let mut editor = VersionEditor::open_for_edit(path)?;
+    let Some(indexed) = editor.index.find(version_id).cloned() else {
+        return Ok(None);
+    };
+    let mut version: DocumentVersion = serde_json::from_value(editor.value(&indexed.entry)?)?;
+    version.parent_ids = indexed.summary.parent_ids;
+    version.deleted_at = None;
+
+    let ancestors = ancestor_ids(&parent_map(&editor.index), version_id);
+    for name in std::mem::take(&mut version.trashed_heads) {
+        let heads = &mut editor.index.branches.heads;
+        if heads.get(&name).is_none_or(|head| ancestors.contains(head)) {
+            heads.insert(name, version_id.to_string());
+        }
+    }
+    let order = editor.manifest.files.versions.clone();
+    let changed = BTreeMap::from([(indexed.entry, serde_json::to_value(&version)?)]);
+    editor.commit(path, order, changed).map(Some)
+}
+
+/// Stored (compressed) size of each version's entry, by version ID.
+pub fn stored_sizes(path: &Path) -> Result<HashMap<String, u64>, StorageError> {
+    let index = read_index(path)?;
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let mut sizes = HashMap::new();
+    for indexed in &index.versions {
+        if let Ok(file) = archive.by_name(&indexed.entry) {
+            sizes.insert(indexed.summary.id.clone(), file.compressed_size());
+        }
+    }
+    Ok(sizes)
+}
+
+/// Change the branch heads without touching any version.
+pub fn update_branches(
+    path: &Path,
+    update: impl FnOnce(&mut VersionBranches),
+) -> Result<VersionIndex, StorageError> {
+    let mut editor = VersionEditor::open_for_edit(path)?;
+    update(&mut editor.index.branches);
+    let order = editor.manifest.files.versions.clone();
+    editor.commit(path, order, BTreeMap::new())
+}
+
+// ============================================================================
+// Helper Functions
+// ============================================================================
+
+/// An open archive with its version index, caching decoded versions.
+struct VersionEditor {
+    archive: ZipArchive<File>,
+    manifest: Manifest,
+    index: VersionIndex,
+    index_path: String,
+    decoded: HashMap<String, Value>,
+}
+
+impl VersionEditor {
+    /// Open a document and read its version index. Returns `None` for
+    /// archives written before the index existed.
+    fn open(path: &Path) -> Result<Option<Self>, StorageError> {
+        let mut archive = ZipArchive::new(File::open(path)?)?;
+        let manifest = read_manifest(&mut archive)?;
+        let Some(index_path) = manifest.files.version_index.clone() else {
+            return Ok(None);
+        };
+        let index = serde_json::from_slice(&read_entry(&mut archive, &index_path)?)?;
+        Ok(Some(Self {
+            archive,
+            manifest,
+            index,
+            index_path,
+            decoded: HashMap::new(),
+        }))
+    }
+
+    /// Like [`open`](Self::open), but first migrates archives without an index.
+    fn open_for_edit(path: &Path) -> Result<Self, StorageError> {
+        if let Some(editor) = Self::open(path)? {
+            return Ok(editor);
+        }
+        save_document(path, &load_document(path)?)?;
+        Self::open(path)?.ok_or_else(|| StorageError::Integrity("version index missing after save".to_string()))
+    }
+
+    /// Decode the version stored at `entry`, caching the whole run read for it.
+    fn value(&mut self, entry: &str) -> Result<Value, StorageError> {
+        if let Some(value) = self.decoded.get(entry) {
+            return Ok(value.clone());
+        }
+        let position = entry_position(&self.manifest, entry)?;
+        let paths = self.manifest.files.versions[position..].to_vec();
+        let values = version_delta::decode_run(read_run(&mut self.archive, &paths)?)?;
+        for (path, value) in paths.into_iter().zip(values) {
+            self.decoded.insert(path, value);
+        }
+        Ok(self.decoded[entry].clone())
+    }
+
+    fn is_keyframe(&self, entry: &str) -> bool {
+        self.index.find_entry(entry).is_some_and(|indexed| indexed.keyframe)
+    }
+
+    /// Write the version list `order`, with new or changed versions in
+    /// `changed`. Entries left out of `order` are removed. Only entries whose
+    /// stored record changes are rewritten.
+    fn commit(
+        mut self,
+        path: &Path,
+        order: Vec<String>,
+        mut changed: BTreeMap<String, Value>,
+    ) -> Result<VersionIndex, StorageError> {
+        let old = self.manifest.files.versions.clone();
+        let old_positions: HashMap<&str, usize> =
+            old.iter().enumerate().map(|(idx, entry)| (entry.as_str(), idx)).collect();
+
+        let mut updates = BTreeMap::new();
+        let mut versions = Vec::with_capacity(order.len());
+        for (idx, entry) in order.iter().enumerate() {
+            let successor = order.get(idx + 1);
+            let old_successor = old_positions.get(entry.as_str()).and_then(|pos| old.get(pos + 1));
+            let was_keyframe = old_positions
+                .contains_key(entry.as_str())
+                .then(|| self.is_keyframe(entry));
+            let keyframe = match (successor, was_keyframe) {
+                (None, _) => true,
+                (Some(_), None) => idx % KEYFRAME_INTERVAL == 0,
+                // The former newest version becomes a delta unless its position keeps it a keyframe.
+                (Some(_), Some(true)) => old_successor.is_some() || idx % KEYFRAME_INTERVAL == 0,
+                // A delta whose keyframe successor was removed becomes a keyframe, so chains never grow.
+                (Some(_), Some(false)) => {
+                    old_successor.is_some_and(|next| !order.contains(next) && self.is_keyframe(next))
+                }
+            };
+            let rewrite = was_keyframe != Some(keyframe)
+                || changed.contains_key(entry)
+                || (!keyframe
+                    && (successor != old_successor || successor.is_some_and(|next| changed.contains_key(next))));
+
+            if rewrite {
+                let value = self.value_or_changed(entry, &changed)?;
+                let record = match successor.filter(|_| !keyframe) {
+                    Some(next) => version_delta::reverse_delta(&value, &self.value_or_changed(next, &changed)?),
+                    None => version_delta::keyframe(&value),
+                };
+                updates.insert(entry.clone(), Some(serde_json::to_vec(&record)?));
+            }
+
+            let summary = match changed.remove(entry) {
+                Some(value) => summaries(&[value]).pop(),
+                None => self.index.find_entry(entry).map(|indexed| indexed.summary.clone()),
+            };
+            if let Some(summary) = summary {
+                versions.push(IndexEntry {
+                    summary,
+                    entry: entry.clone(),
+                    keyframe,
+                });
+            }
+        }
+        for entry in old.iter().filter(|entry| !order.contains(entry)) {
+            updates.insert(entry.clone(), None);
+        }
+
+        self.index.versions = versions;
+        updates.insert(self.index_path.clone(), Some(serde_json::to_vec(&self.index)?));
+        drop(self.archive);
+        rewrite_entries(path, &updates, |files| files.versions = order)?;
+        Ok(self.index)
+    }
+
+    fn value_or_changed(&mut self, entry: &str, changed: &BTreeMap<String, Value>) -> Result<Value, StorageError> {
+        match changed.get(entry) {
+            Some(value) => Ok(value.clone()),
+            None => self.value(entry),
+        }
+    }
+}
+
+/// Re-link children and branch heads of `removed` versions to their nearest
+/// surviving ancestors. Returns the remaining entry order and the re-linked
+/// children for [`VersionEditor::commit`].
+fn plan_removal(
+    editor: &mut VersionEditor,
+    removed: &HashSet<String>,
+) -> Result<(Vec<String>, BTreeMap<String, Value>), StorageError> {
+    let mut changed = BTreeMap::new();
+    for (child, reattached) in relink_removed(&mut editor.index, removed) {
+        let Some(entry) = editor.index.find(&child).map(|indexed| indexed.entry.clone()) else {
+            continue;
+        };
+        let mut value = editor.value(&entry)?;
+        value["parentIds"] = serde_json::to_value(reattached)?;
+        changed.insert(entry, value);
+    }
+
+    let order = editor
+        .manifest
+        .files
+        .versions
+        .iter()
+        .filter(|entry| {
+            let id = editor.index.find_entry(entry).map(|indexed| &indexed.summary.id);
+            !id.is_some_and(|id| removed.contains(id))
+        })
+        .cloned()
+        .collect();
+    Ok((order, changed))
+}
+
+/// Move the branch heads on `removed` versions to their nearest surviving
+/// ancestors outside the trash. Returns the new parents of the children of
+/// removed versions, by version ID.
+fn relink_removed(index: &mut VersionIndex, removed: &HashSet<String>) -> Vec<(String, Vec<String>)> {
+    let parents = parent_map(index);
+    let children = index
+        .versions
+        .iter()
+        .filter(|entry| !removed.contains(&entry.summary.id))
+        .filter(|entry| entry.summary.parent_ids.iter().any(|id| removed.contains(id)))
+        .map(|entry| {
+            let reattached = surviving_ancestors(&parents, removed, &entry.summary.parent_ids);
+            (entry.summary.id.clone(), reattached)
+        })
+        .collect();
+
+    let mut hidden = trashed_ids(index);
+    hidden.extend(removed.iter().cloned());
+    let heads = std::mem::take(&mut index.branches.heads);
+    index.branches.heads = heads
+        .into_iter()
+        .filter_map(|(name, head)| match removed.contains(&head) {
+            true => surviving_ancestors(&parents, &hidden, &[head])
+                .into_iter()
+                .next()
+                .map(|head| (name, head)),
+            false => Some((name, head)),
+        })
+        .collect();
+    children
+}
+
+/// Mark `version` trashed and move the branch heads on it to its nearest
+/// ancestor outside the trash, remembering them on the version.
+fn move_to_trash(index: &mut VersionIndex, version: &mut DocumentVersion, now: DateTime<Utc>) {
+    let parents = parent_map(index);
+    let mut hidden = trashed_ids(index);
+    hidden.insert(version.id.clone());
+
+    let heads = std::mem::take(&mut index.branches.heads);
+    index.branches.heads = heads
+        .into_iter()
+        .filter_map(|(name, head)| {
+            if head != version.id {
+                return Some((name, head));
+            }
+            if !version.trashed_heads.contains(&name) {
+                version.trashed_heads.push(name.clone());
+            }
+            surviving_ancestors(&parents, &hidden, &[head]).into_iter().next().map(|head| (name, head))
+        })
+        .collect();
+    version.deleted_at.get_or_insert(now);
+}
+
+fn parent_map(index: &VersionIndex) -> HashMap<String, Vec<String>> {
+    index
+        .versions
+        .iter()
+        .map(|entry| (entry.summary.id.clone(), entry.summary.parent_ids.clone()))
+        .collect()
+}
+
+fn trashed_ids(index: &VersionIndex) -> HashSet<String> {
+    index.trash().into_iter().map(|summary| summary.id).collect()
+}
+
+/// The nearest ancestors of `ids` (or `ids` themselves) that are not in
+/// `removed`, in parent order.
+fn surviving_ancestors(
+    parents: &HashMap<String, Vec<String>>,
+    removed: &HashSet<String>,
+    ids: &[String],
+) -> Vec<String> {
+    let mut out: Vec<String> = Vec::new();
+    let mut stack: Vec<String> = ids.iter().rev().cloned().collect();
+    let mut seen = HashSet::new();
+    while let Some(id) = stack.pop() {
+        if !seen.insert(id.clone()) {
+            continue;
+        }
+        if removed.contains(&id) {
+            stack.extend(parents.get(&id).into_iter().flatten().rev().cloned());
+        } else if !out.contains(&id) {
+            out.push(id);
+        }
+    }
+    out
+}
+
+/// `version_id` and every version it descends from.
+fn ancestor_ids(parents: &HashMap<String, Vec<String>>, version_id: &str) -> HashSet<String> {
+    let mut seen = HashSet::new();
+    let mut stack = vec![version_id.to_string()];
+    while let Some(id) = stack.pop() {
+        if seen.insert(id.clone()) {
+            stack.extend(parents.get(&id).into_iter().flatten().cloned());
+        }
+    }
+    seen
+}
+
+/// Read records from the start of `paths` up to and including the first keyframe.
+fn read_run(archive: &mut ZipArchive<File>, paths: &[String]) -> Result<Vec<VersionRecord>, StorageError> {
+    let mut run = Vec::new();
+    for path in paths {
+        let record: VersionRecord = serde_json::from_slice(&read_entry(archive, path)?)?;
+        let done = matches!(record, VersionRecord::Keyframe { .. });
+        run.push(record);
+        if done {
+            break;
+        }
+    }
+    Ok(run)
+}
+
+fn entry_position(manifest: &Manifest, entry: &str) -> Result<usize, StorageError> {
+    manifest
+        .files
+        .versions
+        .iter()
+        .position(|path| path == entry)
+        .ok_or_else(|| StorageError::Integrity(format!("version index references missing entry {entry}")))
+}
+
+fn version_id_of(version: &Value) -> Option<&str> {
+    version.get("id").and_then(Value::as_str)
+}
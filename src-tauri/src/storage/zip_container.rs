@@ -1,4 +1,4 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::{self, File};
 use std::io::{Cursor, Read, Write};
 use std::path::Path;
@@ -11,8 +11,14 @@ use zip::read::ZipArchive;
 use zip::write::SimpleFileOptions;
 use zip::{CompressionMethod, ZipWriter};
 
+use crate::model::document_tree::DocumentTree;
+use crate::model::operation::OperationLog;
 use crate::model::piece_table::PieceTableContent;
+use crate::model::version::VersionBranches;
 use crate::storage::checksum::sha256_hex;
+use crate::storage::json_patch::PatchError;
+use crate::storage::version_delta;
+use crate::storage::version_store::{self, VersionIndex, VERSION_INDEX_PATH};
 
 #[derive(Debug, thiserror::Error)]
 pub enum StorageError {
@@ -26,6 +32,8 @@ pub enum StorageError {
     Cbor(#[from] serde_cbor::Error),
     #[error("integrity check failed: {0}")]
     Integrity(String),
+    #[error("json patch error: {0}")]
+    Patch(#[from] PatchError),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -52,7 +60,7 @@ pub struct MetadataPayload {
     pub custom: BTreeMap<String, Value>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AssetRef {
     pub name: String,
@@ -74,7 +82,17 @@ pub struct DocumentPayload {
     #[serde(default)]
     pub assets: Vec<AssetRef>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub document_tree: Option<Value>,
+    pub document_tree: Option<DocumentTree>,
+    /// Append-only edit history (`versions/operations.json`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operations: Option<OperationLog>,
+    /// Branch heads of the version graph (stored in the version index)
+    #[serde(default)]
+    pub branches: VersionBranches,
+    /// Bytes of assets that versions reference but the document no longer
+    /// contains, by SHA-256. Stays in the backend.
+    #[serde(skip)]
+    pub asset_history: BTreeMap<String, Vec<u8>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -86,7 +104,16 @@ pub struct ManifestFiles {
     pub document_tree: Option<String>,
     #[serde(default)]
     pub versions: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operations: Option<String>,
+    /// Version summaries (`versions/index.json`). When present, versions are
+    /// excluded from the payload checksum so they can be updated in place.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version_index: Option<String>,
     pub assets: Vec<String>,
+    /// Content-addressed asset bytes kept for versions (`assets/history/<sha256>`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub asset_history: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -100,6 +127,9 @@ pub struct Manifest {
     pub file_checksums: BTreeMap<String, String>,
 }
 
+const OPERATIONS_PATH: &str = "versions/operations.json";
+const ASSET_HISTORY_DIR: &str = "assets/history/";
+
 fn maybe_compress_metadata(bytes: &[u8]) -> (String, Vec<u8>) {
     if bytes.len() <= 1024 {
         return ("metadata.json".to_string(), bytes.to_vec());
@@ -122,161 +152,115 @@ fn maybe_decompress_metadata(path: &str, bytes: &[u8]) -> Result<Vec<u8>, Storag
     Ok(out)
 }
 
+/// Parse `versions/operations.json`, rejecting logs whose undo and redo
+/// entries point at edits that are not in the log.
+fn parse_operation_log(bytes: &[u8]) -> Result<OperationLog, StorageError> {
+    let log: OperationLog = serde_json::from_slice(bytes)?;
+    log.validate().map_err(|err| StorageError::Integrity(err.to_string()))?;
+    Ok(log)
+}
+
 fn crc_hex(crc: u32) -> String {
     format!("{crc:08x}")
 }
 
-pub fn save_document(path: &Path, payload: &DocumentPayload) -> Result<(), StorageError> {
-    let file = File::create(path)?;
-    let mut zip = ZipWriter::new(file);
-    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
-
-    let content = PieceTableContent {
-        base_text: payload.base_text.clone(),
-        chunks: payload.chunks.clone(),
-    };
-    let content_bytes = serde_cbor::to_vec(&content)?;
-
-    let metadata_json = serde_json::to_vec(&payload.metadata)?;
-    let (metadata_path, metadata_bytes) = maybe_compress_metadata(&metadata_json);
-
-    let document_tree_bytes: Option<Vec<u8>> = payload
-        .document_tree
-        .as_ref()
-        .map(serde_json::to_vec)
-        .transpose()?;
-
-    let version_paths: Vec<String> = payload
-        .versions
-        .iter()
-        .enumerate()
-        .map(|(idx, _)| format!("versions/delta-{}.jsonpatch", idx + 1))
-        .collect();
-
-    let mut asset_paths = Vec::with_capacity(payload.assets.len());
-    for asset in &payload.assets {
-        asset_paths.push(format!("assets/{}", asset.name));
-    }
-
-    // Build all bytes first to compute a deterministic payload hash.
+/// Deterministic hash over the payload, computed identically on save and
+/// load. Entries are hashed as stored (metadata possibly compressed), so the
+/// result does not depend on how the typed payload serializes; versions are
+/// hashed as compact JSON.
+fn payload_checksum(
+    content: &[u8],
+    metadata: &[u8],
+    document_tree: Option<&[u8]>,
+    versions: &[Value],
+    operations: Option<&[u8]>,
+    assets: &[AssetRef],
+) -> Result<String, StorageError> {
     let mut hash_input = Vec::new();
-    hash_input.extend_from_slice(&content_bytes);
-    hash_input.extend_from_slice(&metadata_bytes);
-    if let Some(ref dt) = document_tree_bytes {
-        hash_input.extend_from_slice(dt);
+    hash_input.extend_from_slice(content);
+    hash_input.extend_from_slice(metadata);
+    if let Some(tree) = document_tree {
+        hash_input.extend_from_slice(tree);
     }
-    for version in &payload.versions {
+    for version in versions {
         hash_input.extend_from_slice(serde_json::to_string(version)?.as_bytes());
     }
-    for asset in &payload.assets {
-        hash_input.extend_from_slice(&asset.bytes);
-    }
-    let checksum = sha256_hex(&hash_input);
-
-    let schema_version = if payload.document_tree.is_some() {
-        "2.0"
-    } else {
-        "1.0"
-    };
-
-    zip.start_file("content.cbor", options)?;
-    zip.write_all(&content_bytes)?;
-
-    zip.start_file(&metadata_path, options)?;
-    zip.write_all(&metadata_bytes)?;
-
-    if let Some(ref dt) = document_tree_bytes {
-        zip.start_file("documentTree.json", options)?;
-        zip.write_all(dt)?;
-    }
-
-    for (idx, version) in payload.versions.iter().enumerate() {
-        zip.start_file(&version_paths[idx], options)?;
-        zip.write_all(serde_json::to_string_pretty(version)?.as_bytes())?;
+    if let Some(log) = operations {
+        hash_input.extend_from_slice(log);
     }
-
-    let mut rels = BTreeMap::<String, Value>::new();
-    for asset in &payload.assets {
-        let asset_path = format!("assets/{}", asset.name);
-        zip.start_file(&asset_path, options)?;
-        zip.write_all(&asset.bytes)?;
-        rels.insert(
-            asset.name.clone(),
-            serde_json::json!({
-                "targetPos": asset.target_pos,
-                "alt": asset.alt,
-                "size": [asset.size.0, asset.size.1],
-            }),
-        );
+    for asset in assets {
+        hash_input.extend_from_slice(&asset.bytes);
     }
+    Ok(sha256_hex(&hash_input))
+}
 
-    zip.start_file("assets/rels.json", options)?;
-    zip.write_all(serde_json::to_string_pretty(&rels)?.as_bytes())?;
-
-    // Finalize to get CRC values from a read pass.
-    zip.finish()?;
-
-    let mut archive = ZipArchive::new(File::open(path)?)?;
-    let mut file_checksums = BTreeMap::new();
-    for idx in 0..archive.len() {
-        let entry = archive.by_index(idx)?;
-        file_checksums.insert(entry.name().to_string(), crc_hex(entry.crc32()));
+pub fn save_document(path: &Path, payload: &DocumentPayload) -> Result<(), StorageError> {
+    let version_paths: Vec<String> = payload
+        .versions
+        .iter()
+        .enumerate()
+        .map(|(idx, version)| match version.get("id").and_then(Value::as_str) {
+            Some(id) => format!("versions/{id}.json"),
+            None => format!("versions/version-{}.json", idx + 1),
+        })
+        .collect();
+    let mut encoded = Vec::with_capacity(version_paths.len() + 1);
+    for (entry, record) in version_paths.iter().zip(version_delta::encode(&payload.versions)) {
+        encoded.push((entry.clone(), serde_json::to_vec(&record)?));
     }
-
-    let manifest = Manifest {
-        schema_version: schema_version.to_string(),
-        content_type: "text/grokedoc".to_string(),
-        last_modified: Utc::now().to_rfc3339(),
-        checksum: checksum.clone(),
-        files: ManifestFiles {
-            content: "content.cbor".to_string(),
-            metadata: metadata_path.clone(),
-            document_tree: document_tree_bytes.map(|_| "documentTree.json".to_string()),
-            versions: version_paths.clone(),
-            assets: asset_paths.clone(),
-        },
-        file_checksums,
+    let index = version_store::build_index(&payload.versions, &version_paths, &payload.branches);
+    encoded.push((VERSION_INDEX_PATH.to_string(), serde_json::to_vec(&index)?));
+
+    let versions = StoredVersions {
+        paths: version_paths,
+        index_path: VERSION_INDEX_PATH.to_string(),
+        encoded,
+        source: None,
     };
+    write_document(path, payload, versions, retained_asset_history(payload))
+}
 
-    // Rewrite ZIP with manifest first for fast validation.
-    let mut buffer = Vec::new();
-    {
-        let mut final_zip = ZipWriter::new(Cursor::new(&mut buffer));
-        final_zip.start_file("manifest.json", options)?;
-        final_zip.write_all(serde_json::to_string_pretty(&manifest)?.as_bytes())?;
-
-        final_zip.start_file("content.cbor", options)?;
-        final_zip.write_all(&content_bytes)?;
-
-        final_zip.start_file(&metadata_path, options)?;
-        final_zip.write_all(&metadata_bytes)?;
-
-        if let Some(ref dt) = document_tree_bytes {
-            final_zip.start_file("documentTree.json", options)?;
-            final_zip.write_all(dt)?;
-        }
-
-        for (idx, version) in payload.versions.iter().enumerate() {
-            final_zip.start_file(&version_paths[idx], options)?;
-            final_zip.write_all(serde_json::to_string_pretty(version)?.as_bytes())?;
-        }
+/// Save the current state of a document and copy its stored version entries
+/// and index unchanged, without decoding them. `versions` and `branches` of
+/// the payload are ignored. Since which asset bytes the stored versions
+/// reference is not known without decoding them, the whole asset history is
+/// kept; [`save_document`] drops unreferenced bytes.
+/// Returns `false` without writing if the archive has no version index.
+pub fn save_working_copy(path: &Path, payload: &DocumentPayload) -> Result<bool, StorageError> {
+    let mut source = ZipArchive::new(File::open(path)?)?;
+    let manifest = read_manifest(&mut source)?;
+    let Some(index_path) = manifest.files.version_index else {
+        return Ok(false);
+    };
+    let current: BTreeSet<String> = payload.assets.iter().map(|asset| sha256_hex(&asset.bytes)).collect();
+    let asset_history = payload
+        .asset_history
+        .iter()
+        .filter(|(hash, _)| !current.contains(*hash))
+        .map(|(hash, bytes)| (format!("{ASSET_HISTORY_DIR}{hash}"), bytes.as_slice()))
+        .collect();
 
-        for asset in &payload.assets {
-            let asset_path = format!("assets/{}", asset.name);
-            final_zip.start_file(&asset_path, options)?;
-            final_zip.write_all(&asset.bytes)?;
-        }
+    let versions = StoredVersions {
+        paths: manifest.files.versions,
+        index_path,
+        encoded: Vec::new(),
+        source: Some(source),
+    };
+    write_document(path, payload, versions, asset_history)?;
+    Ok(true)
+}
 
-        final_zip.start_file("assets/rels.json", options)?;
-        final_zip.write_all(serde_json::to_string_pretty(&rels)?.as_bytes())?;
-        final_zip.finish()?;
-    }
+pub fn load_document(path: &Path) -> Result<DocumentPayload, StorageError> {
+    read_payload(path, true)
+}
 
-    fs::write(path, buffer)?;
-    Ok(())
+/// Load the current state of a document without its versions (except for
+/// archives without a version index, whose checksum covers them).
+pub fn load_working_copy(path: &Path) -> Result<DocumentPayload, StorageError> {
+    read_payload(path, false)
 }
 
-pub fn load_document(path: &Path) -> Result<DocumentPayload, StorageError> {
+fn read_payload(path: &Path, include_versions: bool) -> Result<DocumentPayload, StorageError> {
     let file = File::open(path)?;
     let mut archive = ZipArchive::new(file)?;
 
@@ -303,40 +287,52 @@ pub fn load_document(path: &Path) -> Result<DocumentPayload, StorageError> {
         }
     }
 
-    let content: PieceTableContent = {
-        let mut content_file = archive.by_name(&manifest.files.content)?;
-        let mut content_bytes = Vec::new();
-        content_file.read_to_end(&mut content_bytes)?;
-        serde_cbor::from_slice(&content_bytes)?
-    };
+    let content_bytes = read_entry(&mut archive, &manifest.files.content)?;
+    let content: PieceTableContent = serde_cbor::from_slice(&content_bytes)?;
 
-    let metadata: MetadataPayload = {
-        let mut metadata_file = archive.by_name(&manifest.files.metadata)?;
-        let mut metadata_bytes = Vec::new();
-        metadata_file.read_to_end(&mut metadata_bytes)?;
-        let metadata_json_bytes = maybe_decompress_metadata(&manifest.files.metadata, &metadata_bytes)?;
-        serde_json::from_slice(&metadata_json_bytes)?
-    };
+    let metadata_bytes = read_entry(&mut archive, &manifest.files.metadata)?;
+    let metadata: MetadataPayload =
+        serde_json::from_slice(&maybe_decompress_metadata(&manifest.files.metadata, &metadata_bytes)?)?;
 
-    let document_tree = manifest
-        .files
-        .document_tree
-        .as_ref()
-        .and_then(|path| {
-            let mut file = archive.by_name(path).ok()?;
-            let mut bytes = Vec::new();
-            file.read_to_end(&mut bytes).ok()?;
-            serde_json::from_slice::<Value>(&bytes).ok()
-        });
+    let document_tree_bytes = match manifest.files.document_tree.as_ref() {
+        Some(path) => match archive.by_name(path) {
+            Ok(mut file) => {
+                let mut bytes = Vec::new();
+                file.read_to_end(&mut bytes)?;
+                Some(bytes)
+            }
+            Err(_) => None,
+        },
+        None => None,
+    };
+    let document_tree: Option<DocumentTree> = document_tree_bytes
+        .as_deref()
+        .map(serde_json::from_slice)
+        .transpose()?;
 
-    let mut versions = Vec::new();
-    for version_path in &manifest.files.versions {
-        if let Ok(mut version_file) = archive.by_name(version_path) {
-            let mut bytes = Vec::new();
-            version_file.read_to_end(&mut bytes)?;
-            versions.push(serde_json::from_slice::<Value>(&bytes)?);
+    let mut version_entries = Vec::new();
+    if include_versions || manifest.files.version_index.is_none() {
+        for version_path in &manifest.files.versions {
+            let bytes = read_entry(&mut archive, version_path).map_err(|err| match err {
+                StorageError::Zip(zip::result::ZipError::FileNotFound) => {
+                    StorageError::Integrity(format!("version entry {version_path} is missing"))
+                }
+                err => err,
+            })?;
+            version_entries.push(serde_json::from_slice::<Value>(&bytes)?);
         }
     }
+    let versions = version_delta::decode(version_entries)?;
+    let branches = match manifest.files.version_index.as_ref() {
+        Some(path) => serde_json::from_slice::<VersionIndex>(&read_entry(&mut archive, path)?)?.branches,
+        None => VersionBranches::default(),
+    };
+
+    let operations_bytes = match manifest.files.operations.as_ref() {
+        Some(path) => Some(read_entry(&mut archive, path)?),
+        None => None,
+    };
+    let operations = operations_bytes.as_deref().map(parse_operation_log).transpose()?;
 
     let rels: BTreeMap<String, Value> = if let Ok(mut rels_file) = archive.by_name("assets/rels.json") {
         let mut rels_bytes = Vec::new();
@@ -379,23 +375,27 @@ pub fn load_document(path: &Path) -> Result<DocumentPayload, StorageError> {
         }
     }
 
-    // Validate payload checksum.
-    let mut hash_input = Vec::new();
-    hash_input.extend_from_slice(content.base_text.as_bytes());
-    for chunk in &content.chunks {
-        hash_input.extend_from_slice(serde_json::to_string(chunk)?.as_bytes());
+    let mut asset_history = BTreeMap::new();
+    for entry in &manifest.files.asset_history {
+        let hash = entry.trim_start_matches(ASSET_HISTORY_DIR).to_string();
+        asset_history.insert(hash, read_entry(&mut archive, entry)?);
     }
-    hash_input.extend_from_slice(serde_json::to_string(&metadata)?.as_bytes());
-    if let Some(ref dt) = document_tree {
-        hash_input.extend_from_slice(serde_json::to_string(dt)?.as_bytes());
-    }
-    for version in &versions {
-        hash_input.extend_from_slice(serde_json::to_string(version)?.as_bytes());
-    }
-    for asset in &assets {
-        hash_input.extend_from_slice(&asset.bytes);
-    }
-    let checksum = sha256_hex(&hash_input);
+
+    // Validate payload checksum. Indexed archives keep versions out of it;
+    // each version is covered by its entry CRC and content hash instead.
+    let checksummed_versions: &[Value] = if manifest.files.version_index.is_some() {
+        &[]
+    } else {
+        &versions
+    };
+    let checksum = payload_checksum(
+        &content_bytes,
+        &metadata_bytes,
+        document_tree_bytes.as_deref(),
+        checksummed_versions,
+        operations_bytes.as_deref(),
+        &assets,
+    )?;
     if checksum != manifest.checksum {
         return Err(StorageError::Integrity(format!(
             "payload checksum mismatch: expected {}, got {}",
@@ -410,10 +410,439 @@ pub fn load_document(path: &Path) -> Result<DocumentPayload, StorageError> {
         versions,
         assets,
         document_tree,
+        operations,
+        branches,
+        asset_history,
     })
 }
 
+/// Bytes of every asset a saved document can restore into a version: its
+/// asset history plus its current assets, by SHA-256. Empty if the file
+/// does not exist.
+pub fn load_asset_history(path: &Path) -> Result<BTreeMap<String, Vec<u8>>, StorageError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut archive = ZipArchive::new(file)?;
+    let manifest = read_manifest(&mut archive)?;
+    let mut history = BTreeMap::new();
+    for entry in &manifest.files.asset_history {
+        let hash = entry.trim_start_matches(ASSET_HISTORY_DIR).to_string();
+        history.insert(hash, read_entry(&mut archive, entry)?);
+    }
+    for entry in &manifest.files.assets {
+        let bytes = read_entry(&mut archive, entry)?;
+        history.insert(sha256_hex(&bytes), bytes);
+    }
+    Ok(history)
+}
+
+/// Version entries of an archive being written.
+struct StoredVersions {
+    /// Version entries, oldest first, as listed in the manifest
+    paths: Vec<String>,
+    index_path: String,
+    /// Encoded entries to write, the version index included
+    encoded: Vec<(String, Vec<u8>)>,
+    /// Archive to copy `paths` and `index_path` from instead
+    source: Option<ZipArchive<File>>,
+}
+
+fn write_document(
+    path: &Path,
+    payload: &DocumentPayload,
+    mut versions: StoredVersions,
+    asset_history: BTreeMap<String, &[u8]>,
+) -> Result<(), StorageError> {
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    let content = PieceTableContent {
+        base_text: payload.base_text.clone(),
+        chunks: payload.chunks.clone(),
+    };
+    let content_bytes = serde_cbor::to_vec(&content)?;
+
+    let metadata_json = serde_json::to_vec(&payload.metadata)?;
+    let (metadata_path, metadata_bytes) = maybe_compress_metadata(&metadata_json);
+
+    let document_tree_bytes: Option<Vec<u8>> = payload
+        .document_tree
+        .as_ref()
+        .map(serde_json::to_vec)
+        .transpose()?;
+
+    let operations_bytes: Option<Vec<u8>> = payload.operations.as_ref().map(serde_json::to_vec).transpose()?;
+
+    let mut asset_paths = Vec::with_capacity(payload.assets.len());
+    let mut rels = BTreeMap::<String, Value>::new();
+    for asset in &payload.assets {
+        asset_paths.push(format!("assets/{}", asset.name));
+        rels.insert(
+            asset.name.clone(),
+            serde_json::json!({
+                "targetPos": asset.target_pos,
+                "alt": asset.alt,
+                "size": [asset.size.0, asset.size.1],
+            }),
+        );
+    }
+
+    let checksum = payload_checksum(
+        &content_bytes,
+        &metadata_bytes,
+        document_tree_bytes.as_deref(),
+        &[],
+        operations_bytes.as_deref(),
+        &payload.assets,
+    )?;
+
+    let schema_version = if payload.document_tree.is_some() {
+        "2.0"
+    } else {
+        "1.0"
+    };
+
+    // Compress every entry once to learn its CRC, then copy it raw behind
+    // the manifest.
+    let mut staging = ZipWriter::new(Cursor::new(Vec::new()));
+    staging.start_file("content.cbor", options)?;
+    staging.write_all(&content_bytes)?;
+
+    staging.start_file(&metadata_path, options)?;
+    staging.write_all(&metadata_bytes)?;
+
+    if let Some(ref dt) = document_tree_bytes {
+        staging.start_file("documentTree.json", options)?;
+        staging.write_all(dt)?;
+    }
+
+    for (entry, bytes) in &versions.encoded {
+        staging.start_file(entry, options)?;
+        staging.write_all(bytes)?;
+    }
+
+    if let Some(ref ops) = operations_bytes {
+        staging.start_file(OPERATIONS_PATH, options)?;
+        staging.write_all(ops)?;
+    }
+
+    for (asset_path, asset) in asset_paths.iter().zip(&payload.assets) {
+        staging.start_file(asset_path, options)?;
+        staging.write_all(&asset.bytes)?;
+    }
+
+    staging.start_file("assets/rels.json", options)?;
+    staging.write_all(serde_json::to_string_pretty(&rels)?.as_bytes())?;
+
+    for (entry, bytes) in &asset_history {
+        staging.start_file(entry, options)?;
+        staging.write_all(bytes)?;
+    }
+    let mut staged = ZipArchive::new(staging.finish()?)?;
+
+    let mut file_checksums = BTreeMap::new();
+    for idx in 0..staged.len() {
+        let entry = staged.by_index(idx)?;
+        file_checksums.insert(entry.name().to_string(), crc_hex(entry.crc32()));
+    }
+    let copied: Vec<String> = match versions.source {
+        Some(_) => versions.paths.iter().chain([&versions.index_path]).cloned().collect(),
+        None => Vec::new(),
+    };
+    if let Some(source) = versions.source.as_mut() {
+        for entry in &copied {
+            let crc = source.by_name(entry)?.crc32();
+            file_checksums.insert(entry.clone(), crc_hex(crc));
+        }
+    }
+
+    let manifest = Manifest {
+        schema_version: schema_version.to_string(),
+        content_type: "text/grokedoc".to_string(),
+        last_modified: Utc::now().to_rfc3339(),
+        checksum,
+        files: ManifestFiles {
+            content: "content.cbor".to_string(),
+            metadata: metadata_path,
+            document_tree: document_tree_bytes.as_ref().map(|_| "documentTree.json".to_string()),
+            versions: versions.paths,
+            operations: operations_bytes.as_ref().map(|_| OPERATIONS_PATH.to_string()),
+            version_index: Some(versions.index_path),
+            assets: asset_paths,
+            asset_history: asset_history.keys().cloned().collect(),
+        },
+        file_checksums,
+    };
+
+    // Manifest first for fast validation.
+    let mut buffer = Vec::new();
+    {
+        let mut zip = ZipWriter::new(Cursor::new(&mut buffer));
+        zip.start_file("manifest.json", options)?;
+        zip.write_all(serde_json::to_string_pretty(&manifest)?.as_bytes())?;
+        for idx in 0..staged.len() {
+            zip.raw_copy_file(staged.by_index_raw(idx)?)?;
+        }
+        if let Some(source) = versions.source.as_mut() {
+            for entry in &copied {
+                let idx = source.index_for_name(entry).ok_or(zip::result::ZipError::FileNotFound)?;
+                zip.raw_copy_file(source.by_index_raw(idx)?)?;
+            }
+        }
+        zip.finish()?;
+    }
+    drop(versions.source);
+
+    fs::write(path, buffer)?;
+    Ok(())
+}
+
+/// Asset history entries to write: bytes of assets referenced by versions
+/// that are not among the document's current assets.
+fn retained_asset_history(payload: &DocumentPayload) -> BTreeMap<String, &[u8]> {
+    let current: BTreeMap<String, &[u8]> = payload
+        .assets
+        .iter()
+        .map(|asset| (sha256_hex(&asset.bytes), asset.bytes.as_slice()))
+        .collect();
+    let mut retained = BTreeMap::new();
+    let referenced = payload
+        .versions
+        .iter()
+        .filter_map(|version| version.get("assets").and_then(Value::as_array))
+        .flatten()
+        .filter_map(|asset| asset.get("hash").and_then(Value::as_str));
+    for hash in referenced {
+        if current.contains_key(hash) {
+            continue;
+        }
+        match payload.asset_history.get(hash) {
+            Some(bytes) => {
+                retained.insert(format!("{ASSET_HISTORY_DIR}{hash}"), bytes.as_slice());
+            }
+            None => log::warn!("bytes of versioned asset {} are missing", hash),
+        }
+    }
+    retained
+}
+
+/// Read only the operation log of a saved document, without loading the payload.
+/// Returns `None` if the file does not exist or has no log.
+pub fn load_operation_log(path: &Path) -> Result<Option<OperationLog>, StorageError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let mut archive = ZipArchive::new(file)?;
+    let manifest = read_manifest(&mut archive)?;
+    let Some(ops_path) = manifest.files.operations else {
+        return Ok(None);
+    };
+    parse_operation_log(&read_entry(&mut archive, &ops_path)?).map(Some)
+}
+
+/// Read only the metadata of a saved document.
+pub fn load_metadata(path: &Path) -> Result<MetadataPayload, StorageError> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let manifest = read_manifest(&mut archive)?;
+    let bytes = read_entry(&mut archive, &manifest.files.metadata)?;
+    Ok(serde_json::from_slice(&maybe_decompress_metadata(&manifest.files.metadata, &bytes)?)?)
+}
+
+pub fn read_manifest(archive: &mut ZipArchive<File>) -> Result<Manifest, StorageError> {
+    Ok(serde_json::from_slice(&read_entry(archive, "manifest.json")?)?)
+}
+
+pub fn read_entry(archive: &mut ZipArchive<File>, name: &str) -> Result<Vec<u8>, StorageError> {
+    let mut file = archive.by_name(name)?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Replace or add (`Some`) and remove (`None`) individual entries of a saved
+/// document. Untouched entries are copied without recompression; the
+/// manifest's CRCs are updated and `edit_files` adjusts its file list.
+pub fn rewrite_entries(
+    path: &Path,
+    updates: &BTreeMap<String, Option<Vec<u8>>>,
+    edit_files: impl FnOnce(&mut ManifestFiles),
+) -> Result<(), StorageError> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let mut manifest = read_manifest(&mut archive)?;
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    // Compress new entries once to learn their CRCs, then copy them raw.
+    let mut staging = ZipWriter::new(Cursor::new(Vec::new()));
+    for (name, bytes) in updates {
+        if let Some(bytes) = bytes {
+            staging.start_file(name, options)?;
+            staging.write_all(bytes)?;
+        }
+    }
+    let mut staged = ZipArchive::new(staging.finish()?)?;
+
+    for name in updates.keys() {
+        manifest.file_checksums.remove(name);
+    }
+    for idx in 0..staged.len() {
+        let entry = staged.by_index(idx)?;
+        manifest
+            .file_checksums
+            .insert(entry.name().to_string(), crc_hex(entry.crc32()));
+    }
+    edit_files(&mut manifest.files);
+    manifest.last_modified = Utc::now().to_rfc3339();
+
+    let mut buffer = Vec::new();
+    {
+        let mut zip = ZipWriter::new(Cursor::new(&mut buffer));
+        zip.start_file("manifest.json", options)?;
+        zip.write_all(serde_json::to_string_pretty(&manifest)?.as_bytes())?;
+        for idx in 0..archive.len() {
+            let entry = archive.by_index_raw(idx)?;
+            if entry.name() == "manifest.json" || updates.contains_key(entry.name()) {
+                continue;
+            }
+            zip.raw_copy_file(entry)?;
+        }
+        for idx in 0..staged.len() {
+            zip.raw_copy_file(staged.by_index_raw(idx)?)?;
+        }
+        zip.finish()?;
+    }
+    drop(archive);
+
+    fs::write(path, buffer)?;
+    Ok(())
+}
+
 pub fn export_markdown(path: &Path, content: &PieceTableContent) -> Result<(), StorageError> {
     fs::write(path, content.to_text())?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::model::version::DocumentVersion;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("yeno-{}.grokedoc", uuid::Uuid::new_v4()))
+    }
+
+    fn sample_payload() -> DocumentPayload {
+        let tree = serde_json::json!({
+            "version": 2,
+            "root": {"children": [{"id": "s1", "type": "section", "children": [
+                {"id": "h1", "type": "heading", "level": 1, "textRange": {"start": 0, "end": 5}, "marks": []},
+                {"id": "p1", "type": "paragraph", "textRange": {"start": 6, "end": 11},
+                    "marks": [{"start": 0, "end": 5, "attrs": {"i": true}}]}
+            ]}]}
+        });
+        let mut metadata = MetadataPayload::default();
+        // Large enough to be stored compressed.
+        metadata.custom.insert("notes".to_string(), Value::from("note ".repeat(400)));
+        DocumentPayload {
+            base_text: "Title\nHello".to_string(),
+            chunks: vec![],
+            metadata,
+            versions: vec![],
+            assets: vec![AssetRef {
+                name: "a.png".to_string(),
+                target_pos: 5,
+                alt: "a".to_string(),
+                size: (1, 1),
+                bytes: vec![1, 2, 3],
+            }],
+            document_tree: Some(serde_json::from_value(tree).unwrap()),
+            operations: None,
+            branches: VersionBranches::default(),
+            asset_history: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn document_tree_round_trips_through_the_archive() {
+        let path = temp_path();
+        let payload = sample_payload();
+        save_document(&path, &payload).unwrap();
+
+        let loaded = load_document(&path).unwrap();
+        assert_eq!(loaded.document_tree, payload.document_tree);
+        assert_eq!(loaded.assets, payload.assets);
+
+        let mut archive = ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let stored: DocumentTree = serde_json::from_slice(&read_entry(&mut archive, "documentTree.json").unwrap()).unwrap();
+        assert_eq!(Some(stored), payload.document_tree);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn checksum_covers_stored_entry_bytes() {
+        let path = temp_path();
+        save_document(&path, &sample_payload()).unwrap();
+
+        let mut archive = ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let manifest = read_manifest(&mut archive).unwrap();
+        assert!(manifest.files.metadata.ends_with(".br"));
+        let mut hash_input = Vec::new();
+        for entry in ["content.cbor", manifest.files.metadata.as_str(), "documentTree.json", "assets/a.png"] {
+            hash_input.extend_from_slice(&read_entry(&mut archive, entry).unwrap());
+        }
+        assert_eq!(manifest.checksum, sha256_hex(&hash_input));
+        fs::remove_file(path).unwrap();
+    }
+
+    fn with_versions(count: u32) -> DocumentPayload {
+        let mut payload = sample_payload();
+        for number in 1..=count {
+            let version = DocumentVersion::new(number, format!("Title\nHello {number}"), None);
+            payload.versions.push(serde_json::to_value(version).unwrap());
+        }
+        payload
+    }
+
+    #[test]
+    fn working_copy_saves_copy_version_entries() {
+        let path = temp_path();
+        let payload = with_versions(3);
+        save_document(&path, &payload).unwrap();
+        let entries = |path: &Path| {
+            let mut archive = ZipArchive::new(File::open(path).unwrap()).unwrap();
+            let manifest = read_manifest(&mut archive).unwrap();
+            let names = manifest.files.versions.iter().chain(manifest.files.version_index.as_ref());
+            names
+                .map(|name| (name.clone(), read_entry(&mut archive, name).unwrap()))
+                .collect::<Vec<_>>()
+        };
+        let stored = entries(&path);
+
+        let mut edited = sample_payload();
+        edited.base_text = "Title\nHello again".to_string();
+        assert!(save_working_copy(&path, &edited).unwrap());
+
+        assert_eq!(entries(&path), stored);
+        let loaded = load_document(&path).unwrap();
+        assert_eq!(loaded.base_text, edited.base_text);
+        assert_eq!(loaded.versions, payload.versions);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn missing_version_entries_fail_the_load() {
+        let path = temp_path();
+        let payload = with_versions(2);
+        save_document(&path, &payload).unwrap();
+        let entry = format!("versions/{}.json", payload.versions[0]["id"].as_str().unwrap());
+        rewrite_entries(&path, &BTreeMap::from([(entry, None)]), |_| {}).unwrap();
+
+        assert!(matches!(load_document(&path), Err(StorageError::Integrity(_))));
+        fs::remove_file(path).unwrap();
+    }
+}